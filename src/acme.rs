@@ -1,15 +1,43 @@
-use std::collections::HashSet;
-use std::path::Path;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use futures::future::join_all;
 use log::{info, warn};
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::PrivatePkcs8KeyDer;
+use rustls::sign::CertifiedKey;
+use serde::{Deserialize, Serialize};
+
+use crate::acme_account::{contact_urls, directory, eab_key, SavedAccount};
+use crate::dns::{parse_resolver, DnsProvider, DnsProviderConfig, PropagationCheck};
+use crate::proxy::TlsConfig;
 
 use instant_acme::{
-    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
-    NewAccount, NewOrder, OrderStatus, RetryPolicy,
+    Account, AccountBuilder, AuthorizationStatus, ChallengeType, ExternalAccountKey, Identifier,
+    NewAccount, NewOrder, Order, OrderStatus, RetryPolicy,
 };
 
-/// Check if the certificate at the given path covers all the required domains
+/// Whether a certificate name covers a hostname. A `*.` wildcard name covers exactly
+/// one label, and a wildcard hostname is only covered by the same wildcard.
+fn san_covers(san: &str, domain: &str) -> bool {
+    if san.eq_ignore_ascii_case(domain) {
+        return true;
+    }
+
+    match san.strip_prefix("*.") {
+        Some(parent) => !domain.starts_with("*.")
+            && domain
+                .split_once('.')
+                .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(parent)),
+        None => false,
+    }
+}
+
+/// Check if the certificate at the given path covers all the required domains, either
+/// by name or through a wildcard SAN
 pub fn cert_covers_domains(cert_path: &Path, required_domains: &[String]) -> bool {
     let cert_data = match std::fs::read(cert_path) {
         Ok(data) => data,
@@ -33,21 +61,39 @@ pub fn cert_covers_domains(cert_path: &Path, required_domains: &[String]) -> boo
         }
     };
 
-    let required_set: HashSet<&str> = required_domains.iter().map(|s| s.as_str()).collect();
-    let cert_set: HashSet<&str> = cert_sans.iter().map(|s| s.as_str()).collect();
+    let missing: Vec<&str> = required_domains
+        .iter()
+        .filter(|domain| !cert_sans.iter().any(|san| san_covers(san, domain)))
+        .map(|domain| domain.as_str())
+        .collect();
 
-    if required_set == cert_set {
+    if missing.is_empty() {
         info!("Certificate covers all required domains");
         true
     } else {
         info!(
-            "Certificate domain mismatch. Required: {:?}, Have: {:?}",
-            required_set, cert_set
+            "Certificate domain mismatch. Missing: {:?}, Have: {:?}",
+            missing, cert_sans
         );
         false
     }
 }
 
+/// Expiry time (`notAfter`) of the certificate at the given path
+pub fn cert_expiry(cert_path: &Path) -> Option<SystemTime> {
+    use rustls_pemfile::certs;
+    use std::io::BufReader;
+
+    let cert_data = std::fs::read(cert_path).ok()?;
+    let mut reader = BufReader::new(cert_data.as_slice());
+    let cert = certs(&mut reader).next()?.ok()?;
+
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert).ok()?;
+    let not_after = cert.validity().not_after.timestamp();
+
+    Some(UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64))
+}
+
 /// Extract Subject Alternative Names from a PEM certificate
 fn extract_sans_from_pem(pem_data: &str) -> Option<Vec<String>> {
     use rustls_pemfile::certs;
@@ -81,198 +127,607 @@ fn extract_sans_from_pem(pem_data: &str) -> Option<Vec<String>> {
     Some(sans)
 }
 
+/// Paths of the per-domain certificate and key files written by [`provision_certificates`]
+pub fn domain_cert_paths(cert_dir: &Path, domain: &str) -> (PathBuf, PathBuf) {
+    let subdomain = domain.strip_suffix(".duckdns.org").unwrap_or(domain);
+    // `*.zone` is stored as `_wildcard.zone`, which can't clash with a real hostname
+    let name = match subdomain.strip_prefix("*.") {
+        Some(zone) => format!("_wildcard.{}", zone),
+        None => subdomain.to_string(),
+    };
+    let name = name.replace(|c: char| !c.is_ascii_alphanumeric() && !matches!(c, '.' | '-' | '_'), "_");
+    (
+        cert_dir.join(format!("{}_cert.pem", name)),
+        cert_dir.join(format!("{}_key.pem", name)),
+    )
+}
+
+/// Challenge type used to prove control over a domain, as named in the config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AcmeChallengeType {
+    #[default]
+    #[serde(rename = "dns-01")]
+    Dns01,
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+/// Pending HTTP-01 challenges (token -> key authorization), answered by the proxy itself
+#[derive(Debug, Default)]
+pub struct Http01Tokens {
+    tokens: RwLock<HashMap<String, String>>,
+}
+
+impl Http01Tokens {
+    pub fn insert(&self, token: &str, key_authorization: &str) {
+        self.tokens
+            .write()
+            .unwrap()
+            .insert(token.to_string(), key_authorization.to_string());
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.tokens.write().unwrap().remove(token);
+    }
+
+    /// Key authorization to serve for a token, if a challenge with that token is pending
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.tokens.read().unwrap().get(token).cloned()
+    }
+}
+
+/// Pending TLS-ALPN-01 challenges (domain -> self-signed challenge certificate),
+/// presented by the TLS listener to `acme-tls/1` handshakes
+#[derive(Debug, Default)]
+pub struct TlsAlpn01Certs {
+    certs: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl TlsAlpn01Certs {
+    /// Create the challenge certificate for `domain`: self-signed, with the SHA-256
+    /// digest of the key authorization in the critical acmeIdentifier extension (RFC 8737)
+    pub fn insert(&self, domain: &str, key_authorization_digest: &[u8]) -> anyhow::Result<()> {
+        let mut params = CertificateParams::new(vec![domain.to_string()])?;
+        params
+            .custom_extensions
+            .push(CustomExtension::new_acme_identifier(key_authorization_digest));
+        let key_pair = KeyPair::generate()?;
+        let cert = params.self_signed(&key_pair)?;
+
+        // Not `CertifiedKey::from_der`: its certificate check rejects the critical extension
+        let signing_key = CryptoProvider::get_default()
+            .ok_or_else(|| anyhow::anyhow!("No rustls crypto provider installed"))?
+            .key_provider
+            .load_private_key(PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into())?;
+        let key = CertifiedKey::new(vec![cert.der().clone()], signing_key);
+        self.certs
+            .write()
+            .unwrap()
+            .insert(domain.to_lowercase(), Arc::new(key));
+        Ok(())
+    }
+
+    pub fn remove(&self, domain: &str) {
+        self.certs.write().unwrap().remove(&domain.to_lowercase());
+    }
+
+    /// Challenge certificate for a server name, if a challenge for it is pending
+    pub fn get(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        self.certs.read().unwrap().get(&domain.to_lowercase()).cloned()
+    }
+}
+
+/// How challenges are answered during provisioning
+#[derive(Debug, Clone)]
+pub enum AcmeChallenge {
+    /// DNS-01 with TXT records published through the DNS provider
+    Dns01(Arc<dyn DnsProvider>),
+    /// HTTP-01 with tokens served by the running proxy on its HTTP listener
+    Http01(Arc<Http01Tokens>),
+    /// TLS-ALPN-01 with challenge certificates presented by the running TLS listener
+    TlsAlpn01(Arc<TlsAlpn01Certs>),
+}
+
+/// External Account Binding credentials, required by CAs such as ZeroSSL or step-ca
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExternalAccountBinding {
+    /// EAB key identifier issued by the CA
+    pub key_id: String,
+    /// Base64url encoded EAB HMAC key issued by the CA
+    pub hmac_key: String,
+}
+
+impl ExternalAccountBinding {
+    fn key(&self) -> anyhow::Result<ExternalAccountKey> {
+        eab_key(&self.key_id, &self.hmac_key)
+    }
+}
+
 /// Configuration for ACME certificate provisioning
 #[derive(Debug, Clone)]
 pub struct AcmeConfig {
     pub domains: Vec<String>,
-    pub duckdns_token: String,
+    pub challenge: AcmeChallenge,
     pub cert_path: std::path::PathBuf,
     pub key_path: std::path::PathBuf,
-    pub production: bool,
-    pub dns_wait_seconds: u64,
+    /// ACME directory URL of the CA
+    pub directory_url: String,
+    /// PEM root certificate trusted for the directory, for internal CAs and Pebble
+    pub root_cert: Option<PathBuf>,
+    pub eab: Option<ExternalAccountBinding>,
+    /// Account contacts as URLs (`mailto:...`)
+    pub contact: Vec<String>,
+    /// How DNS-01 challenge records are checked before validation
+    pub propagation: PropagationCheck,
     pub account_path: Option<std::path::PathBuf>,
+    /// Also save the first certificate obtained as the default `cert_path`/`key_path`
+    pub save_default: bool,
+    /// Order one certificate for all domains instead of one per domain, if the
+    /// challenges of all domains can be published at the same time
+    pub san_order: bool,
 }
 
-/// Provision certificates for the given domains using ACME DNS-01 challenge
-/// Note: Due to DuckDNS limitation (one TXT record per subdomain), we provision
-/// each domain separately. Each domain gets its own cert file (domain_cert.pem, domain_key.pem).
+impl AcmeConfig {
+    /// ACME settings for the given domains, `None` if ACME is not configured (DNS-01
+    /// without a `dns_provider` block or DuckDNS token)
+    pub fn from_tls_config(
+        tls_config: &TlsConfig,
+        domains: Vec<String>,
+        http01_tokens: Arc<Http01Tokens>,
+        tls_alpn01_certs: Arc<TlsAlpn01Certs>,
+    ) -> anyhow::Result<Option<Self>> {
+        let challenge = match tls_config.acme_challenge {
+            AcmeChallengeType::Http01 => AcmeChallenge::Http01(http01_tokens),
+            AcmeChallengeType::TlsAlpn01 => AcmeChallenge::TlsAlpn01(tls_alpn01_certs),
+            AcmeChallengeType::Dns01 => {
+                let provider_config = match (&tls_config.dns_provider, &tls_config.duckdns_token) {
+                    (Some(provider_config), _) => provider_config.clone(),
+                    (None, Some(token)) => DnsProviderConfig::Duckdns { token: token.clone() },
+                    (None, None) => return Ok(None),
+                };
+                AcmeChallenge::Dns01(provider_config.build()?)
+            }
+        };
+        // The CA only validates wildcard names through DNS
+        let mut domains = domains;
+        if !matches!(challenge, AcmeChallenge::Dns01(_)) {
+            domains.retain(|domain| {
+                let wildcard = domain.starts_with("*.");
+                if wildcard {
+                    warn!("Skipping {}: wildcard certificates need the dns-01 challenge", domain);
+                }
+                !wildcard
+            });
+        }
+
+        let cert_path = PathBuf::from(&tls_config.cert_path);
+        let cert_dir = cert_path.parent().unwrap_or(Path::new("."));
+
+        let (directory_url, account_file) =
+            directory(tls_config.acme_directory.as_deref(), tls_config.acme_production)?;
+
+        Ok(Some(AcmeConfig {
+            domains,
+            challenge,
+            account_path: Some(cert_dir.join(account_file)),
+            cert_path,
+            key_path: PathBuf::from(&tls_config.key_path),
+            directory_url,
+            root_cert: tls_config.acme_root_cert.as_ref().map(PathBuf::from),
+            eab: tls_config.acme_eab.clone(),
+            contact: contact_urls(&tls_config.acme_contact),
+            propagation: PropagationCheck {
+                resolvers: tls_config
+                    .dns_resolvers
+                    .iter()
+                    .map(|resolver| parse_resolver(resolver))
+                    .collect::<anyhow::Result<_>>()?,
+                timeout: Duration::from_secs(tls_config.dns_propagation_timeout_seconds),
+                fallback_wait: Duration::from_secs(tls_config.dns_wait_seconds),
+            },
+            save_default: true,
+            san_order: tls_config.acme_san_order,
+        }))
+    }
+}
+
+/// Configured CA for an account, either Let's Encrypt or the custom `acme_directory`
+fn account_builder(config: &AcmeConfig) -> anyhow::Result<AccountBuilder> {
+    Ok(match &config.root_cert {
+        Some(root_cert) => Account::builder_with_root(root_cert)?,
+        None => Account::builder()?,
+    })
+}
+
+/// Save the account credentials with the contacts registered for it
+fn save_account(path: &Path, account: &SavedAccount) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(account)?)?;
+    Ok(())
+}
+
+/// Load the saved ACME account, or register a new one with the configured contacts
+/// and External Account Binding and save its credentials
+async fn load_or_create_account(config: &AcmeConfig) -> anyhow::Result<Account> {
+    let contact = config.contact.iter().map(String::as_str).collect::<Vec<_>>();
+
+    if let Some(path) = &config.account_path
+        && path.exists()
+    {
+        let json = std::fs::read_to_string(path)?;
+        let saved: SavedAccount = serde_json::from_str(&json)?;
+        let saved_contact = saved.contact;
+        let account = account_builder(config)?.from_credentials(saved.credentials).await?;
+        info!("Loaded existing ACME account");
+
+        // Keep the contacts in sync with the config, e.g. for accounts created without one
+        if saved_contact != config.contact {
+            match account.update_contacts(&contact).await {
+                Ok(()) => {
+                    info!("Updated ACME account contacts to {:?}", config.contact);
+                    let mut saved: SavedAccount = serde_json::from_str(&json)?;
+                    saved.contact = config.contact.clone();
+                    if let Err(e) = save_account(path, &saved) {
+                        warn!("Failed to save ACME account contacts to {}: {}", path.display(), e);
+                    }
+                }
+                Err(e) => warn!("Failed to update ACME account contacts: {}", e),
+            }
+        }
+        return Ok(account);
+    }
+
+    info!("Registering ACME account at {}", config.directory_url);
+    let eab = config.eab.as_ref().map(ExternalAccountBinding::key).transpose()?;
+    let (account, credentials) = account_builder(config)?
+        .create(
+            &NewAccount {
+                contact: &contact,
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            config.directory_url.clone(),
+            eab.as_ref(),
+        )
+        .await?;
+
+    // Save account credentials for future use
+    if let Some(path) = &config.account_path {
+        let saved = SavedAccount {
+            credentials,
+            contact: config.contact.clone(),
+        };
+        save_account(path, &saved)?;
+        info!("Saved ACME account credentials to {}", path.display());
+    }
+
+    info!("Created new ACME account");
+    Ok(account)
+}
+
+/// Provision certificates for the given domains using ACME DNS-01, HTTP-01 or TLS-ALPN-01 challenges.
+///
+/// Each domain gets its own cert file (domain_cert.pem, domain_key.pem) from its own order.
+/// Orders run concurrently, except for domains sharing the single TXT record of a DNS
+/// provider like DuckDNS, which are ordered one after another. With `san_order`, a single
+/// certificate covering all domains is ordered instead when the challenges allow it.
 /// The first domain's cert is also saved as the default cert.pem/key.pem.
 pub async fn provision_certificates(config: &AcmeConfig) -> anyhow::Result<()> {
     info!("Starting certificate provisioning for: {:?}", config.domains);
 
-    // Try to load existing account credentials, or create new account
-    let account = match &config.account_path {
-        Some(path) if path.exists() => {
-            let creds_json = std::fs::read_to_string(path)?;
-            let creds: AccountCredentials = serde_json::from_str(&creds_json)?;
-            info!("Loaded existing ACME account");
-            Account::builder()?.from_credentials(creds).await?
+    let account = load_or_create_account(config).await?;
+    let groups = order_groups(config);
+
+    let results = if config.san_order && groups.iter().all(|group| group.len() == 1) {
+        provision_san_certificate(config, &account).await?;
+        config
+            .domains
+            .iter()
+            .map(|domain| (domain.clone(), Ok(())))
+            .collect::<Vec<_>>()
+    } else {
+        if groups.len() > 1 {
+            info!("Ordering certificates in {} concurrent groups: {:?}", groups.len(), groups);
         }
-        _ => {
-            let (account, credentials) = Account::builder()?
-                .create(
-                    &NewAccount {
-                        contact: &[],
-                        terms_of_service_agreed: true,
-                        only_return_existing: false,
-                    },
-                    if config.production {
-                        LetsEncrypt::Production.url().to_owned()
-                    } else {
-                        info!("Using Let's Encrypt STAGING environment");
-                        LetsEncrypt::Staging.url().to_owned()
-                    },
-                    None,
-                )
-                .await?;
-
-            // Save account credentials for future use
-            if let Some(path) = &config.account_path {
-                if let Some(parent) = path.parent() {
-                    std::fs::create_dir_all(parent)?;
-                }
-                std::fs::write(path, serde_json::to_string_pretty(&credentials)?)?;
-                info!("Saved ACME account credentials to {}", path.display());
+        let account = &account;
+        join_all(groups.iter().map(|group| async move {
+            let mut results = Vec::new();
+            for domain in group {
+                results.push((domain.clone(), provision_domain(config, account, domain).await));
             }
+            results
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    };
 
-            info!("Created new ACME account");
-            account
+    let mut first_saved = None;
+    let mut first_error = None;
+    for domain in &config.domains {
+        match results.iter().find(|(d, _)| d == domain).map(|(_, result)| result) {
+            Some(Ok(())) => {
+                first_saved.get_or_insert(domain);
+            }
+            Some(Err(e)) => {
+                warn!("Failed to provision certificate for {}: {}", domain, e);
+                first_error.get_or_insert_with(|| format!("{}: {}", domain, e));
+            }
+            None => {}
         }
+    }
+
+    let Some(first_saved) = first_saved else {
+        return Err(anyhow::anyhow!(
+            "Failed to provision any certificates ({})",
+            first_error.unwrap_or_default()
+        ));
     };
 
-    // Process each domain separately due to DuckDNS TXT record limitation
-    let cert_dir = config.cert_path.parent().unwrap_or(Path::new("."));
-    let mut first_cert_saved = false;
+    // Save the first successful cert as the default
+    if config.save_default {
+        let cert_dir = config.cert_path.parent().unwrap_or(Path::new("."));
+        let (domain_cert_path, domain_key_path) = domain_cert_paths(cert_dir, first_saved);
+        std::fs::copy(&domain_cert_path, &config.cert_path)?;
+        std::fs::copy(&domain_key_path, &config.key_path)?;
+        info!("Default certificate saved to: {}", config.cert_path.display());
+    }
+
+    info!("Certificate provisioning complete!");
+    Ok(())
+}
 
-    for (i, domain) in config.domains.iter().enumerate() {
-        info!("Processing domain {}/{}: {}", i + 1, config.domains.len(), domain);
+/// Split the domains into groups whose orders may run concurrently. Within a group,
+/// domains share the single TXT record slot of their DNS provider and are ordered one
+/// after another; with any other provider or challenge type every domain is its own group.
+fn order_groups(config: &AcmeConfig) -> Vec<Vec<String>> {
+    let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+    for domain in &config.domains {
+        let slot = match &config.challenge {
+            AcmeChallenge::Dns01(provider) if !provider.capabilities().multiple_txt_values => {
+                // A wildcard's record is the one of its zone
+                provider.txt_slot(domain.strip_prefix("*.").unwrap_or(domain))
+            }
+            _ => domain.clone(),
+        };
 
-        // Create order for single domain
-        let identifier = Identifier::Dns(domain.clone());
-        let mut order = account
-            .new_order(&NewOrder::new(&[identifier]))
-            .await?;
+        match groups.iter_mut().find(|(group_slot, _)| *group_slot == slot) {
+            Some((_, group)) => group.push(domain.clone()),
+            None => groups.push((slot, vec![domain.clone()])),
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Retry policy for order status and certificate polling (up to 2 minutes)
+fn order_retry_policy() -> RetryPolicy {
+    RetryPolicy::new()
+        .initial_delay(Duration::from_secs(3))
+        .backoff(1.5)
+        .timeout(Duration::from_secs(120))
+}
 
-        info!("Order created for {}, status: {:?}", domain, order.state().status);
+/// Order a certificate for a single domain and save it to the domain's cert files
+async fn provision_domain(config: &AcmeConfig, account: &Account, domain: &str) -> anyhow::Result<()> {
+    info!("Processing domain {}", domain);
 
-        // Process authorization
-        let mut authorizations = order.authorizations();
-        if let Some(result) = authorizations.next().await {
-            let mut authz = result?;
+    let identifier = Identifier::Dns(domain.to_string());
+    let mut order = account.new_order(&NewOrder::new(&[identifier])).await?;
+    info!("Order created for {}, status: {:?}", domain, order.state().status);
 
-            if authz.status == AuthorizationStatus::Pending {
+    let mut challenges = Vec::new();
+    let result = complete_order(config, &mut order, &mut challenges).await;
+    for (challenge_domain, value) in &challenges {
+        cleanup_challenge(config, challenge_domain, value).await;
+    }
+    let (cert_pem, key_pem) = result?;
+
+    save_domain_cert(config, domain, &cert_pem, &key_pem)?;
+    info!("Certificate obtained for {}", domain);
+    Ok(())
+}
+
+/// Order one certificate covering all domains and save it to every domain's cert files
+async fn provision_san_certificate(config: &AcmeConfig, account: &Account) -> anyhow::Result<()> {
+    info!("Ordering one certificate for {:?}", config.domains);
+
+    let identifiers = config
+        .domains
+        .iter()
+        .map(|domain| Identifier::Dns(domain.clone()))
+        .collect::<Vec<_>>();
+    let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+    info!("Order created, status: {:?}", order.state().status);
+
+    let mut challenges = Vec::new();
+    let result = complete_order(config, &mut order, &mut challenges).await;
+    for (challenge_domain, value) in &challenges {
+        cleanup_challenge(config, challenge_domain, value).await;
+    }
+    let (cert_pem, key_pem) = result?;
+
+    for domain in &config.domains {
+        save_domain_cert(config, domain, &cert_pem, &key_pem)?;
+    }
+    info!("Certificate obtained for {:?}", config.domains);
+    Ok(())
+}
+
+/// Answer every pending authorization of the order, then finalize it. The published
+/// challenges are added to `challenges` as they are created so the caller can remove
+/// them again whether or not the order succeeds. Returns the certificate chain and key.
+async fn complete_order(
+    config: &AcmeConfig,
+    order: &mut Order,
+    challenges: &mut Vec<(String, String)>,
+) -> anyhow::Result<(String, String)> {
+    let mut authorizations = order.authorizations();
+    while let Some(result) = authorizations.next().await {
+        let mut authz = result?;
+        if authz.status != AuthorizationStatus::Pending {
+            continue;
+        }
+
+        let domain = match authz.identifier().identifier {
+            Identifier::Dns(domain) => domain.clone(),
+            identifier => return Err(anyhow::anyhow!("Unsupported identifier {:?}", identifier)),
+        };
+
+        // The value to remove afterwards: the TXT value (DNS-01), the token (HTTP-01)
+        // or the domain (TLS-ALPN-01)
+        match &config.challenge {
+            AcmeChallenge::Dns01(dns_provider) => {
                 let mut challenge = authz
                     .challenge(ChallengeType::Dns01)
                     .ok_or_else(|| anyhow::anyhow!("No DNS-01 challenge found"))?;
 
-                let txt_value = challenge.key_authorization().dns_value();
+                let value = challenge.key_authorization().dns_value();
 
                 info!("Setting TXT record for {}", domain);
-                set_duckdns_txt(domain, &config.duckdns_token, &txt_value).await?;
+                dns_provider.present(&domain, &value).await?;
+                challenges.push((domain.clone(), value.clone()));
+                config.propagation.wait(&domain, &value).await;
+
+                challenge.set_ready().await?;
+            }
+            AcmeChallenge::Http01(tokens) => {
+                let mut challenge = authz
+                    .challenge(ChallengeType::Http01)
+                    .ok_or_else(|| anyhow::anyhow!("No HTTP-01 challenge found"))?;
 
-                wait_for_dns_propagation(config.dns_wait_seconds).await;
+                info!("Serving HTTP-01 token for {}", domain);
+                tokens.insert(&challenge.token, challenge.key_authorization().as_str());
+                challenges.push((domain.clone(), challenge.token.clone()));
 
                 challenge.set_ready().await?;
-                info!("Challenge marked ready for {}", domain);
             }
-        }
-        drop(authorizations);
-
-        // Wait for order to become ready with longer timeout (2 minutes)
-        info!("Waiting for order to become ready for {}...", domain);
-        let retry_policy = RetryPolicy::new()
-            .initial_delay(Duration::from_secs(3))
-            .backoff(1.5)
-            .timeout(Duration::from_secs(120));
-        let status = order.poll_ready(&retry_policy).await?;
-
-        if status != OrderStatus::Ready {
-            warn!("Order failed for {} with status: {:?}, skipping", domain, status);
-            clear_duckdns_txt(domain, &config.duckdns_token).await?;
-            continue;
-        }
+            AcmeChallenge::TlsAlpn01(certs) => {
+                let mut challenge = authz
+                    .challenge(ChallengeType::TlsAlpn01)
+                    .ok_or_else(|| anyhow::anyhow!("No TLS-ALPN-01 challenge found"))?;
 
-        // Finalize and get certificate
-        info!("Finalizing order for {}...", domain);
-        let key_pem = order.finalize().await?;
-        let cert_pem = order.poll_certificate(&retry_policy).await?;
-
-        // Save domain-specific cert files
-        let subdomain = domain.strip_suffix(".duckdns.org").unwrap_or(domain);
-        let domain_cert_path = cert_dir.join(format!("{}_cert.pem", subdomain));
-        let domain_key_path = cert_dir.join(format!("{}_key.pem", subdomain));
-        
-        std::fs::create_dir_all(cert_dir)?;
-        std::fs::write(&domain_cert_path, &cert_pem)?;
-        std::fs::write(&domain_key_path, &key_pem)?;
-        info!("Certificate for {} saved to {}", domain, domain_cert_path.display());
-
-        // Save first successful cert as the default
-        if !first_cert_saved {
-            std::fs::write(&config.cert_path, &cert_pem)?;
-            std::fs::write(&config.key_path, &key_pem)?;
-            info!("Default certificate saved to: {}", config.cert_path.display());
-            first_cert_saved = true;
-        }
+                info!("Presenting TLS-ALPN-01 certificate for {}", domain);
+                certs.insert(&domain, challenge.key_authorization().digest().as_ref())?;
+                challenges.push((domain.clone(), domain.clone()));
 
-        info!("Certificate obtained for {}", domain);
-        clear_duckdns_txt(domain, &config.duckdns_token).await?;
+                challenge.set_ready().await?;
+            }
+        }
+        info!("Challenge marked ready for {}", domain);
     }
 
-    if !first_cert_saved {
-        return Err(anyhow::anyhow!("Failed to provision any certificates"));
+    info!("Waiting for order to become ready...");
+    let retry_policy = order_retry_policy();
+    let status = order.poll_ready(&retry_policy).await?;
+    if status != OrderStatus::Ready {
+        return Err(anyhow::anyhow!("Order failed with status: {:?}", status));
     }
 
-    info!("Certificate provisioning complete!");
+    info!("Finalizing order...");
+    let key_pem = order.finalize().await?;
+    let cert_pem = order.poll_certificate(&retry_policy).await?;
+    Ok((cert_pem, key_pem))
+}
+
+/// Write a certificate and key to the per-domain files
+fn save_domain_cert(config: &AcmeConfig, domain: &str, cert_pem: &str, key_pem: &str) -> anyhow::Result<()> {
+    let cert_dir = config.cert_path.parent().unwrap_or(Path::new("."));
+    let (domain_cert_path, domain_key_path) = domain_cert_paths(cert_dir, domain);
+
+    std::fs::create_dir_all(cert_dir)?;
+    std::fs::write(&domain_cert_path, cert_pem)?;
+    std::fs::write(&domain_key_path, key_pem)?;
+    info!("Certificate for {} saved to {}", domain, domain_cert_path.display());
     Ok(())
 }
 
-/// Set TXT record via DuckDNS API
-async fn set_duckdns_txt(domain: &str, token: &str, txt_value: &str) -> anyhow::Result<()> {
-    let subdomain = domain.strip_suffix(".duckdns.org").unwrap_or(domain);
+/// Remove a challenge TXT record, HTTP-01 token or TLS-ALPN-01 certificate, logging rather than failing if
+/// that doesn't work
+async fn cleanup_challenge(config: &AcmeConfig, domain: &str, value: &str) {
+    match &config.challenge {
+        AcmeChallenge::Dns01(dns_provider) => {
+            if let Err(e) = dns_provider.cleanup(domain, value).await {
+                warn!("Failed to remove challenge TXT record for {}: {}", domain, e);
+            }
+        }
+        AcmeChallenge::Http01(tokens) => tokens.remove(value),
+        AcmeChallenge::TlsAlpn01(certs) => certs.remove(value),
+    }
+}
 
-    let url = format!(
-        "https://www.duckdns.org/update?domains={}&token={}&txt={}&verbose=true",
-        subdomain, token, txt_value
-    );
 
-    info!("Setting DuckDNS TXT record for {}", subdomain);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_parser::extensions::GeneralName;
 
-    let response = reqwest::get(&url).await?.text().await?;
+    /// id-pe-acmeIdentifier (RFC 8737)
+    const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
 
-    if response.starts_with("OK") {
-        info!(
-            "DuckDNS TXT record set successfully: {}",
-            response.replace('\n', " ")
-        );
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("DuckDNS API error: {}", response))
-    }
-}
+    #[test]
+    fn tls_alpn01_certificate_carries_acme_identifier() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let digest = [7u8; 32];
+        let certs = TlsAlpn01Certs::default();
+        certs.insert("Example.com", &digest).unwrap();
 
-/// Clear TXT record via DuckDNS API
-async fn clear_duckdns_txt(domain: &str, token: &str) -> anyhow::Result<()> {
-    let subdomain = domain.strip_suffix(".duckdns.org").unwrap_or(domain);
+        let key = certs.get("example.COM").unwrap();
+        let (_, cert) = x509_parser::parse_x509_certificate(key.end_entity_cert().unwrap()).unwrap();
 
-    let url = format!(
-        "https://www.duckdns.org/update?domains={}&token={}&txt=&clear=true",
-        subdomain, token
-    );
+        let extension = cert
+            .extensions()
+            .iter()
+            .find(|extension| extension.oid.to_id_string() == ACME_IDENTIFIER_OID)
+            .unwrap();
+        assert!(extension.critical);
+        // DER OCTET STRING holding the SHA-256 digest of the key authorization
+        assert_eq!(extension.value, [&[0x04, 0x20][..], &digest].concat());
 
-    let response = reqwest::get(&url).await?.text().await?;
+        let names = cert.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(names.value.general_names, vec![GeneralName::DNSName("Example.com")]);
 
-    if response.starts_with("OK") {
-        info!("DuckDNS TXT record cleared for {}", subdomain);
-        Ok(())
-    } else {
-        warn!("Failed to clear TXT record: {}", response);
-        Ok(())
+        certs.remove("EXAMPLE.com");
+        assert!(certs.get("example.com").is_none());
+    }
+
+    #[test]
+    fn wildcard_coverage() {
+        for (san, domain, covered) in [
+            ("example.com", "example.com", true),
+            ("Example.COM", "example.com", true),
+            ("example.com", "www.example.com", false),
+            ("*.example.com", "www.example.com", true),
+            ("*.example.com", "WWW.Example.com", true),
+            ("*.example.com", "example.com", false),
+            ("*.example.com", "a.b.example.com", false),
+            ("*.example.com", ".example.com", false),
+            ("*.example.com", "www.example.org", false),
+            ("*.example.com", "*.example.com", true),
+            ("*.example.com", "*.b.example.com", false),
+            ("www.example.com", "*.example.com", false),
+        ] {
+            assert_eq!(san_covers(san, domain), covered, "{} covers {}", san, domain);
+        }
     }
-}
 
-/// Wait for DNS propagation
-async fn wait_for_dns_propagation(seconds: u64) {
-    info!("Waiting {}s for DNS propagation...", seconds);
-    tokio::time::sleep(Duration::from_secs(seconds)).await;
+    #[test]
+    fn cert_paths() {
+        let dir = Path::new("/certs");
+        for (domain, name) in [
+            ("example.com", "example.com"),
+            ("home.duckdns.org", "home"),
+            ("*.example.com", "_wildcard.example.com"),
+            ("*.home.duckdns.org", "_wildcard.home"),
+            ("a/../b.example.com", "a_.._b.example.com"),
+        ] {
+            let (cert, key) = domain_cert_paths(dir, domain);
+            assert_eq!(cert, dir.join(format!("{}_cert.pem", name)), "{}", domain);
+            assert_eq!(key, dir.join(format!("{}_key.pem", name)), "{}", domain);
+        }
+    }
 }
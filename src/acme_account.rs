@@ -0,0 +1,95 @@
+use base64::Engine;
+use instant_acme::{AccountCredentials, ExternalAccountKey, LetsEncrypt};
+use serde::{Deserialize, Serialize};
+
+/// Contents of an account file: the credentials and the contacts last registered
+/// with the CA, so they are only updated when the configured ones change
+#[derive(Serialize, Deserialize)]
+pub struct SavedAccount {
+    #[serde(flatten)]
+    pub credentials: AccountCredentials,
+    /// Missing in files written by older versions, which then update the contacts once
+    #[serde(default)]
+    pub contact: Vec<String>,
+}
+
+/// Directory URL of the CA and the name of the file its account is saved in.
+/// Accounts only exist at the CA that created them, so every CA gets its own file:
+/// `account.json` for Let's Encrypt production, `account_staging.json` for staging
+/// and `account_<host>.json` for a custom directory.
+pub fn directory(acme_directory: Option<&str>, production: bool) -> anyhow::Result<(String, String)> {
+    Ok(match acme_directory {
+        Some(url) => {
+            let host = url
+                .split("://")
+                .nth(1)
+                .and_then(|rest| rest.split('/').next())
+                .filter(|host| !host.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("Invalid acme_directory URL: {}", url))?;
+            let host = host.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-', "_");
+            (url.to_string(), format!("account_{}.json", host))
+        }
+        None if production => (LetsEncrypt::Production.url().to_owned(), "account.json".to_string()),
+        None => (LetsEncrypt::Staging.url().to_owned(), "account_staging.json".to_string()),
+    })
+}
+
+/// Contact URLs for the configured addresses, adding `mailto:` where it is missing
+pub fn contact_urls(contact: &[String]) -> Vec<String> {
+    contact
+        .iter()
+        .map(|email| match email.starts_with("mailto:") {
+            true => email.clone(),
+            false => format!("mailto:{}", email),
+        })
+        .collect()
+}
+
+/// External Account Binding key from the key identifier and base64url HMAC key issued by the CA
+pub fn eab_key(key_id: &str, hmac_key: &str) -> anyhow::Result<ExternalAccountKey> {
+    let hmac_key = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(hmac_key.trim_end_matches('='))
+        .map_err(|e| anyhow::anyhow!("Invalid EAB HMAC key: {}", e))?;
+    Ok(ExternalAccountKey::new(key_id.to_string(), &hmac_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_ca_has_its_own_account_file() {
+        let files = [
+            directory(None, true).unwrap(),
+            directory(None, false).unwrap(),
+            directory(Some("https://acme.zerossl.com/v2/DV90"), false).unwrap(),
+        ];
+        assert_eq!(files[0].1, "account.json");
+        assert_eq!(files[1].1, "account_staging.json");
+        assert_eq!(files[2].0, "https://acme.zerossl.com/v2/DV90");
+        assert_eq!(files[2].1, "account_acme.zerossl.com.json");
+        assert_ne!(files[0].0, files[1].0);
+        assert!(directory(Some("acme.zerossl.com"), false).is_err());
+    }
+
+    #[test]
+    fn contacts_get_mailto() {
+        let contact = vec!["admin@example.com".to_string(), "mailto:ops@example.com".to_string()];
+        assert_eq!(contact_urls(&contact), ["mailto:admin@example.com", "mailto:ops@example.com"]);
+    }
+
+    #[test]
+    fn saved_account_without_contacts() {
+        // As written by older versions: the plain credentials
+        let json = r#"{"id":"https://ca.example/acct/1","key_pkcs8":"AAAA","directory":"https://ca.example/dir"}"#;
+        let saved: SavedAccount = serde_json::from_str(json).unwrap();
+        assert!(saved.contact.is_empty());
+
+        let saved = SavedAccount {
+            contact: vec!["mailto:admin@example.com".to_string()],
+            ..saved
+        };
+        let saved: SavedAccount = serde_json::from_str(&serde_json::to_string(&saved).unwrap()).unwrap();
+        assert_eq!(saved.contact, ["mailto:admin@example.com"]);
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use async_trait::async_trait;
+use base64::Engine;
+use log::{info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::time::MissedTickBehavior;
+
+use crate::proxy::RouteTable;
+
+/// How often the htpasswd files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Password hashes verified at the same time, one per CPU, so a flood of wrong
+/// passwords queues up instead of occupying every blocking thread
+static VERIFICATIONS: LazyLock<Semaphore> = LazyLock::new(|| {
+    Semaphore::new(std::thread::available_parallelism().map_or(4, |cpus| cpus.get()))
+});
+
+/// HTTP Basic authentication in front of a domain or route
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BasicAuthConfig {
+    /// htpasswd file with bcrypt ("htpasswd -B") or argon2 hashes, reloaded on change
+    pub htpasswd: String,
+    /// Optional: Realm shown in the browser's login prompt (default: "Restricted")
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// Optional: Forward the `Authorization` header to the backend (default: false)
+    #[serde(default)]
+    pub passthrough: bool,
+}
+
+fn default_realm() -> String { "Restricted".to_string() }
+
+/// Users and password hashes of an htpasswd file
+struct Credentials {
+    users: HashMap<String, String>,
+    /// Hash checked for unknown users, so they take as long to reject as wrong
+    /// passwords and don't reveal which users exist
+    dummy: Option<String>,
+    /// Digests of user/password pairs that passed, so each pair is hashed only once
+    verified: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl Credentials {
+    fn load(path: &str) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read htpasswd file {}: {}", path, e))?;
+        let mut users = HashMap::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, hash)) = line.split_once(':') else {
+                return Err(anyhow::anyhow!("Invalid line in htpasswd file {}: no ':'", path));
+            };
+            if !is_supported(hash) {
+                // MD5 (apr1), SHA1 and crypt() hashes are too cheap to brute force
+                warn!("Ignoring user {} in {}: use bcrypt (htpasswd -B) or argon2 hashes", user, path);
+                continue;
+            }
+            users.insert(user.to_string(), hash.to_string());
+        }
+        Ok(Self {
+            dummy: users.values().next().cloned(),
+            users,
+            verified: Mutex::new(HashSet::new()),
+        })
+    }
+}
+
+fn is_supported(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$", "$argon2"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+/// [`verify`] on a blocking thread, as password hashes are slow on purpose and would
+/// stall the proxy's threads
+async fn verify_blocking(password: String, hash: String) -> bool {
+    let Ok(_permit) = VERIFICATIONS.acquire().await else {
+        return false;
+    };
+    tokio::task::spawn_blocking(move || verify(&password, &hash))
+        .await
+        .unwrap_or(false)
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+/// User and password of a `Basic` `Authorization` header value
+fn parse_basic(authorization: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(credentials.trim())
+        .ok()?;
+    let (user, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Checks Basic credentials against an htpasswd file
+pub struct BasicAuth {
+    path: String,
+    realm: String,
+    /// Whether the backend gets the `Authorization` header
+    pub passthrough: bool,
+    credentials: RwLock<Arc<Credentials>>,
+    /// Modification time of the file when it was last loaded
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl std::fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("path", &self.path)
+            .field("realm", &self.realm)
+            .field("passthrough", &self.passthrough)
+            .finish()
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl BasicAuth {
+    pub fn new(config: &BasicAuthConfig) -> anyhow::Result<Self> {
+        let modified = modified(&config.htpasswd);
+        Ok(Self {
+            path: config.htpasswd.clone(),
+            realm: config.realm.clone(),
+            passthrough: config.passthrough,
+            credentials: RwLock::new(Arc::new(Credentials::load(&config.htpasswd)?)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reload the credentials if the file changed. A file that can't be read keeps
+    /// the previous credentials in use.
+    fn reload(&self) {
+        let modified = modified(&self.path);
+        {
+            let mut loaded = self.modified.lock().unwrap();
+            if *loaded == modified {
+                return;
+            }
+            *loaded = modified;
+        }
+
+        match Credentials::load(&self.path) {
+            Ok(credentials) => {
+                info!("Reloaded {} ({} users)", self.path, credentials.users.len());
+                *self.credentials.write().unwrap() = Arc::new(credentials);
+            }
+            Err(e) => warn!("{}, keeping the previous credentials", e),
+        }
+    }
+
+    /// The user of a valid `Authorization` header value, `None` if missing or wrong
+    pub async fn authenticate(&self, authorization: Option<&str>) -> Option<String> {
+        let (user, password) = parse_basic(authorization?)?;
+        let credentials = self.credentials.read().unwrap().clone();
+        let Some(hash) = credentials.users.get(&user).cloned() else {
+            if let Some(dummy) = credentials.dummy.clone() {
+                verify_blocking(password, dummy).await;
+            }
+            return None;
+        };
+
+        let mut pair = digest::Context::new(&digest::SHA256);
+        pair.update(user.as_bytes());
+        pair.update(b"\0");
+        pair.update(password.as_bytes());
+        pair.update(b"\0");
+        pair.update(hash.as_bytes());
+        let pair = pair.finish().as_ref().to_vec();
+        if credentials.verified.lock().unwrap().contains(&pair) {
+            return Some(user);
+        }
+
+        if !verify_blocking(password, hash).await {
+            return None;
+        }
+        credentials.verified.lock().unwrap().insert(pair);
+        Some(user)
+    }
+
+    /// `WWW-Authenticate` value asking the browser for credentials
+    pub fn challenge(&self) -> String {
+        format!(
+            "Basic realm=\"{}\", charset=\"UTF-8\"",
+            self.realm.replace('\\', "\\\\").replace('"', "\\\"")
+        )
+    }
+}
+
+/// Background service reloading the htpasswd files of all routes when they change
+pub struct HtpasswdReloadService {
+    routes: Arc<RouteTable>,
+}
+
+impl HtpasswdReloadService {
+    pub fn new(routes: Arc<RouteTable>) -> Self {
+        Self { routes }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for HtpasswdReloadService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = interval.tick() => {}
+            }
+            for auth in self.routes.basic_auths() {
+                auth.reload();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::proxy::ProxyConfig;
+    use crate::resolver::Resolver;
+
+    fn htpasswd(name: &str, users: &[(&str, &str)]) -> String {
+        let path = std::env::temp_dir().join(format!("pingora-htpasswd-{}-{}", std::process::id(), name));
+        let content: String = users
+            .iter()
+            .map(|(user, password)| format!("{}:{}\n", user, bcrypt::hash(password, 4).unwrap()))
+            .collect();
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+        format!("Basic {}", credentials)
+    }
+
+    fn basic_auth(path: &str) -> BasicAuth {
+        BasicAuth::new(&serde_json::from_value(json!({"htpasswd": path})).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn authenticate() {
+        let path = htpasswd("authenticate", &[("alice", "secret")]);
+        let auth = basic_auth(&path);
+
+        assert_eq!(auth.authenticate(Some(&basic("alice", "secret"))).await.as_deref(), Some("alice"));
+        // Verified again from the cache of passing pairs
+        assert_eq!(auth.authenticate(Some(&basic("alice", "secret"))).await.as_deref(), Some("alice"));
+        assert_eq!(auth.authenticate(Some(&basic("alice", "wrong"))).await, None);
+        assert_eq!(auth.authenticate(Some("Bearer token")).await, None);
+        assert_eq!(auth.authenticate(None).await, None);
+
+        // Unknown users are checked against a real hash, which never lets them in,
+        // not even with the password of the user it belongs to
+        let credentials = auth.credentials.read().unwrap().clone();
+        assert!(credentials.dummy.is_some());
+        assert_eq!(auth.authenticate(Some(&basic("mallory", "secret"))).await, None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn changes_are_loaded_by_the_service() {
+        let path = htpasswd("reload", &[("alice", "secret")]);
+        let config = json!({
+            "listen_addr": "127.0.0.1:0",
+            "domains": {
+                "app.example.com": {
+                    "host": "127.0.0.1",
+                    "port": 8080,
+                    "auth": {"htpasswd": path},
+                    "routes": [{"path_prefix": "/api", "host": "127.0.0.1", "port": 8081}]
+                }
+            }
+        });
+        let config: ProxyConfig = serde_json::from_value(config).unwrap();
+        let resolver = Arc::new(Resolver::new(&config.resolver).unwrap());
+        let routes = Arc::new(RouteTable::new(&config, resolver).unwrap());
+        // The route shares the domain's credentials
+        assert_eq!(routes.basic_auths().len(), 1);
+        let auth = routes.find_backend("app.example.com", "/api").unwrap().auth.clone().unwrap();
+
+        // Requests never read the file, only the service does
+        htpasswd("reload", &[("bob", "hunter2")]);
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        tokio::time::sleep(RELOAD_INTERVAL * 2).await;
+        assert_eq!(auth.authenticate(Some(&basic("alice", "secret"))).await.as_deref(), Some("alice"));
+        assert_eq!(auth.authenticate(Some(&basic("bob", "hunter2"))).await, None);
+
+        let (shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+        let service = tokio::spawn(async move { HtpasswdReloadService::new(routes).start(shutdown).await });
+        tokio::time::sleep(RELOAD_INTERVAL / 2).await;
+        assert_eq!(auth.authenticate(Some(&basic("bob", "hunter2"))).await.as_deref(), Some("bob"));
+        assert_eq!(auth.authenticate(Some(&basic("alice", "secret"))).await, None);
+
+        shutdown_tx.send(true).unwrap();
+        service.await.unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
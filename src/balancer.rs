@@ -0,0 +1,502 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::warn;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::Extensions;
+use pingora::lb::health_check::HealthCheck;
+use pingora::lb::selection::{BackendIter, BackendSelection, Consistent, Random, RoundRobin};
+use pingora::lb::{Backend, Backends, LoadBalancer};
+use serde::{Deserialize, Serialize};
+
+use crate::resolver::Resolver;
+
+/// How many backends a selection may look at before giving up
+const MAX_SELECT_ITERATIONS: usize = 256;
+
+/// One upstream address of a balanced backend
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UpstreamConfig {
+    /// Hostname or IP of the upstream (can be Docker container name)
+    pub host: String,
+    /// Port the upstream is listening on
+    pub port: u16,
+    /// Optional: Relative share of the traffic (default: 1)
+    #[serde(default = "default_weight")]
+    pub weight: usize,
+}
+
+fn default_weight() -> usize { 1 }
+
+/// Algorithm spreading requests over the upstreams of a backend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    #[default]
+    RoundRobin,
+    Random,
+    LeastConnections,
+    /// Ketama consistent hashing of the request's [`HashKey`]
+    Ketama,
+}
+
+impl std::fmt::Display for LoadBalancing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LoadBalancing::RoundRobin => "round_robin",
+            LoadBalancing::Random => "random",
+            LoadBalancing::LeastConnections => "least_connections",
+            LoadBalancing::Ketama => "ketama",
+        })
+    }
+}
+
+/// Part of the request hashed by [`LoadBalancing::Ketama`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HashKey {
+    ClientIp,
+    Header(String),
+    Cookie(String),
+}
+
+impl HashKey {
+    /// Parse "client_ip", "header:<name>" or "cookie:<name>"
+    pub fn parse(key: &str) -> anyhow::Result<Self> {
+        match key.split_once(':') {
+            None if key == "client_ip" => Ok(HashKey::ClientIp),
+            Some(("header", name)) if !name.is_empty() => Ok(HashKey::Header(name.to_string())),
+            Some(("cookie", name)) if !name.is_empty() => Ok(HashKey::Cookie(name.to_string())),
+            _ => Err(anyhow::anyhow!(
+                "Invalid hash_key {:?}, expected client_ip, header:<name> or cookie:<name>",
+                key
+            )),
+        }
+    }
+}
+
+/// Hostname an upstream was resolved from, kept in the [`Backend`] extensions for SNI
+#[derive(Debug, Clone)]
+pub struct UpstreamHost(pub String);
+
+/// Requests in flight per upstream address, shared by both listeners
+#[derive(Debug, Default)]
+pub struct ActiveConnections {
+    counts: RwLock<HashMap<SocketAddr, Arc<AtomicUsize>>>,
+}
+
+impl ActiveConnections {
+    fn counter(&self, addr: SocketAddr) -> Arc<AtomicUsize> {
+        if let Some(counter) = self.counts.read().unwrap().get(&addr) {
+            return counter.clone();
+        }
+        self.counts.write().unwrap().entry(addr).or_default().clone()
+    }
+
+    /// Number of requests currently proxied to `addr`
+    pub fn get(&self, addr: &SocketAddr) -> usize {
+        self.counts
+            .read()
+            .unwrap()
+            .get(addr)
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    /// Count a request to `addr` until the returned guard is dropped
+    pub fn acquire(&self, addr: SocketAddr) -> ConnectionGuard {
+        let counter = self.counter(addr);
+        counter.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { counter }
+    }
+}
+
+/// A request counted in [`ActiveConnections`], released on drop
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Least-connections selection: the upstream with the fewest requests in flight
+/// relative to its weight, rotating between equally loaded upstreams
+pub struct LeastConnections {
+    backends: Vec<Backend>,
+    connections: Arc<ActiveConnections>,
+    next: AtomicUsize,
+}
+
+impl BackendSelection for LeastConnections {
+    type Iter = LeastConnectionsIter;
+    type Config = Arc<ActiveConnections>;
+
+    fn build_with_config(backends: &BTreeSet<Backend>, connections: &Self::Config) -> Self {
+        LeastConnections {
+            backends: backends.iter().cloned().collect(),
+            connections: connections.clone(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        Self::build_with_config(backends, &Arc::default())
+    }
+
+    fn iter(self: &Arc<Self>, _key: &[u8]) -> Self::Iter {
+        let load = |backend: &Backend| {
+            let active = backend
+                .as_inet()
+                .map_or(0, |addr| self.connections.get(addr));
+            (active, backend.weight.max(1))
+        };
+
+        let mut backends: Vec<_> = self.backends.iter().map(|b| (load(b), b.clone())).collect();
+        if !backends.is_empty() {
+            let offset = self.next.fetch_add(1, Ordering::Relaxed) % backends.len();
+            backends.rotate_left(offset);
+        }
+        // Compare active/weight without dividing; the sort is stable, so ties keep the rotation
+        backends.sort_by(|((a, wa), _), ((b, wb), _)| (a * wb).cmp(&(b * wa)));
+
+        LeastConnectionsIter {
+            backends: backends.into_iter().map(|(_, backend)| backend).collect(),
+            index: 0,
+        }
+    }
+}
+
+pub struct LeastConnectionsIter {
+    backends: Vec<Backend>,
+    index: usize,
+}
+
+impl BackendIter for LeastConnectionsIter {
+    fn next(&mut self) -> Option<&Backend> {
+        let backend = self.backends.get(self.index);
+        self.index += 1;
+        backend
+    }
+}
+
+enum Selector {
+    RoundRobin(LoadBalancer<RoundRobin>),
+    Random(LoadBalancer<Random>),
+    LeastConnections(LoadBalancer<LeastConnections>),
+    Ketama(LoadBalancer<Consistent>),
+}
+
+/// Health of one upstream address, as last determined by the health checks
+#[derive(Debug, Clone)]
+pub struct UpstreamHealth {
+    /// Hostname the address was resolved from
+    pub host: String,
+    pub addr: SocketAddr,
+    pub healthy: bool,
+}
+
+/// The upstreams of a backend with the load balancer choosing between them
+pub struct Upstreams {
+    selector: Selector,
+    pub algorithm: LoadBalancing,
+    /// What ketama hashes, `None` for the other algorithms
+    pub hash_key: Option<HashKey>,
+    pub connections: Arc<ActiveConnections>,
+    /// How often the health check runs, `None` without health check
+    pub health_check_interval: Option<Duration>,
+}
+
+impl std::fmt::Debug for Upstreams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Upstreams")
+            .field("algorithm", &self.algorithm)
+            .field("hash_key", &self.hash_key)
+            .finish()
+    }
+}
+
+/// Service discovery for the configured upstreams: every address of their hostnames
+/// becomes a backend, re-resolved through the [`Resolver`] cache on each update
+struct ResolvedUpstreams {
+    upstreams: Vec<UpstreamConfig>,
+    resolver: Arc<Resolver>,
+}
+
+#[async_trait]
+impl ServiceDiscovery for ResolvedUpstreams {
+    async fn discover(&self) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let mut backends = BTreeSet::new();
+        for upstream in &self.upstreams {
+            for ip in self.resolver.resolve(&upstream.host).await {
+                let mut ext = Extensions::new();
+                ext.insert(UpstreamHost(upstream.host.clone()));
+                backends.insert(Backend {
+                    addr: pingora::protocols::l4::socket::SocketAddr::Inet(SocketAddr::new(ip, upstream.port)),
+                    weight: upstream.weight,
+                    ext,
+                });
+            }
+        }
+        Ok((backends, HashMap::new()))
+    }
+}
+
+type BoxedHealthCheck = Box<dyn HealthCheck + Send + Sync>;
+
+fn load_balancer<S>(
+    discovery: ResolvedUpstreams,
+    config: Option<S::Config>,
+    health_check: Option<BoxedHealthCheck>,
+) -> LoadBalancer<S>
+where
+    S: BackendSelection + 'static,
+    S::Iter: BackendIter,
+{
+    let mut lb = LoadBalancer::from_backends_with_config(Backends::new(Box::new(discovery)), config);
+    if let Some(health_check) = health_check {
+        lb.set_health_check(health_check);
+    }
+    lb
+}
+
+impl Upstreams {
+    pub fn new(
+        upstreams: &[UpstreamConfig],
+        algorithm: LoadBalancing,
+        hash_key: Option<&str>,
+        health_check: Option<(BoxedHealthCheck, Duration)>,
+        resolver: Arc<Resolver>,
+    ) -> anyhow::Result<Self> {
+        if upstreams.iter().any(|upstream| upstream.weight == 0) {
+            return Err(anyhow::anyhow!("Upstream weights must be at least 1"));
+        }
+        let hash_key = match (algorithm, hash_key) {
+            (LoadBalancing::Ketama, Some(key)) => Some(HashKey::parse(key)?),
+            (LoadBalancing::Ketama, None) => Some(HashKey::ClientIp),
+            (_, Some(_)) => {
+                warn!("hash_key is only used with \"ketama\" load balancing, ignoring it");
+                None
+            }
+            (_, None) => None,
+        };
+
+        let backends = ResolvedUpstreams {
+            upstreams: upstreams.to_vec(),
+            resolver,
+        };
+        let connections = Arc::new(ActiveConnections::default());
+        let (health_check, health_check_interval) = health_check.unzip();
+        let selector = match algorithm {
+            LoadBalancing::RoundRobin => {
+                Selector::RoundRobin(load_balancer(backends, None, health_check))
+            }
+            LoadBalancing::Random => Selector::Random(load_balancer(backends, None, health_check)),
+            LoadBalancing::LeastConnections => Selector::LeastConnections(load_balancer(
+                backends,
+                Some(connections.clone()),
+                health_check,
+            )),
+            LoadBalancing::Ketama => Selector::Ketama(load_balancer(backends, None, health_check)),
+        };
+
+        Ok(Upstreams {
+            selector,
+            algorithm,
+            hash_key,
+            connections,
+            health_check_interval,
+        })
+    }
+
+    /// Resolve the upstream hostnames (cached) and update the balanced addresses.
+    /// Until the first update there are no upstreams to select.
+    pub async fn update(&self) {
+        let result = match &self.selector {
+            Selector::RoundRobin(lb) => lb.update().await,
+            Selector::Random(lb) => lb.update().await,
+            Selector::LeastConnections(lb) => lb.update().await,
+            Selector::Ketama(lb) => lb.update().await,
+        };
+        if let Err(e) = result {
+            warn!("Updating upstreams failed: {}", e);
+        }
+    }
+
+    fn backends(&self) -> &Backends {
+        match &self.selector {
+            Selector::RoundRobin(lb) => lb.backends(),
+            Selector::Random(lb) => lb.backends(),
+            Selector::LeastConnections(lb) => lb.backends(),
+            Selector::Ketama(lb) => lb.backends(),
+        }
+    }
+
+    /// Check every upstream once, updating their health
+    pub async fn run_health_check(&self) {
+        self.backends().run_health_check(true).await
+    }
+
+    /// Current health of every upstream
+    pub fn health(&self) -> Vec<UpstreamHealth> {
+        let backends = self.backends();
+        backends
+            .get_backend()
+            .iter()
+            .filter_map(|backend| {
+                Some(UpstreamHealth {
+                    host: backend.ext.get::<UpstreamHost>()?.0.clone(),
+                    addr: *backend.as_inet()?,
+                    healthy: backends.ready(backend),
+                })
+            })
+            .collect()
+    }
+
+    /// The first upstream accepted by `accept`, with whether it is healthy
+    pub fn find<F>(&self, accept: F) -> Option<(Backend, bool)>
+    where
+        F: Fn(&Backend) -> bool,
+    {
+        let backends = self.backends();
+        let found = backends.get_backend().iter().find(|backend| accept(backend))?.clone();
+        let healthy = backends.ready(&found);
+        Some((found, healthy))
+    }
+
+    /// Pick an upstream for a request; `key` is only used by ketama. `accept` is
+    /// given each candidate and whether it is healthy, the first one accepted wins.
+    pub fn select_with<F>(&self, key: &[u8], accept: F) -> Option<Backend>
+    where
+        F: Fn(&Backend, bool) -> bool,
+    {
+        match &self.selector {
+            Selector::RoundRobin(lb) => lb.select_with(key, MAX_SELECT_ITERATIONS, accept),
+            Selector::Random(lb) => lb.select_with(key, MAX_SELECT_ITERATIONS, accept),
+            Selector::LeastConnections(lb) => lb.select_with(key, MAX_SELECT_ITERATIONS, accept),
+            Selector::Ketama(lb) => lb.select_with(key, MAX_SELECT_ITERATIONS, accept),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::ResolverConfig;
+
+    fn upstream(host: &str, weight: usize) -> UpstreamConfig {
+        UpstreamConfig {
+            host: host.to_string(),
+            port: 8080,
+            weight,
+        }
+    }
+
+    async fn upstreams(upstreams: &[UpstreamConfig], algorithm: LoadBalancing) -> Upstreams {
+        let resolver = Arc::new(Resolver::new(&ResolverConfig::default()).unwrap());
+        let upstreams = Upstreams::new(upstreams, algorithm, None, None, resolver).unwrap();
+        upstreams.update().await;
+        upstreams
+    }
+
+    fn addr(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 8080)
+    }
+
+    fn select(upstreams: &Upstreams) -> SocketAddr {
+        *upstreams.select_with(b"", |_, healthy| healthy).unwrap().as_inet().unwrap()
+    }
+
+    #[test]
+    fn hash_keys() {
+        assert_eq!(HashKey::parse("client_ip").unwrap(), HashKey::ClientIp);
+        assert_eq!(HashKey::parse("header:X-User").unwrap(), HashKey::Header("X-User".to_string()));
+        assert_eq!(HashKey::parse("cookie:session").unwrap(), HashKey::Cookie("session".to_string()));
+        for invalid in ["", "client", "header:", "cookie:", "query:id", "client_ip:x"] {
+            assert!(HashKey::parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn hash_key_and_weight_checks() {
+        let resolver = Arc::new(Resolver::new(&ResolverConfig::default()).unwrap());
+        let one = [upstream("10.0.0.1", 1)];
+        let ketama = Upstreams::new(&one, LoadBalancing::Ketama, None, None, resolver.clone()).unwrap();
+        assert_eq!(ketama.hash_key, Some(HashKey::ClientIp));
+        let round_robin = Upstreams::new(&one, LoadBalancing::RoundRobin, Some("client_ip"), None, resolver.clone());
+        assert_eq!(round_robin.unwrap().hash_key, None);
+        assert!(Upstreams::new(&one, LoadBalancing::Ketama, Some("header:"), None, resolver.clone()).is_err());
+        assert!(Upstreams::new(&[upstream("10.0.0.1", 0)], LoadBalancing::RoundRobin, None, None, resolver).is_err());
+    }
+
+    #[tokio::test]
+    async fn weights_share_the_traffic() {
+        let upstreams = upstreams(&[upstream("10.0.0.1", 3), upstream("10.0.0.2", 1)], LoadBalancing::RoundRobin).await;
+        let mut counts = HashMap::new();
+        for _ in 0..400 {
+            *counts.entry(select(&upstreams)).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&addr("10.0.0.1")], 300);
+        assert_eq!(counts[&addr("10.0.0.2")], 100);
+    }
+
+    #[test]
+    fn guard_releases_its_count() {
+        let connections = ActiveConnections::default();
+        let first = connections.acquire(addr("10.0.0.1"));
+        let second = connections.acquire(addr("10.0.0.1"));
+        let _other = connections.acquire(addr("10.0.0.2"));
+        assert_eq!(connections.get(&addr("10.0.0.1")), 2);
+        drop(first);
+        assert_eq!(connections.get(&addr("10.0.0.1")), 1);
+        drop(second);
+        assert_eq!(connections.get(&addr("10.0.0.1")), 0);
+        assert_eq!(connections.get(&addr("10.0.0.2")), 1);
+        assert_eq!(connections.get(&addr("10.0.0.3")), 0);
+    }
+
+    #[tokio::test]
+    async fn least_connections_picks_the_idle_upstream() {
+        let hosts = [upstream("10.0.0.1", 1), upstream("10.0.0.2", 1), upstream("10.0.0.3", 1)];
+        let upstreams = upstreams(&hosts, LoadBalancing::LeastConnections).await;
+
+        let busy = [
+            upstreams.connections.acquire(addr("10.0.0.1")),
+            upstreams.connections.acquire(addr("10.0.0.3")),
+        ];
+        for _ in 0..5 {
+            assert_eq!(select(&upstreams), addr("10.0.0.2"));
+        }
+
+        // Ties rotate
+        drop(busy);
+        let picked = (0..3).map(|_| select(&upstreams)).collect::<BTreeSet<_>>();
+        assert_eq!(picked.len(), 3);
+
+        // Finished requests count no longer, and a guard per selection spreads the load
+        let guards = (0..6)
+            .map(|_| upstreams.connections.acquire(select(&upstreams)))
+            .collect::<Vec<_>>();
+        for host in &hosts {
+            assert_eq!(upstreams.connections.get(&addr(&host.host)), 2);
+        }
+        drop(guards);
+    }
+
+    #[tokio::test]
+    async fn least_connections_is_relative_to_weight() {
+        let upstreams = upstreams(&[upstream("10.0.0.1", 3), upstream("10.0.0.2", 1)], LoadBalancing::LeastConnections).await;
+        let _heavy = [
+            upstreams.connections.acquire(addr("10.0.0.1")),
+            upstreams.connections.acquire(addr("10.0.0.1")),
+        ];
+        let _light = upstreams.connections.acquire(addr("10.0.0.2"));
+        // 2 of 3 is less loaded than 1 of 1
+        assert_eq!(select(&upstreams), addr("10.0.0.1"));
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// When an upstream's circuit opens and for how long
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    /// Optional: Consecutive failed requests that open the circuit (default: 5)
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Optional: Seconds requests fail fast before the upstream is tried again (default: 30)
+    #[serde(default = "default_cooldown")]
+    pub cooldown_seconds: u64,
+    /// Optional: Response statuses counted as failures (default: [502, 503, 504])
+    #[serde(default = "default_failure_statuses")]
+    pub failure_statuses: Vec<u16>,
+}
+
+fn default_failure_threshold() -> u32 { 5 }
+
+fn default_cooldown() -> u64 { 30 }
+
+fn default_failure_statuses() -> Vec<u16> { vec![502, 503, 504] }
+
+#[derive(Debug, Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    /// Set while the circuit is open: requests are refused until then
+    open_until: Option<Instant>,
+}
+
+/// Passive failure tracking for the upstreams of a route, keyed by upstream address.
+///
+/// After `failure_threshold` consecutive failures the circuit opens and the upstream
+/// is skipped for the cool-down. Then a single trial request is let through: success
+/// closes the circuit, failure opens it for another cool-down.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    failure_statuses: Vec<u16>,
+    states: Mutex<HashMap<String, CircuitState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        Self {
+            failure_threshold: config.failure_threshold.max(1),
+            cooldown: Duration::from_secs(config.cooldown_seconds),
+            failure_statuses: config.failure_statuses.clone(),
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the circuit of `upstream` is open, without claiming the trial request
+    pub fn is_open(&self, upstream: &str) -> bool {
+        self.states
+            .lock()
+            .unwrap()
+            .get(upstream)
+            .and_then(|state| state.open_until)
+            .is_some_and(|open_until| Instant::now() < open_until)
+    }
+
+    /// Whether a request may be sent to `upstream`. Once the cool-down is over this
+    /// claims the trial request, keeping the circuit open for everyone else meanwhile.
+    pub fn allows(&self, upstream: &str) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let Some(state) = states.get_mut(upstream) else {
+            return true;
+        };
+        match state.open_until {
+            Some(open_until) if Instant::now() < open_until => false,
+            Some(_) => {
+                state.open_until = Some(Instant::now() + self.cooldown);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Count a response of `upstream`: a failure if its status is one of the
+    /// `failure_statuses`, a success otherwise
+    pub fn record_response(&self, upstream: &str, status: u16) {
+        if self.failure_statuses.contains(&status) {
+            self.record_failure(upstream);
+        } else {
+            self.record_success(upstream);
+        }
+    }
+
+    pub fn record_success(&self, upstream: &str) {
+        let mut states = self.states.lock().unwrap();
+        if let Some(state) = states.remove(upstream)
+            && state.open_until.is_some()
+        {
+            info!("Circuit for upstream {} closed", upstream);
+        }
+    }
+
+    pub fn record_failure(&self, upstream: &str) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(upstream.to_string()).or_default();
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.failure_threshold {
+            warn!(
+                "Circuit for upstream {} open for {}s after {} consecutive failures",
+                upstream,
+                self.cooldown.as_secs(),
+                state.consecutive_failures
+            );
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPSTREAM: &str = "10.0.0.1:8080";
+
+    fn with_cooldown(cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: 3,
+            cooldown,
+            failure_statuses: default_failure_statuses(),
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = with_cooldown(Duration::from_secs(60));
+        breaker.record_failure(UPSTREAM);
+        breaker.record_failure(UPSTREAM);
+        // A success in between starts the count over
+        breaker.record_success(UPSTREAM);
+        breaker.record_failure(UPSTREAM);
+        breaker.record_failure(UPSTREAM);
+        assert!(breaker.allows(UPSTREAM));
+        assert!(!breaker.is_open(UPSTREAM));
+
+        breaker.record_failure(UPSTREAM);
+        assert!(breaker.is_open(UPSTREAM));
+        assert!(!breaker.allows(UPSTREAM));
+        // Other upstreams are not affected
+        assert!(breaker.allows("10.0.0.2:8080"));
+    }
+
+    #[test]
+    fn half_open_lets_one_trial_through() {
+        let breaker = with_cooldown(Duration::from_millis(50));
+        for _ in 0..3 {
+            breaker.record_failure(UPSTREAM);
+        }
+        assert!(!breaker.allows(UPSTREAM));
+
+        // The cool-down expired: one trial, the circuit stays open for the rest
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!breaker.is_open(UPSTREAM));
+        assert!(breaker.allows(UPSTREAM));
+        assert!(!breaker.allows(UPSTREAM));
+        assert!(breaker.is_open(UPSTREAM));
+
+        // A failed trial opens it for another cool-down
+        breaker.record_failure(UPSTREAM);
+        assert!(!breaker.allows(UPSTREAM));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allows(UPSTREAM));
+
+        // A successful one closes it
+        breaker.record_success(UPSTREAM);
+        assert!(breaker.allows(UPSTREAM));
+        assert!(breaker.allows(UPSTREAM));
+        breaker.record_failure(UPSTREAM);
+        assert!(breaker.allows(UPSTREAM));
+    }
+
+    #[test]
+    fn failing_statuses_count_as_failures() {
+        let breaker = with_cooldown(Duration::from_secs(60));
+        for status in [502, 503, 504] {
+            breaker.record_response(UPSTREAM, status);
+        }
+        assert!(breaker.is_open(UPSTREAM));
+
+        let breaker = with_cooldown(Duration::from_secs(60));
+        for status in [502, 503, 500, 504, 404, 502] {
+            breaker.record_response(UPSTREAM, status);
+        }
+        assert!(!breaker.is_open(UPSTREAM));
+
+        let config: CircuitBreakerConfig = serde_json::from_str(r#"{"failure_statuses": [500]}"#).unwrap();
+        let breaker = CircuitBreaker::new(&config);
+        for _ in 0..5 {
+            breaker.record_response(UPSTREAM, 503);
+        }
+        assert!(!breaker.is_open(UPSTREAM));
+        for _ in 0..5 {
+            breaker.record_response(UPSTREAM, 500);
+        }
+        assert!(breaker.is_open(UPSTREAM));
+    }
+}
@@ -0,0 +1,849 @@
+use std::fmt::Debug;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use base64::Engine;
+use hickory_proto::dnssec::rdata::tsig::TsigAlgorithm;
+use hickory_proto::dnssec::tsig::TSigner;
+use hickory_proto::op::{update_message, Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::rdata::TXT;
+use hickory_proto::rr::{Name, RData, Record, RecordSet, RecordType};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::process::Command;
+
+/// Time to wait for a DNS server to answer an update or query
+const DNS_UPDATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay between two rounds of propagation checks
+const PROPAGATION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Name of the TXT record that answers the DNS-01 challenge for a domain
+pub fn challenge_record_name(domain: &str) -> String {
+    format!("_acme-challenge.{}", domain)
+}
+
+/// What a DNS provider can publish at the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DnsCapabilities {
+    /// Several challenge TXT values can exist at once. Without this, challenges whose
+    /// records share a [`DnsProvider::txt_slot`] are answered one after another.
+    pub multiple_txt_values: bool,
+}
+
+/// Publishes the TXT records used to answer ACME DNS-01 challenges
+#[async_trait]
+pub trait DnsProvider: Debug + Send + Sync {
+    fn capabilities(&self) -> DnsCapabilities {
+        DnsCapabilities {
+            multiple_txt_values: true,
+        }
+    }
+
+    /// The TXT record set the challenge for `domain` is written to, for providers
+    /// that hold a single value per record set
+    fn txt_slot(&self, domain: &str) -> String {
+        challenge_record_name(domain)
+    }
+
+    /// Create the challenge TXT record for `domain` with the given value
+    async fn present(&self, domain: &str, txt_value: &str) -> anyhow::Result<()>;
+
+    /// Remove the TXT record created by [`DnsProvider::present`]
+    async fn cleanup(&self, domain: &str, txt_value: &str) -> anyhow::Result<()>;
+}
+
+/// The `dns_provider` configuration block, selected by its `type` field
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DnsProviderConfig {
+    /// DuckDNS HTTP API (one TXT record per subdomain)
+    Duckdns {
+        /// DuckDNS API token
+        token: String,
+    },
+    /// RFC 2136 dynamic update, authenticated with TSIG
+    Rfc2136 {
+        /// Primary name server for the zone (e.g., "127.0.0.1:53")
+        server: String,
+        /// Zone containing the challenge records (e.g., "example.com")
+        zone: String,
+        /// Name of the TSIG key as known to the server
+        key_name: String,
+        /// Base64 encoded TSIG secret
+        key_secret: String,
+        /// TSIG algorithm (default: "hmac-sha256")
+        #[serde(default = "default_tsig_algorithm")]
+        key_algorithm: String,
+        /// TTL of the challenge record in seconds (default: 60)
+        #[serde(default = "default_txt_ttl")]
+        ttl: u32,
+    },
+    /// External command, called as `<command> [args...] present|cleanup <record name> <txt value>`
+    Exec {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+fn default_tsig_algorithm() -> String { "hmac-sha256".to_string() }
+
+fn default_txt_ttl() -> u32 { 60 }
+
+impl DnsProviderConfig {
+    /// Create the provider described by this configuration
+    pub fn build(&self) -> anyhow::Result<Arc<dyn DnsProvider>> {
+        Ok(match self {
+            DnsProviderConfig::Duckdns { token } => Arc::new(DuckDns {
+                token: token.clone(),
+            }),
+            DnsProviderConfig::Rfc2136 {
+                server,
+                zone,
+                key_name,
+                key_secret,
+                key_algorithm,
+                ttl,
+            } => {
+                let key = base64::engine::general_purpose::STANDARD
+                    .decode(key_secret)
+                    .map_err(|e| anyhow::anyhow!("Invalid TSIG key_secret: {}", e))?;
+                let algorithm = TsigAlgorithm::from_name(Name::from_ascii(key_algorithm)?);
+                let signer = TSigner::new(key, algorithm, Name::from_ascii(key_name)?, 300)?;
+
+                Arc::new(Rfc2136 {
+                    server: server.clone(),
+                    zone: Name::from_str(zone)?,
+                    signer,
+                    ttl: *ttl,
+                })
+            }
+            DnsProviderConfig::Exec { command, args } => Arc::new(ExecHook {
+                command: command.clone(),
+                args: args.clone(),
+            }),
+        })
+    }
+}
+
+/// DuckDNS, which serves the TXT record set for a subdomain on all names below it
+#[derive(Debug)]
+pub struct DuckDns {
+    token: String,
+}
+
+impl DuckDns {
+    fn subdomain(domain: &str) -> &str {
+        // Extract the subdomain part (e.g., "jelly-tea" from "jelly-tea.duckdns.org")
+        domain.strip_suffix(".duckdns.org").unwrap_or(domain)
+    }
+}
+
+#[async_trait]
+impl DnsProvider for DuckDns {
+    fn capabilities(&self) -> DnsCapabilities {
+        DnsCapabilities {
+            multiple_txt_values: false,
+        }
+    }
+
+    /// DuckDNS keeps one TXT value per registered subdomain, served for every name below it
+    fn txt_slot(&self, domain: &str) -> String {
+        Self::subdomain(domain)
+            .rsplit('.')
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    async fn present(&self, domain: &str, txt_value: &str) -> anyhow::Result<()> {
+        let subdomain = Self::subdomain(domain);
+
+        let url = format!(
+            "https://www.duckdns.org/update?domains={}&token={}&txt={}&verbose=true",
+            subdomain, self.token, txt_value
+        );
+
+        info!("Setting DuckDNS TXT record for {}", subdomain);
+
+        let response = reqwest::get(&url).await?.text().await?;
+
+        if response.starts_with("OK") {
+            info!(
+                "DuckDNS TXT record set successfully: {}",
+                response.replace('\n', " ")
+            );
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("DuckDNS API error: {}", response))
+        }
+    }
+
+    async fn cleanup(&self, domain: &str, _txt_value: &str) -> anyhow::Result<()> {
+        let subdomain = Self::subdomain(domain);
+
+        let url = format!(
+            "https://www.duckdns.org/update?domains={}&token={}&txt=&clear=true",
+            subdomain, self.token
+        );
+
+        let response = reqwest::get(&url).await?.text().await?;
+
+        if response.starts_with("OK") {
+            info!("DuckDNS TXT record cleared for {}", subdomain);
+        } else {
+            // Don't fail on cleanup
+            warn!("Failed to clear TXT record: {}", response);
+        }
+        Ok(())
+    }
+}
+
+/// Send a DNS message over UDP and wait for the response with the same id, repeating
+/// the exchange over TCP when the answer didn't fit in a datagram
+async fn exchange(server: SocketAddr, message: &Message) -> anyhow::Result<Message> {
+    let response = exchange_udp(server, message).await?;
+    if !response.truncated() {
+        return Ok(response);
+    }
+    debug!("Truncated answer from {}, retrying over TCP", server);
+    tokio::time::timeout(DNS_UPDATE_TIMEOUT, exchange_tcp(server, message))
+        .await
+        .map_err(|_| anyhow::anyhow!("DNS request to {} over TCP timed out", server))?
+}
+
+/// Send a DNS message over TCP, each message prefixed by its length (RFC 1035 4.2.2)
+async fn exchange_tcp(server: SocketAddr, message: &Message) -> anyhow::Result<Message> {
+    let request = message.to_vec()?;
+    let len = u16::try_from(request.len())?;
+    let mut stream = TcpStream::connect(server).await?;
+    stream.write_all(&[&len.to_be_bytes()[..], &request].concat()).await?;
+
+    loop {
+        let len = stream.read_u16().await?;
+        let mut buf = vec![0u8; len.into()];
+        stream.read_exact(&mut buf).await?;
+        let response = Message::from_vec(&buf)?;
+        if response.id() == message.id() {
+            return Ok(response);
+        }
+    }
+}
+
+async fn exchange_udp(server: SocketAddr, message: &Message) -> anyhow::Result<Message> {
+    let request = message.to_vec()?;
+    let bind_addr: SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.send_to(&request, server).await?;
+
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let (len, from) = tokio::time::timeout(DNS_UPDATE_TIMEOUT, socket.recv_from(&mut buf))
+            .await
+            .map_err(|_| anyhow::anyhow!("DNS request to {} timed out", server))??;
+        if from != server {
+            continue;
+        }
+        let response = Message::from_vec(&buf[..len])?;
+        if response.id() == message.id() {
+            return Ok(response);
+        }
+    }
+}
+
+/// Query `name` for records of `record_type`, returning the answer section
+pub async fn query(
+    server: SocketAddr,
+    name: &Name,
+    record_type: RecordType,
+    recursion_desired: bool,
+) -> anyhow::Result<Vec<Record>> {
+    let mut message = Message::new();
+    message
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(recursion_desired)
+        .add_query(Query::query(name.clone(), record_type));
+
+    let response = exchange(server, &message).await?;
+    match response.response_code() {
+        ResponseCode::NoError | ResponseCode::NXDomain => Ok(response.answers().to_vec()),
+        code => Err(anyhow::anyhow!("DNS query to {} failed: {}", server, code)),
+    }
+}
+
+/// Parse a resolver address, with port 53 unless one is given ("1.1.1.1", "[::1]:5353")
+pub fn parse_resolver(resolver: &str) -> anyhow::Result<SocketAddr> {
+    if let Ok(addr) = resolver.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    resolver
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, 53))
+        .map_err(|_| anyhow::anyhow!("Invalid DNS resolver address: {}", resolver))
+}
+
+/// Name servers from /etc/resolv.conf
+pub fn system_resolvers() -> Vec<SocketAddr> {
+    std::fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect()
+}
+
+/// Authoritative name servers of the zone containing `name`, found by walking up
+/// the name until a level with NS records is found
+async fn authoritative_servers(name: &Name) -> anyhow::Result<Vec<SocketAddr>> {
+    let resolvers = system_resolvers();
+    let resolver = *resolvers
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("No name server in /etc/resolv.conf"))?;
+
+    let mut zone = name.clone();
+    while !zone.is_root() {
+        let name_servers = query(resolver, &zone, RecordType::NS, true)
+            .await?
+            .into_iter()
+            .filter(|record| record.name() == &zone)
+            .filter_map(|record| match record.data() {
+                RData::NS(ns) => Some(ns.0.to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if !name_servers.is_empty() {
+            let mut servers = Vec::new();
+            for name_server in name_servers {
+                match tokio::net::lookup_host((name_server.trim_end_matches('.'), 53)).await {
+                    Ok(addrs) => servers.extend(addrs),
+                    Err(e) => debug!("Cannot resolve name server {}: {}", name_server, e),
+                }
+            }
+            debug!("Authoritative servers for {}: {:?}", zone, servers);
+            return Ok(servers);
+        }
+        zone = zone.base_name();
+    }
+
+    Err(anyhow::anyhow!("No authoritative name servers found for {}", name))
+}
+
+/// Checks that a challenge TXT record is visible before the CA is asked to validate it
+#[derive(Debug, Clone)]
+pub struct PropagationCheck {
+    /// Resolvers to poll; empty to poll the zone's authoritative servers
+    pub resolvers: Vec<SocketAddr>,
+    /// Give up polling after this long and let the CA try anyway
+    pub timeout: Duration,
+    /// Fixed wait used instead when the record cannot be polled at all
+    pub fallback_wait: Duration,
+}
+
+impl PropagationCheck {
+    /// Wait until every resolver serves the challenge TXT record for `domain` with
+    /// `txt_value`, the timeout passes, or (if no server can be polled) the fallback wait.
+    /// Returns whether the record was seen on every server.
+    pub async fn wait(&self, domain: &str, txt_value: &str) -> bool {
+        let record = challenge_record_name(domain);
+        let mut name = match Name::from_str(&record) {
+            Ok(name) => name,
+            Err(e) => {
+                warn!("Cannot check {}: {}", record, e);
+                return self.wait_fallback().await;
+            }
+        };
+        name.set_fqdn(true);
+
+        let (servers, recursion_desired) = if self.resolvers.is_empty() {
+            match authoritative_servers(&name).await {
+                Ok(servers) if !servers.is_empty() => (servers, false),
+                Ok(_) => {
+                    warn!("No reachable authoritative servers for {}", record);
+                    return self.wait_fallback().await;
+                }
+                Err(e) => {
+                    warn!("Cannot find authoritative servers for {}: {}", record, e);
+                    return self.wait_fallback().await;
+                }
+            }
+        } else {
+            (self.resolvers.clone(), true)
+        };
+
+        info!("Waiting for {} to reach {:?}", record, servers);
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        let mut pending = servers;
+        loop {
+            // All servers at once, so a slow one can't hold the others past the deadline
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            let results = join_all(pending.iter().map(|&server| {
+                let name = &name;
+                async move {
+                    let answers = tokio::time::timeout(
+                        remaining,
+                        query(server, name, RecordType::TXT, recursion_desired),
+                    )
+                    .await;
+                    (server, answers)
+                }
+            }))
+            .await;
+
+            pending = results
+                .into_iter()
+                .filter_map(|(server, answers)| match answers {
+                    Ok(Ok(answers)) if answers.iter().any(|record| txt_matches(record, txt_value)) => {
+                        debug!("{} visible on {}", record, server);
+                        None
+                    }
+                    Ok(Ok(_)) => Some(server),
+                    Ok(Err(e)) => {
+                        debug!("Checking {} on {} failed: {}", record, server, e);
+                        Some(server)
+                    }
+                    Err(_) => {
+                        debug!("Checking {} on {} timed out", record, server);
+                        Some(server)
+                    }
+                })
+                .collect();
+
+            if pending.is_empty() {
+                info!("{} has propagated", record);
+                return true;
+            }
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                warn!(
+                    "{} not visible on {:?} after {}s, continuing anyway",
+                    record,
+                    pending,
+                    self.timeout.as_secs()
+                );
+                return false;
+            }
+            tokio::time::sleep(PROPAGATION_POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    async fn wait_fallback(&self) -> bool {
+        info!("Waiting {}s for DNS propagation...", self.fallback_wait.as_secs());
+        tokio::time::sleep(self.fallback_wait).await;
+        false
+    }
+}
+
+fn txt_matches(record: &Record, txt_value: &str) -> bool {
+    match record.data() {
+        RData::TXT(txt) => txt.txt_data().concat() == txt_value.as_bytes(),
+        _ => false,
+    }
+}
+
+/// RFC 2136 dynamic DNS update signed with TSIG (BIND, Knot, PowerDNS, ...)
+pub struct Rfc2136 {
+    server: String,
+    zone: Name,
+    signer: TSigner,
+    ttl: u32,
+}
+
+impl Debug for Rfc2136 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Rfc2136")
+            .field("server", &self.server)
+            .field("zone", &self.zone)
+            .field("key_name", self.signer.signer_name())
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl Rfc2136 {
+    fn challenge_rrset(&self, domain: &str, txt_value: &str) -> anyhow::Result<RecordSet> {
+        let mut name = Name::from_str(&challenge_record_name(domain))?;
+        name.set_fqdn(true);
+        if !self.zone.zone_of(&name) {
+            return Err(anyhow::anyhow!("{} is not in zone {}", name, self.zone));
+        }
+
+        let mut rrset = RecordSet::with_ttl(name, RecordType::TXT, self.ttl);
+        rrset.add_rdata(RData::TXT(TXT::new(vec![txt_value.to_string()])));
+        Ok(rrset)
+    }
+
+    /// The UPDATE adding (or with `present` false, deleting) the challenge record,
+    /// signed with TSIG at `now` (seconds since the epoch)
+    fn update(&self, domain: &str, txt_value: &str, present: bool, now: u32) -> anyhow::Result<Message> {
+        let rrset = self.challenge_rrset(domain, txt_value)?;
+        let mut message = if present {
+            update_message::append(rrset, self.zone.clone(), false, false)
+        } else {
+            update_message::delete_by_rdata(rrset, self.zone.clone(), false)
+        };
+        message.set_id(rand::random());
+        message.finalize(&self.signer, now)?;
+        Ok(message)
+    }
+
+    /// Send a signed update to the server and check the response code
+    async fn send_update(&self, domain: &str, txt_value: &str, present: bool) -> anyhow::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+        let message = self.update(domain, txt_value, present, now)?;
+
+        let server = tokio::net::lookup_host(&self.server)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Cannot resolve DNS server {}", self.server))?;
+        let response = exchange(server, &message).await?;
+
+        match response.response_code() {
+            ResponseCode::NoError => Ok(()),
+            code => Err(anyhow::anyhow!("DNS update rejected by {}: {}", server, code)),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Rfc2136 {
+    async fn present(&self, domain: &str, txt_value: &str) -> anyhow::Result<()> {
+        info!(
+            "Adding TXT record {} via RFC 2136 update to {}",
+            challenge_record_name(domain),
+            self.server
+        );
+        self.send_update(domain, txt_value, true).await
+    }
+
+    async fn cleanup(&self, domain: &str, txt_value: &str) -> anyhow::Result<()> {
+        info!(
+            "Removing TXT record {} via RFC 2136 update to {}",
+            challenge_record_name(domain),
+            self.server
+        );
+        self.send_update(domain, txt_value, false).await
+    }
+}
+
+/// Delegates record management to an external command, e.g. a script calling a DNS API
+#[derive(Debug)]
+pub struct ExecHook {
+    command: String,
+    args: Vec<String>,
+}
+
+impl ExecHook {
+    async fn run(&self, action: &str, domain: &str, txt_value: &str) -> anyhow::Result<()> {
+        let record = challenge_record_name(domain);
+        info!("Running {} {} for {}", self.command, action, record);
+
+        let output = Command::new(&self.command)
+            .args(&self.args)
+            .arg(action)
+            .arg(&record)
+            .arg(txt_value)
+            .env("ACME_DOMAIN", domain)
+            .output()
+            .await?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "{} {} failed ({}): {}",
+                self.command,
+                action,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+}
+
+#[async_trait]
+impl DnsProvider for ExecHook {
+    async fn present(&self, domain: &str, txt_value: &str) -> anyhow::Result<()> {
+        self.run("present", domain, txt_value).await
+    }
+
+    async fn cleanup(&self, domain: &str, txt_value: &str) -> anyhow::Result<()> {
+        self.run("cleanup", domain, txt_value).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::UpdateMessage;
+    use hickory_proto::rr::DNSClass;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn signer(key: &[u8]) -> TSigner {
+        TSigner::new(
+            key.to_vec(),
+            TsigAlgorithm::HmacSha256,
+            Name::from_ascii("acme-update").unwrap(),
+            300,
+        )
+        .unwrap()
+    }
+
+    fn rfc2136(server: &str) -> Rfc2136 {
+        Rfc2136 {
+            server: server.to_string(),
+            zone: Name::from_ascii("example.com.").unwrap(),
+            signer: signer(KEY),
+            ttl: 60,
+        }
+    }
+
+    fn now() -> u32 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+    }
+
+    fn txt(record: &Record) -> String {
+        match record.data() {
+            RData::TXT(txt) => String::from_utf8(txt.txt_data().concat()).unwrap(),
+            data => panic!("not a TXT record: {:?}", data),
+        }
+    }
+
+    #[test]
+    fn update_adds_signed_txt_record() {
+        let bytes = rfc2136("127.0.0.1:53")
+            .update("www.example.com", "token-digest", true, now())
+            .unwrap()
+            .to_vec()
+            .unwrap();
+        let message = Message::from_vec(&bytes).unwrap();
+
+        assert_eq!(message.op_code(), OpCode::Update);
+        assert_eq!(message.zones().len(), 1);
+        assert_eq!(message.zones()[0].name().to_ascii(), "example.com.");
+        assert_eq!(message.zones()[0].query_type(), RecordType::SOA);
+        assert!(message.prerequisites().is_empty());
+
+        let updates = message.updates();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].name().to_ascii(), "_acme-challenge.www.example.com.");
+        assert_eq!(updates[0].record_type(), RecordType::TXT);
+        assert_eq!(updates[0].dns_class(), DNSClass::IN);
+        assert_eq!(updates[0].ttl(), 60);
+        assert_eq!(txt(&updates[0]), "token-digest");
+
+        assert_eq!(message.signature().len(), 1);
+        assert_eq!(message.signature()[0].name().to_ascii(), "acme-update.");
+        assert!(signer(KEY).verify_message_byte(None, &bytes, true).is_ok());
+        assert!(signer(b"another key").verify_message_byte(None, &bytes, true).is_err());
+    }
+
+    #[test]
+    fn cleanup_deletes_only_the_challenge_value() {
+        let bytes = rfc2136("127.0.0.1:53")
+            .update("www.example.com", "token-digest", false, now())
+            .unwrap()
+            .to_vec()
+            .unwrap();
+        let message = Message::from_vec(&bytes).unwrap();
+
+        let updates = message.updates();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].dns_class(), DNSClass::NONE);
+        assert_eq!(updates[0].ttl(), 0);
+        assert_eq!(txt(&updates[0]), "token-digest");
+        assert!(signer(KEY).verify_message_byte(None, &bytes, true).is_ok());
+    }
+
+    #[test]
+    fn update_outside_zone_is_rejected() {
+        assert!(rfc2136("127.0.0.1:53").update("example.org", "token", true, now()).is_err());
+    }
+
+    /// Answer one UPDATE with `code` and hand back the request
+    async fn update_stub(code: ResponseCode) -> (SocketAddr, tokio::task::JoinHandle<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0u8; 4096];
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let request = Message::from_vec(&buf[..len]).unwrap();
+
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(OpCode::Update)
+                .set_response_code(code);
+            socket.send_to(&response.to_vec().unwrap(), from).await.unwrap();
+            buf.truncate(len);
+            buf
+        });
+        (addr, handle)
+    }
+
+    #[tokio::test]
+    async fn present_sends_signed_update() {
+        let (addr, stub) = update_stub(ResponseCode::NoError).await;
+        rfc2136(&addr.to_string())
+            .present("www.example.com", "token-digest")
+            .await
+            .unwrap();
+
+        let request = stub.await.unwrap();
+        assert!(signer(KEY).verify_message_byte(None, &request, true).is_ok());
+        let message = Message::from_vec(&request).unwrap();
+        assert_eq!(txt(&message.updates()[0]), "token-digest");
+    }
+
+    #[tokio::test]
+    async fn refused_update_fails() {
+        let (addr, stub) = update_stub(ResponseCode::Refused).await;
+        let result = rfc2136(&addr.to_string())
+            .present("www.example.com", "token-digest")
+            .await;
+        stub.await.unwrap();
+        assert!(result.is_err());
+    }
+
+    /// Resolver answering TXT queries with `value` from the `propagated_after`th query
+    /// on, or never answering at all without a value. Counts the queries it receives.
+    async fn txt_stub(
+        value: Option<&'static str>,
+        propagated_after: usize,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 4096];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let Some(value) = value else {
+                    continue;
+                };
+
+                let query = request.queries()[0].clone();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(OpCode::Query)
+                    .set_response_code(ResponseCode::NoError);
+                if count >= propagated_after {
+                    response.add_answer(Record::from_rdata(
+                        query.name().clone(),
+                        60,
+                        RData::TXT(TXT::new(vec![value.to_string()])),
+                    ));
+                }
+                response.add_query(query);
+                socket.send_to(&response.to_vec().unwrap(), from).await.unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    fn propagation(resolvers: Vec<SocketAddr>, timeout: Duration) -> PropagationCheck {
+        PropagationCheck {
+            resolvers,
+            timeout,
+            fallback_wait: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn propagated_record_is_found() {
+        let (first, _) = txt_stub(Some("token-digest"), 1).await;
+        let (second, _) = txt_stub(Some("token-digest"), 1).await;
+        let check = propagation(vec![first, second], Duration::from_secs(5));
+        assert!(check.wait("www.example.com", "token-digest").await);
+    }
+
+    #[tokio::test]
+    async fn record_is_polled_until_propagated() {
+        let (resolver, queries) = txt_stub(Some("token-digest"), 2).await;
+        let check = propagation(vec![resolver], Duration::from_secs(10));
+        assert!(check.wait("www.example.com", "token-digest").await);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn other_value_is_not_propagated() {
+        let (resolver, queries) = txt_stub(Some("old-digest"), 1).await;
+        let check = propagation(vec![resolver], Duration::from_millis(500));
+        assert!(!check.wait("www.example.com", "token-digest").await);
+        assert!(queries.load(Ordering::SeqCst) >= 1);
+    }
+
+    #[tokio::test]
+    async fn silent_resolver_does_not_outlast_timeout() {
+        let (silent, silent_queries) = txt_stub(None, 0).await;
+        let (answering, _) = txt_stub(Some("token-digest"), 1).await;
+        let check = propagation(vec![silent, answering], Duration::from_millis(500));
+
+        let started = tokio::time::Instant::now();
+        assert!(!check.wait("www.example.com", "token-digest").await);
+        // Well below the per-query timeout of a single exchange
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(silent_queries.load(Ordering::SeqCst), 1);
+    }
+
+    /// Against a real server, see "Testing RFC 2136 updates" in the readme
+    #[tokio::test]
+    #[ignore]
+    async fn rfc2136_server() {
+        let env = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{} not set", name));
+        let server = env("RFC2136_SERVER");
+        let zone = env("RFC2136_ZONE");
+        let domain = format!("pingora-test.{}", zone);
+        let provider = DnsProviderConfig::Rfc2136 {
+            server: server.clone(),
+            zone,
+            key_name: env("RFC2136_KEY_NAME"),
+            key_secret: env("RFC2136_KEY_SECRET"),
+            key_algorithm: std::env::var("RFC2136_KEY_ALGORITHM")
+                .unwrap_or_else(|_| default_tsig_algorithm()),
+            ttl: default_txt_ttl(),
+        }
+        .build()
+        .unwrap();
+
+        let server = tokio::net::lookup_host(&server).await.unwrap().next().unwrap();
+        let name = Name::from_str(&format!("{}.", challenge_record_name(&domain))).unwrap();
+        let values = || async {
+            query(server, &name, RecordType::TXT, false)
+                .await
+                .unwrap()
+                .iter()
+                .filter(|record| record.record_type() == RecordType::TXT)
+                .map(txt)
+                .collect::<Vec<_>>()
+        };
+
+        provider.present(&domain, "rfc2136-test").await.unwrap();
+        assert!(values().await.contains(&"rfc2136-test".to_string()));
+        provider.cleanup(&domain, "rfc2136-test").await.unwrap();
+        assert!(!values().await.contains(&"rfc2136-test".to_string()));
+    }
+}
@@ -0,0 +1,561 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{debug, info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+use crate::proxy::{BackendConfig, DomainRoutes, RouteTable};
+
+/// Delay before reconnecting after the Docker API failed or the event stream ended
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Settings for building domains from Docker container labels
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DockerDiscoveryConfig {
+    /// Optional: Docker Engine API socket (default: "/var/run/docker.sock")
+    #[serde(default = "default_socket")]
+    pub socket: PathBuf,
+    /// Optional: Prefix of the labels read from containers (default: "pingora")
+    #[serde(default = "default_label_prefix")]
+    pub label_prefix: String,
+    /// Optional: Connect to the container's IP instead of its name, for a proxy
+    /// outside the containers' network (default: false)
+    #[serde(default)]
+    pub use_container_ip: bool,
+}
+
+fn default_socket() -> PathBuf { PathBuf::from("/var/run/docker.sock") }
+
+fn default_label_prefix() -> String { "pingora".to_string() }
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Container {
+    id: String,
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    network_settings: Option<NetworkSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkSettings {
+    #[serde(default)]
+    networks: BTreeMap<String, Network>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Network {
+    #[serde(rename = "IPAddress", default)]
+    ip_address: String,
+}
+
+/// Response body of the Docker API, plain or chunked
+struct Body {
+    reader: BufReader<UnixStream>,
+    chunked: bool,
+    done: bool,
+}
+
+impl Body {
+    /// The next piece of the body, `None` at the end
+    async fn next_chunk(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        if !self.chunked {
+            let mut buf = vec![0; 8192];
+            let n = self.reader.read(&mut buf).await?;
+            buf.truncate(n);
+            self.done = n == 0;
+            return Ok((n > 0).then_some(buf));
+        }
+
+        let mut size_line = String::new();
+        self.reader.read_line(&mut size_line).await?;
+        let size_hex = size_line.trim().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size_hex, 16)
+            .map_err(|_| anyhow::anyhow!("Invalid chunk size {:?}", size_line.trim()))?;
+        if size == 0 {
+            self.done = true;
+            return Ok(None);
+        }
+        let mut chunk = vec![0; size + 2];
+        self.reader.read_exact(&mut chunk).await?;
+        chunk.truncate(size);
+        Ok(Some(chunk))
+    }
+
+    async fn read_to_end(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.next_chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+}
+
+/// Percent-encode a query parameter value
+fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Send a GET request to the Docker API, returning the body of a successful response
+async fn docker_get(socket: &Path, path: &str) -> anyhow::Result<Body> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| anyhow::anyhow!("Cannot connect to {}: {}", socket.display(), e))?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: docker\r\nConnection: close\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).await?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid response from Docker: {:?}", status_line.trim()))?;
+
+    let mut chunked = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.trim().eq_ignore_ascii_case("transfer-encoding")
+        {
+            chunked = value.trim().eq_ignore_ascii_case("chunked");
+        }
+    }
+
+    let mut body = Body {
+        reader,
+        chunked,
+        done: false,
+    };
+    if !(200..300).contains(&status) {
+        let message = String::from_utf8_lossy(&body.read_to_end().await?).trim().to_string();
+        return Err(anyhow::anyhow!("Docker API {} returned {}: {}", path, status, message));
+    }
+    Ok(body)
+}
+
+/// Domains found by the last sync
+#[derive(Default)]
+struct Discovered {
+    /// Printed backend per domain, to find the ones that changed
+    printed: BTreeMap<String, String>,
+    routes: DomainRoutes,
+}
+
+/// Background service keeping the discovered domains in the [`RouteTable`] in sync
+/// with the labels of running containers.
+///
+/// A container labelled `<prefix>.host=app.example.com` becomes a backend for that
+/// domain (several comma separated domains are allowed), using `<prefix>.port`
+/// (default 80) and `<prefix>.tls`. Containers sharing a domain are balanced as
+/// upstreams. The container list is read again whenever a container starts or dies.
+///
+/// Discovered domains are served over HTTP. No certificate is ordered for them, so
+/// HTTPS only works with a wildcard or default certificate that covers them.
+pub struct DockerDiscoveryService {
+    config: DockerDiscoveryConfig,
+    routes: Arc<RouteTable>,
+}
+
+impl DockerDiscoveryService {
+    pub fn new(config: DockerDiscoveryConfig, routes: Arc<RouteTable>) -> Self {
+        Self { config, routes }
+    }
+
+    fn label<'a>(&self, container: &'a Container, name: &str) -> Option<&'a str> {
+        container
+            .labels
+            .get(&format!("{}.{}", self.config.label_prefix, name))
+            .map(|value| value.trim())
+    }
+
+    /// Backend configuration per domain from the labels of running containers
+    fn backends(&self, containers: &[Container]) -> BTreeMap<String, BackendConfig> {
+        // domain -> (tls, upstreams as (host, port))
+        let mut domains: BTreeMap<String, (bool, Vec<(String, u16)>)> = BTreeMap::new();
+
+        for container in containers {
+            let Some(hosts) = self.label(container, "host") else {
+                continue;
+            };
+            let name = container
+                .names
+                .first()
+                .map(|name| name.trim_start_matches('/').to_string())
+                .unwrap_or_else(|| container.id.chars().take(12).collect());
+
+            let port = match self.label(container, "port").map(str::parse::<u16>) {
+                None => 80,
+                Some(Ok(port)) => port,
+                Some(Err(_)) => {
+                    warn!("Container {}: invalid {}.port label, skipping it", name, self.config.label_prefix);
+                    continue;
+                }
+            };
+            let tls = self.label(container, "tls").is_some_and(|tls| tls.eq_ignore_ascii_case("true"));
+
+            let address = if self.config.use_container_ip {
+                let ip = container
+                    .network_settings
+                    .as_ref()
+                    .and_then(|settings| {
+                        settings.networks.values().find(|network| !network.ip_address.is_empty())
+                    })
+                    .map(|network| network.ip_address.clone());
+                match ip {
+                    Some(ip) => ip,
+                    None => {
+                        warn!("Container {} has no IP address, skipping it", name);
+                        continue;
+                    }
+                }
+            } else {
+                name
+            };
+
+            for domain in hosts.split(',').map(str::trim).filter(|domain| !domain.is_empty()) {
+                let entry = domains.entry(domain.to_lowercase()).or_default();
+                entry.0 |= tls;
+                entry.1.push((address.clone(), port));
+            }
+        }
+
+        domains
+            .into_iter()
+            .filter_map(|(domain, (tls, mut upstreams))| {
+                upstreams.sort();
+                let backend = if let [(host, port)] = upstreams.as_slice() {
+                    json!({ "host": host, "port": port, "tls": tls })
+                } else {
+                    let upstreams = upstreams
+                        .iter()
+                        .map(|(host, port)| json!({ "host": host, "port": port }))
+                        .collect::<Vec<_>>();
+                    json!({ "upstreams": upstreams, "tls": tls })
+                };
+                match serde_json::from_value(backend) {
+                    Ok(backend) => Some((domain, backend)),
+                    Err(e) => {
+                        warn!("Discovered domain {} is invalid: {}", domain, e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Read the running containers and replace the discovered domains. Only domains
+    /// whose backend changed are compiled again, the others keep their routes with
+    /// their health, circuit breaker, connection and resolver state.
+    async fn sync(&self, previous: &mut Discovered) -> anyhow::Result<()> {
+        let filters = json!({ "label": [format!("{}.host", self.config.label_prefix)] });
+        let path = format!("/containers/json?filters={}", encode_query(&filters.to_string()));
+        let body = docker_get(&self.config.socket, &path).await?.read_to_end().await?;
+        let containers: Vec<Container> = serde_json::from_slice(&body)?;
+        let mut backends = self.backends(&containers);
+
+        // Compare by the printed form of each backend
+        let current = backends
+            .iter()
+            .map(|(domain, backend)| {
+                let target = if self.routes.is_static(domain) {
+                    "ignored, configured in config.json".to_string()
+                } else {
+                    backend.settings.to_string()
+                };
+                (domain.clone(), target)
+            })
+            .collect::<BTreeMap<_, _>>();
+        backends.retain(|domain, _| !self.routes.is_static(domain));
+        if current == previous.printed {
+            return Ok(());
+        }
+        for (domain, backend) in &current {
+            if previous.printed.get(domain) != Some(backend) {
+                info!("Docker discovery: {} -> {}", domain, backend);
+            }
+        }
+        for domain in previous.printed.keys().filter(|domain| !current.contains_key(*domain)) {
+            info!("Docker discovery: {} removed", domain);
+        }
+
+        let mut routes = DomainRoutes::new();
+        let mut compiled = Vec::new();
+        for (domain, backend) in backends {
+            if previous.printed.get(&domain) == current.get(&domain)
+                && let Some(unchanged) = previous.routes.get(&domain)
+            {
+                routes.insert(domain, unchanged.clone());
+                continue;
+            }
+            match self.routes.compile(&backend) {
+                Ok(domain_routes) => {
+                    compiled.extend(domain_routes.iter().cloned());
+                    routes.insert(domain, domain_routes);
+                }
+                Err(e) => warn!("Discovered domain {} is invalid: {}", domain, e),
+            }
+        }
+        // Resolve the new upstreams before they receive requests
+        join_all(compiled.iter().map(|route| route.upstreams.update())).await;
+        self.routes.set_discovered(routes.clone());
+        *previous = Discovered {
+            printed: current,
+            routes,
+        };
+        Ok(())
+    }
+
+    /// Wait for the next container start or stop. `Ok(false)` when the stream ended.
+    async fn next_event(body: &mut Body, buffer: &mut Vec<u8>) -> anyhow::Result<bool> {
+        loop {
+            if let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                let line = buffer.drain(..=end).collect::<Vec<_>>();
+                if let Ok(event) = serde_json::from_slice::<serde_json::Value>(&line) {
+                    debug!(
+                        "Docker event: {} {}",
+                        event["Action"].as_str().unwrap_or_default(),
+                        event["Actor"]["Attributes"]["name"].as_str().unwrap_or_default()
+                    );
+                    return Ok(true);
+                }
+                continue;
+            }
+            match body.next_chunk().await? {
+                Some(chunk) => buffer.extend_from_slice(&chunk),
+                None => return Ok(false),
+            }
+        }
+    }
+
+    /// Sync once, then again on every container event until the stream fails
+    async fn watch(&self, previous: &mut Discovered) -> anyhow::Result<()> {
+        // Subscribe first so no event between listing and watching is missed
+        let filters = json!({ "type": ["container"], "event": ["start", "die"] });
+        let path = format!("/events?filters={}", encode_query(&filters.to_string()));
+        let mut events = docker_get(&self.config.socket, &path).await?;
+
+        self.sync(previous).await?;
+        let mut buffer = Vec::new();
+        while Self::next_event(&mut events, &mut buffer).await? {
+            self.sync(previous).await?;
+        }
+        Err(anyhow::anyhow!("Docker event stream ended"))
+    }
+}
+
+#[async_trait]
+impl BackgroundService for DockerDiscoveryService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        info!("Docker discovery enabled on {}", self.config.socket.display());
+
+        let mut previous = Discovered::default();
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                result = self.watch(&mut previous) => {
+                    if let Err(e) = result {
+                        warn!("Docker discovery: {}, retrying in {}s", e, RECONNECT_DELAY.as_secs());
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    use serde_json::Value;
+    use tokio::net::UnixListener;
+    use tokio::sync::mpsc;
+
+    use crate::proxy::{ProxyConfig, Route};
+    use crate::resolver::Resolver;
+
+    /// Docker API on a Unix socket serving `containers` and, on `/events`, a chunked
+    /// stream with one line per message sent to the returned channel
+    fn mock_docker(name: &str, containers: Arc<Mutex<Value>>) -> (PathBuf, mpsc::UnboundedSender<String>) {
+        let socket = std::env::temp_dir().join(format!("pingora-docker-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let (events_tx, events_rx) = mpsc::unbounded_channel::<String>();
+        let events_rx = Arc::new(tokio::sync::Mutex::new(events_rx));
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let containers = containers.clone();
+                let events_rx = events_rx.clone();
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).await.unwrap();
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).await.unwrap() > 2 {
+                        line.clear();
+                    }
+                    let mut stream = reader.into_inner();
+
+                    if request_line.starts_with("GET /events?") {
+                        stream
+                            .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")
+                            .await
+                            .unwrap();
+                        let mut events_rx = events_rx.lock().await;
+                        while let Some(event) = events_rx.recv().await {
+                            let chunk = format!("{}\n", event);
+                            let chunk = format!("{:x}\r\n{}\r\n", chunk.len(), chunk);
+                            stream.write_all(chunk.as_bytes()).await.unwrap();
+                        }
+                    } else if request_line.starts_with("GET /containers/json?") {
+                        let body = containers.lock().unwrap().to_string();
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n{}",
+                            body
+                        );
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    } else {
+                        stream.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await.unwrap();
+                    }
+                });
+            }
+        });
+        (socket, events_tx)
+    }
+
+    fn container(name: &str, ip: &str, labels: Value) -> Value {
+        json!({
+            "Id": format!("{}0123456789abcdef", name),
+            "Names": [format!("/{}", name)],
+            "Labels": labels,
+            "NetworkSettings": { "Networks": { "bridge": { "IPAddress": ip } } }
+        })
+    }
+
+    fn service(socket: PathBuf) -> DockerDiscoveryService {
+        let config: ProxyConfig = serde_json::from_value(json!({
+            "listen_addr": "127.0.0.1:0",
+            "domains": { "static.example.com": { "host": "127.0.0.1", "port": 8000 } }
+        }))
+        .unwrap();
+        let resolver = Arc::new(Resolver::new(&config.resolver).unwrap());
+        let routes = Arc::new(RouteTable::new(&config, resolver).unwrap());
+        let config = DockerDiscoveryConfig {
+            socket,
+            label_prefix: default_label_prefix(),
+            use_container_ip: true,
+        };
+        DockerDiscoveryService::new(config, routes)
+    }
+
+    /// The route table's first route per domain
+    fn routes(service: &DockerDiscoveryService) -> BTreeMap<String, Arc<Route>> {
+        let mut routes = BTreeMap::new();
+        for (domain, route) in service.routes.iter() {
+            routes.entry(domain).or_insert(route);
+        }
+        routes
+    }
+
+    #[tokio::test]
+    async fn sync_recompiles_only_changed_domains() {
+        let containers = Arc::new(Mutex::new(json!([
+            container("app1", "10.0.0.1", json!({ "pingora.host": "a.example.com" })),
+            container("app2", "10.0.0.2", json!({ "pingora.host": "b.example.com" })),
+            container("app3", "10.0.0.3", json!({ "pingora.host": "static.example.com" })),
+        ])));
+        let (socket, _events) = mock_docker("sync", containers.clone());
+        let service = service(socket.clone());
+        let mut discovered = Discovered::default();
+
+        service.sync(&mut discovered).await.unwrap();
+        let first = routes(&service);
+        assert_eq!(
+            first.keys().collect::<Vec<_>>(),
+            ["a.example.com", "b.example.com", "static.example.com"]
+        );
+        // Configured domains take precedence over labels
+        assert_eq!(first["static.example.com"].settings.port, 8000);
+
+        *containers.lock().unwrap() = json!([
+            container("app1", "10.0.0.1", json!({ "pingora.host": "a.example.com" })),
+            container("app2", "10.0.0.2", json!({ "pingora.host": "b.example.com", "pingora.port": "8080" })),
+            container("app4", "10.0.0.4", json!({ "pingora.host": "c.example.com" })),
+        ]);
+        service.sync(&mut discovered).await.unwrap();
+        let second = routes(&service);
+        assert!(Arc::ptr_eq(&first["a.example.com"], &second["a.example.com"]));
+        assert!(!Arc::ptr_eq(&first["b.example.com"], &second["b.example.com"]));
+        assert_eq!(second["b.example.com"].settings.port, 8080);
+        assert!(second.contains_key("c.example.com"));
+        assert!(Arc::ptr_eq(&first["static.example.com"], &second["static.example.com"]));
+
+        *containers.lock().unwrap() = json!([]);
+        service.sync(&mut discovered).await.unwrap();
+        assert_eq!(routes(&service).keys().collect::<Vec<_>>(), ["static.example.com"]);
+        let _ = std::fs::remove_file(socket);
+    }
+
+    #[tokio::test]
+    async fn container_events_trigger_sync() {
+        let containers = Arc::new(Mutex::new(json!([])));
+        let (socket, events) = mock_docker("events", containers.clone());
+        let service = Arc::new(service(socket.clone()));
+
+        let watcher = service.clone();
+        let watch = tokio::spawn(async move { watcher.watch(&mut Discovered::default()).await });
+
+        *containers.lock().unwrap() = json!([
+            container("app1", "10.0.0.1", json!({ "pingora.host": "a.example.com,www.example.com" })),
+        ]);
+        events
+            .send(json!({ "Action": "start", "Actor": { "Attributes": { "name": "app1" } } }).to_string())
+            .unwrap();
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while !routes(&service).contains_key("www.example.com") {
+            assert!(tokio::time::Instant::now() < deadline, "event did not trigger a sync");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(routes(&service).contains_key("a.example.com"));
+
+        // The proxy reconnects when the stream ends
+        drop(events);
+        assert!(watch.await.unwrap().is_err());
+        let _ = std::fs::remove_file(socket);
+    }
+}
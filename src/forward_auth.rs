@@ -0,0 +1,240 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use pingora::http::{RequestHeader, ResponseHeader};
+use reqwest::header::{HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+/// Headers describing a single connection, never passed between the auth service,
+/// the client and the backend
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Delegates the decision whether a request may pass to an external service
+/// (Authelia, oauth2-proxy, ...)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ForwardAuthConfig {
+    /// Verification endpoint of the auth service (e.g., "http://authelia:9091/api/authz/forward-auth")
+    pub url: String,
+    /// Optional: Headers copied from an accepting response to the backend request
+    /// (e.g., ["Remote-User", "Remote-Groups"])
+    #[serde(default)]
+    pub response_headers: Vec<String>,
+    /// Optional: Seconds to wait for the auth service (default: 5)
+    #[serde(default = "default_timeout")]
+    pub timeout_seconds: u64,
+}
+
+fn default_timeout() -> u64 { 5 }
+
+/// Outcome of asking the auth service
+pub enum Verdict {
+    /// Proxy the request, setting these headers (each `response_headers` entry, with
+    /// the values the auth service sent for it)
+    Allow(Vec<(HeaderName, Vec<HeaderValue>)>),
+    /// Answer the client with the auth service's response, e.g. a login redirect
+    Deny(Box<ResponseHeader>, Bytes),
+}
+
+#[derive(Debug)]
+pub struct ForwardAuth {
+    url: reqwest::Url,
+    response_headers: Vec<HeaderName>,
+    client: reqwest::Client,
+}
+
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP.contains(&name.as_str())
+}
+
+impl ForwardAuth {
+    pub fn new(config: &ForwardAuthConfig) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(&config.url)
+            .map_err(|e| anyhow::anyhow!("Invalid forward_auth url {:?}: {}", config.url, e))?;
+        let response_headers = config
+            .response_headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| anyhow::anyhow!("Invalid forward_auth response header {:?}", name))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // Redirects to a login page are for the client to follow
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(Duration::from_secs(config.timeout_seconds.max(1)))
+            .build()?;
+        Ok(Self {
+            url,
+            response_headers,
+            client,
+        })
+    }
+
+    /// Ask the auth service about `request`, whose headers already carry the
+    /// `X-Forwarded-*` set of the proxy. The body is not sent.
+    pub async fn check(&self, request: &RequestHeader) -> anyhow::Result<Verdict> {
+        let method = reqwest::Method::from_bytes(request.method.as_str().as_bytes())?;
+        let uri = request.uri.path_and_query().map_or("/", |path| path.as_str());
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in request.headers.iter() {
+            if !is_hop_by_hop(name) && !matches!(name.as_str(), "host" | "content-length") {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        headers.insert("x-forwarded-method", HeaderValue::from_str(request.method.as_str())?);
+        headers.insert("x-forwarded-uri", HeaderValue::from_str(uri)?);
+
+        let response = self
+            .client
+            .request(method, self.url.clone())
+            .headers(headers)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            let headers = self
+                .response_headers
+                .iter()
+                .map(|name| {
+                    let values = response.headers().get_all(name).iter().cloned().collect();
+                    (name.clone(), values)
+                })
+                .collect();
+            return Ok(Verdict::Allow(headers));
+        }
+
+        let mut header = ResponseHeader::build(status.as_u16(), None)?;
+        for (name, value) in response.headers() {
+            if !is_hop_by_hop(name) && name.as_str() != "content-length" {
+                header.append_header(name.clone(), value.clone())?;
+            }
+        }
+        let body = response.bytes().await?;
+        header.insert_header("Content-Length", body.len().to_string())?;
+        Ok(Verdict::Deny(Box::new(header), body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Auth service answering every request with `response`, keeping the requests it got
+    async fn stub(response: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/verify", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                received.lock().unwrap().push(String::from_utf8(request).unwrap().to_lowercase());
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn forward_auth(url: String) -> ForwardAuth {
+        ForwardAuth::new(&ForwardAuthConfig {
+            url,
+            response_headers: vec!["Remote-User".to_string(), "Remote-Groups".to_string()],
+            timeout_seconds: 5,
+        })
+        .unwrap()
+    }
+
+    fn request() -> RequestHeader {
+        let mut request = RequestHeader::build("POST", b"/admin?page=2", None).unwrap();
+        request.insert_header("Host", "app.example.com").unwrap();
+        request.insert_header("Cookie", "session=abc").unwrap();
+        request.insert_header("Remote-User", "admin").unwrap();
+        request.insert_header("Connection", "keep-alive").unwrap();
+        request.insert_header("Content-Length", "5").unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn allow_copies_only_configured_headers() {
+        let (url, requests) = stub(
+            "HTTP/1.1 200 OK\r\nRemote-Groups: admins\r\nRemote-Groups: users\r\n\
+             Remote-Email: alice@example.com\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let Verdict::Allow(headers) = forward_auth(url).check(&request()).await.unwrap() else {
+            panic!("expected Allow");
+        };
+
+        // Remote-User is listed without values, so the client's one is removed
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].0, "remote-user");
+        assert!(headers[0].1.is_empty());
+        assert_eq!(headers[1].0, "remote-groups");
+        assert_eq!(headers[1].1, ["admins", "users"]);
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert!(request.starts_with("post /verify http/1.1\r\n"), "{}", request);
+        assert!(request.contains("x-forwarded-method: post\r\n"));
+        assert!(request.contains("x-forwarded-uri: /admin?page=2\r\n"));
+        assert!(request.contains("cookie: session=abc\r\n"));
+        assert!(!request.contains("app.example.com"));
+        assert!(!request.contains("content-length: 5"));
+        assert!(!request.contains("keep-alive"));
+    }
+
+    #[tokio::test]
+    async fn deny_passes_the_response_back() {
+        let (url, _) = stub(
+            "HTTP/1.1 302 Found\r\nLocation: https://auth.example.com/login\r\n\
+             Set-Cookie: rd=app\r\nContent-Type: text/html\r\nContent-Length: 12\r\n\
+             Connection: close\r\n\r\n<a>login</a>",
+        )
+        .await;
+        let Verdict::Deny(header, body) = forward_auth(url).check(&request()).await.unwrap() else {
+            panic!("expected Deny");
+        };
+
+        assert_eq!(header.status, 302);
+        assert_eq!(header.headers["location"], "https://auth.example.com/login");
+        assert_eq!(header.headers["set-cookie"], "rd=app");
+        assert_eq!(header.headers["content-type"], "text/html");
+        assert_eq!(header.headers["content-length"], "12");
+        assert!(header.headers.get("connection").is_none());
+        assert_eq!(body, "<a>login</a>");
+    }
+
+    #[tokio::test]
+    async fn unreachable_service_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/verify", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(forward_auth(url).check(&request()).await.is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+/// An IP network, written as CIDR ("10.0.0.0/8") or a single address ("10.0.0.1")
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNetwork(IpNet);
+
+impl IpNetwork {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(ip)
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .parse::<IpNet>()
+            .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+            .map(IpNetwork)
+            .map_err(|_| format!("invalid IP address or CIDR {:?}", value))
+    }
+}
+
+impl From<IpNetwork> for String {
+    fn from(network: IpNetwork) -> Self {
+        network.to_string()
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+pub fn is_trusted(trusted_proxies: &[IpNetwork], ip: &IpAddr) -> bool {
+    trusted_proxies.iter().any(|network| network.contains(ip))
+}
+
+/// Addresses in `X-Forwarded-For` values, oldest first. Entries that aren't IP
+/// addresses (e.g. "unknown", or with a port) are skipped.
+pub fn forwarded_for(values: &[&str]) -> Vec<IpAddr> {
+    values
+        .iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| entry.trim().parse().ok())
+        .collect()
+}
+
+/// The original client of a request from `peer`. Behind trusted proxies this is the
+/// last `X-Forwarded-For` address not belonging to one, as the addresses before it
+/// can't be verified.
+pub fn client_ip(trusted_proxies: &[IpNetwork], peer: IpAddr, forwarded_for: &[IpAddr]) -> IpAddr {
+    if !is_trusted(trusted_proxies, &peer) {
+        return peer;
+    }
+    forwarded_for
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(trusted_proxies, ip))
+        .or(forwarded_for.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+/// An RFC 7239 `Forwarded` element describing one hop
+pub fn forwarded_element(client: IpAddr, proto: &str, host: Option<&str>) -> String {
+    // IPv6 addresses are bracketed and therefore need quoting
+    let mut element = match client {
+        IpAddr::V4(ip) => format!("for={}", ip),
+        IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+    };
+    if let Some(host) = host {
+        if host.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_')) {
+            element.push_str(&format!(";host={}", host));
+        } else {
+            element.push_str(&format!(";host=\"{}\"", host.replace('\\', "\\\\").replace('"', "\\\"")));
+        }
+    }
+    element.push_str(&format!(";proto={}", proto));
+    element
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn networks(networks: &[&str]) -> Vec<IpNetwork> {
+        networks.iter().map(|network| IpNetwork::try_from(network.to_string()).unwrap()).collect()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_parse() {
+        let trusted = networks(&["10.0.0.0/8", "192.168.1.1", "fd00::/8"]);
+        assert!(is_trusted(&trusted, &ip("10.1.2.3")));
+        assert!(is_trusted(&trusted, &ip("192.168.1.1")));
+        assert!(!is_trusted(&trusted, &ip("192.168.1.2")));
+        assert!(is_trusted(&trusted, &ip("fd12::1")));
+        assert!(!is_trusted(&trusted, &ip("2001:db8::1")));
+        assert!(IpNetwork::try_from("10.0.0.0/33".to_string()).is_err());
+        assert!(IpNetwork::try_from("proxy.local".to_string()).is_err());
+    }
+
+    #[test]
+    fn forwarded_for_skips_invalid_entries() {
+        assert_eq!(
+            forwarded_for(&["198.51.100.1, unknown", " 2001:db8::1 ,10.0.0.1:8080", "10.0.0.2"]),
+            vec![ip("198.51.100.1"), ip("2001:db8::1"), ip("10.0.0.2")]
+        );
+        assert!(forwarded_for(&[]).is_empty());
+    }
+
+    #[test]
+    fn client_is_the_rightmost_untrusted_hop() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let hops = [ip("198.51.100.1"), ip("203.0.113.9"), ip("10.0.0.5")];
+
+        // Anything left of the first untrusted hop may be made up by the client
+        assert_eq!(client_ip(&trusted, ip("10.0.0.1"), &hops), ip("203.0.113.9"));
+        // Only trusted hops: the oldest one
+        assert_eq!(client_ip(&trusted, ip("10.0.0.1"), &[ip("10.0.0.7"), ip("10.0.0.5")]), ip("10.0.0.7"));
+        assert_eq!(client_ip(&trusted, ip("10.0.0.1"), &[]), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let hops = [ip("198.51.100.1")];
+        assert_eq!(client_ip(&networks(&["10.0.0.0/8"]), ip("203.0.113.7"), &hops), ip("203.0.113.7"));
+        assert_eq!(client_ip(&[], ip("10.0.0.1"), &hops), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_elements() {
+        assert_eq!(
+            forwarded_element(ip("203.0.113.7"), "https", Some("example.com")),
+            "for=203.0.113.7;host=example.com;proto=https"
+        );
+        assert_eq!(
+            forwarded_element(ip("2001:db8::1"), "http", None),
+            "for=\"[2001:db8::1]\";proto=http"
+        );
+        // Ports and anything else outside a token are quoted, with quotes escaped
+        assert_eq!(
+            forwarded_element(ip("203.0.113.7"), "http", Some("example.com:8080")),
+            "for=203.0.113.7;host=\"example.com:8080\";proto=http"
+        );
+        assert_eq!(
+            forwarded_element(ip("203.0.113.7"), "http", Some("a\"b\\c")),
+            "for=203.0.113.7;host=\"a\\\"b\\\\c\";proto=http"
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{info, warn};
+use pingora::connectors::L4Connect;
+use pingora::http::RequestHeader;
+use pingora::lb::health_check::{HealthCheck, HttpHealthCheck, TcpHealthCheck};
+use pingora::lb::Backend;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::{Error, ErrorType};
+use serde::{Deserialize, Serialize};
+
+use crate::balancer::UpstreamHost;
+use crate::proxy_protocol::{ProxyProtocolConnector, ProxyProtocolVersion};
+use crate::proxy::{Route, RouteTable};
+
+/// How an upstream is probed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckType {
+    /// The upstream accepts TCP connections
+    #[default]
+    Tcp,
+    /// A GET request to `path` returns the expected status
+    Http,
+}
+
+/// Active health check of a backend's upstreams
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HealthCheckConfig {
+    /// Optional: "tcp" or "http" (default: "tcp")
+    #[serde(rename = "type", default)]
+    pub kind: HealthCheckType,
+    /// Optional: Path requested by "http" checks (default: "/")
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Optional: Status expected from "http" checks (default: any 2xx)
+    pub expected_status: Option<u16>,
+    /// Optional: Seconds between checks (default: 10)
+    #[serde(default = "default_interval")]
+    pub interval_seconds: u64,
+    /// Optional: Seconds to wait for the connection and response (default: 2)
+    #[serde(default = "default_timeout")]
+    pub timeout_seconds: u64,
+    /// Optional: Consecutive passed checks that bring an upstream back (default: 2)
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: usize,
+    /// Optional: Consecutive failed checks that take an upstream out of rotation (default: 3)
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: usize,
+}
+
+fn default_path() -> String { "/".to_string() }
+
+fn default_interval() -> u64 { 10 }
+
+fn default_timeout() -> u64 { 2 }
+
+fn default_healthy_threshold() -> usize { 2 }
+
+fn default_unhealthy_threshold() -> usize { 3 }
+
+impl HealthCheckConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_seconds.max(1))
+    }
+
+    /// The pingora health check for upstreams reached with `tls`. HTTP checks send
+    /// each upstream's host (or `sni`, if set) as Host header and SNI, like proxied
+    /// requests. Upstreams expecting the PROXY protocol are sent a header for a
+    /// connection of the proxy's own.
+    pub fn build(
+        &self,
+        hosts: &[&str],
+        sni: Option<&str>,
+        tls: bool,
+        proxy_protocol: Option<ProxyProtocolVersion>,
+    ) -> anyhow::Result<Box<dyn HealthCheck + Send + Sync>> {
+        let mut checks = HashMap::new();
+        for &host in hosts {
+            if !checks.contains_key(host) {
+                let check = self.build_for(sni.unwrap_or(host), tls, proxy_protocol)?;
+                checks.insert(host.to_string(), check);
+            }
+        }
+        let default = self.build_for(sni.or(hosts.first().copied()).unwrap_or_default(), tls, proxy_protocol)?;
+        Ok(Box::new(UpstreamHealthCheck { checks, default }))
+    }
+
+    /// The check of the upstreams of one host, sent `host` as Host header and SNI
+    fn build_for(
+        &self,
+        host: &str,
+        tls: bool,
+        proxy_protocol: Option<ProxyProtocolVersion>,
+    ) -> anyhow::Result<Box<dyn HealthCheck + Send + Sync>> {
+        let timeout = Duration::from_secs(self.timeout_seconds.max(1));
+        let custom_l4 = proxy_protocol.map(|version| {
+            Arc::new(ProxyProtocolConnector::new(version, None, timeout)) as Arc<dyn L4Connect + Send + Sync>
+        });
+        let timeout = Some(timeout);
+        Ok(match self.kind {
+            HealthCheckType::Tcp => {
+                let mut check = TcpHealthCheck::new();
+                check.peer_template.options.connection_timeout = timeout;
+                check.peer_template.options.custom_l4 = custom_l4;
+                check.consecutive_success = self.healthy_threshold.max(1);
+                check.consecutive_failure = self.unhealthy_threshold.max(1);
+                check
+            }
+            HealthCheckType::Http => {
+                let mut check = HttpHealthCheck::new(host, tls);
+                check.req = RequestHeader::build("GET", self.path.as_bytes(), None)?;
+                check.req.insert_header("Host", host)?;
+                check.peer_template.options.connection_timeout = timeout;
+                check.peer_template.options.read_timeout = timeout;
+                check.peer_template.options.custom_l4 = custom_l4;
+                check.consecutive_success = self.healthy_threshold.max(1);
+                check.consecutive_failure = self.unhealthy_threshold.max(1);
+
+                let expected_status = self.expected_status;
+                check.validator = Some(Box::new(move |response| {
+                    let status = response.status.as_u16();
+                    let ok = match expected_status {
+                        Some(expected) => status == expected,
+                        None => response.status.is_success(),
+                    };
+                    if ok {
+                        Ok(())
+                    } else {
+                        Error::e_explain(
+                            ErrorType::CustomCode("unexpected status", status),
+                            "during http health check",
+                        )
+                    }
+                }));
+                Box::new(check)
+            }
+        })
+    }
+}
+
+/// Dispatches to the check of each upstream's host, and names upstreams by host and
+/// address in the logs
+struct UpstreamHealthCheck {
+    checks: HashMap<String, Box<dyn HealthCheck + Send + Sync>>,
+    /// For upstreams without a known host
+    default: Box<dyn HealthCheck + Send + Sync>,
+}
+
+impl UpstreamHealthCheck {
+    fn check_for(&self, target: &Backend) -> &(dyn HealthCheck + Send + Sync) {
+        target
+            .ext
+            .get::<UpstreamHost>()
+            .and_then(|host| self.checks.get(&host.0))
+            .unwrap_or(&self.default)
+            .as_ref()
+    }
+}
+
+#[async_trait]
+impl HealthCheck for UpstreamHealthCheck {
+    async fn check(&self, target: &Backend) -> pingora::Result<()> {
+        self.check_for(target).check(target).await
+    }
+
+    fn backend_summary(&self, target: &Backend) -> String {
+        match (target.ext.get::<UpstreamHost>(), target.as_inet()) {
+            (Some(host), Some(addr)) => format!("Upstream {} ({})", host.0, addr),
+            _ => format!("Upstream {:?}", target.addr),
+        }
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        self.default.health_threshold(success)
+    }
+}
+
+/// How often the route table is read again for routes added or removed at runtime
+const ROUTE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Health check state of one route
+struct CheckedRoute {
+    route: Arc<Route>,
+    next_check: Instant,
+    /// Healthy upstream count, to log when it changes
+    healthy: Option<usize>,
+}
+
+fn key_of(route: &Arc<Route>) -> usize {
+    Arc::as_ptr(route) as usize
+}
+
+/// Background service running the health checks of every route with one configured.
+/// Each route is checked at its own interval; unhealthy upstreams are skipped by the
+/// load balancer until they pass again. Routes discovered at runtime are picked up
+/// from the route table on the next tick.
+pub struct HealthCheckService {
+    routes: Arc<RouteTable>,
+}
+
+impl HealthCheckService {
+    pub fn new(routes: Arc<RouteTable>) -> Self {
+        Self { routes }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for HealthCheckService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        // Keyed by route address; the Arc held here keeps the address from being reused
+        let mut checked: HashMap<usize, CheckedRoute> = HashMap::new();
+
+        loop {
+            let now = Instant::now();
+            let routes = self
+                .routes
+                .iter()
+                .into_iter()
+                .filter_map(|(domain, route)| {
+                    let interval = route.upstreams.health_check_interval?;
+                    Some((domain, route, interval))
+                })
+                .collect::<Vec<_>>();
+            let count = checked.len();
+            checked.retain(|key, _| routes.iter().any(|(_, route, _)| key_of(route) == *key));
+            for (_, route, _) in &routes {
+                checked.entry(key_of(route)).or_insert_with(|| CheckedRoute {
+                    route: route.clone(),
+                    next_check: now,
+                    healthy: None,
+                });
+            }
+            if checked.len() != count {
+                info!("Health checks enabled for {} routes", checked.len());
+            }
+
+            let due = routes
+                .iter()
+                .filter(|(_, route, _)| checked[&key_of(route)].next_check <= now)
+                .collect::<Vec<_>>();
+            join_all(due.iter().map(|(_, route, _)| route.upstreams.run_health_check())).await;
+
+            for (domain, route, interval) in due {
+                let state = checked.get_mut(&key_of(route)).unwrap();
+                state.next_check = now + *interval;
+
+                let health = state.route.upstreams.health();
+                let healthy = health.iter().filter(|upstream| upstream.healthy).count();
+                if state.healthy.replace(healthy) == Some(healthy) {
+                    continue;
+                }
+                let down = health
+                    .iter()
+                    .filter(|upstream| !upstream.healthy)
+                    .map(|upstream| format!("{} ({})", upstream.host, upstream.addr))
+                    .collect::<Vec<_>>();
+                if healthy == 0 {
+                    warn!("{} (route {}): no healthy upstreams", domain, route.matcher);
+                } else if down.is_empty() {
+                    info!("{} (route {}): all {} upstreams healthy", domain, route.matcher, healthy);
+                } else {
+                    info!(
+                        "{} (route {}): {} of {} upstreams healthy, down: {}",
+                        domain, route.matcher, healthy, health.len(), down.join(", ")
+                    );
+                }
+            }
+
+            let wake = checked
+                .values()
+                .map(|state| state.next_check)
+                .min()
+                .unwrap_or(now)
+                .min(now + ROUTE_POLL_INTERVAL);
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep_until(wake.into()) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// HTTP upstream answering one request with 200 and handing back its Host header
+    async fn upstream() -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request)
+                .unwrap()
+                .lines()
+                .find_map(|line| line.strip_prefix("Host: ").or_else(|| line.strip_prefix("host: ")))
+                .unwrap()
+                .to_string()
+        });
+        (addr, handle)
+    }
+
+    fn backend(addr: &str, host: &str) -> Backend {
+        let mut backend = Backend::new(addr).unwrap();
+        backend.ext.insert(UpstreamHost(host.to_string()));
+        backend
+    }
+
+    fn http_check() -> HealthCheckConfig {
+        serde_json::from_str(r#"{"type": "http"}"#).unwrap()
+    }
+
+    #[tokio::test]
+    async fn http_check_sends_each_upstreams_host() {
+        let (alpha_addr, alpha) = upstream().await;
+        let (beta_addr, beta) = upstream().await;
+        let check = http_check()
+            .build(&["alpha.internal", "beta.internal"], None, false, None)
+            .unwrap();
+
+        check.check(&backend(&alpha_addr, "alpha.internal")).await.unwrap();
+        check.check(&backend(&beta_addr, "beta.internal")).await.unwrap();
+        assert_eq!(alpha.await.unwrap(), "alpha.internal");
+        assert_eq!(beta.await.unwrap(), "beta.internal");
+    }
+
+    #[tokio::test]
+    async fn http_check_sends_configured_sni() {
+        let (addr, upstream) = upstream().await;
+        let check = http_check()
+            .build(&["alpha.internal", "beta.internal"], Some("app.example.com"), false, None)
+            .unwrap();
+
+        check.check(&backend(&addr, "beta.internal")).await.unwrap();
+        assert_eq!(upstream.await.unwrap(), "app.example.com");
+    }
+
+    #[tokio::test]
+    async fn routes_added_at_runtime_are_checked() {
+        let config: crate::proxy::ProxyConfig =
+            serde_json::from_value(serde_json::json!({ "listen_addr": "127.0.0.1:0", "domains": {} })).unwrap();
+        let resolver = Arc::new(crate::resolver::Resolver::new(&config.resolver).unwrap());
+        let routes = Arc::new(RouteTable::new(&config, resolver).unwrap());
+        let (shutdown_tx, shutdown) = tokio::sync::watch::channel(false);
+        let service = HealthCheckService::new(routes.clone());
+        let running = tokio::spawn(async move { service.start(shutdown).await });
+
+        // Nothing listens on the port, so the upstream fails its first check
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let backend = serde_json::from_value(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": addr.port(),
+            "health_check": { "interval_seconds": 60, "unhealthy_threshold": 1 }
+        }))
+        .unwrap();
+        let domain_routes = routes.compile(&backend).unwrap();
+        domain_routes[0].upstreams.update().await;
+        let upstreams = &domain_routes[0].upstreams;
+        routes.set_discovered(HashMap::from([("app.example.com".to_string(), domain_routes.clone())]));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while upstreams.health().iter().any(|upstream| upstream.healthy) {
+            assert!(Instant::now() < deadline, "discovered route was not checked");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        shutdown_tx.send(true).unwrap();
+        running.await.unwrap();
+    }
+}
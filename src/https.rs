@@ -0,0 +1,44 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// `Strict-Transport-Security` header sent on HTTPS responses of a domain
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HstsConfig {
+    /// Optional: Seconds browsers only use HTTPS for the domain (default: 31536000, one year)
+    #[serde(default = "default_max_age")]
+    pub max_age_seconds: u64,
+    /// Optional: Apply to all subdomains as well (default: false)
+    #[serde(default)]
+    pub include_subdomains: bool,
+    /// Optional: Ask to be included in browsers' preload lists (default: false)
+    #[serde(default)]
+    pub preload: bool,
+}
+
+fn default_max_age() -> u64 { 31536000 }
+
+impl HstsConfig {
+    /// The header value, e.g. "max-age=31536000; includeSubDomains"
+    pub fn header_value(&self) -> String {
+        if self.preload && (self.max_age_seconds < default_max_age() || !self.include_subdomains) {
+            warn!("HSTS preload needs a max_age_seconds of at least one year and include_subdomains");
+        }
+        let mut value = format!("max-age={}", self.max_age_seconds);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+/// Where a plain HTTP request is redirected on the HTTPS listener
+pub fn https_location(host: &str, port: u16, path_and_query: &str) -> String {
+    if port == 443 {
+        format!("https://{}{}", host, path_and_query)
+    } else {
+        format!("https://{}:{}{}", host, port, path_and_query)
+    }
+}
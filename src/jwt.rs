@@ -0,0 +1,742 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use log::{info, warn};
+use reqwest::header::{HeaderName, HeaderValue};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// How often a JWKS file is checked for changes, at most
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Least time between attempts to fetch a JWKS URL
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Least time between fetches of a JWKS URL triggered by an unknown key id
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Validation of `Authorization: Bearer` JSON Web Tokens for a domain
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JwtConfig {
+    /// Optional: JSON Web Key Set file with the issuer's public keys, reloaded on change
+    pub jwks_file: Option<String>,
+    /// Optional: URL of the issuer's JSON Web Key Set (e.g., "https://id.example.com/jwks.json")
+    pub jwks_url: Option<String>,
+    /// Optional: Seconds keys fetched from `jwks_url` are used before fetching them again (default: 300)
+    #[serde(default = "default_jwks_cache")]
+    pub jwks_cache_seconds: u64,
+    /// Optional: Required `iss` claim (default: not checked)
+    pub issuer: Option<String>,
+    /// Optional: Accepted `aud` values, the token must name one of them (default: not checked)
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    /// Optional: Seconds of clock skew allowed for `exp` and `nbf` (default: 60)
+    #[serde(default = "default_leeway")]
+    pub leeway_seconds: u64,
+    /// Optional: Claims forwarded to the backend, claim name to header name
+    /// (e.g., {"sub": "X-User", "email": "X-Email"})
+    #[serde(default)]
+    pub claim_headers: HashMap<String, String>,
+    /// Optional: Realm in the `WWW-Authenticate` header of rejections (default: the domain)
+    pub realm: Option<String>,
+}
+
+fn default_jwks_cache() -> u64 { 300 }
+
+fn default_leeway() -> u64 { 60 }
+
+/// Claims and scopes a route requires on top of a valid token
+#[derive(Debug, Clone, Default)]
+pub struct Requirements {
+    /// Claim values, matched exactly or as an element of an array claim
+    pub claims: HashMap<String, Value>,
+    /// Scopes from the space separated `scope` claim (or `scp`)
+    pub scopes: Vec<String>,
+}
+
+impl Requirements {
+    pub fn is_empty(&self) -> bool {
+        self.claims.is_empty() && self.scopes.is_empty()
+    }
+}
+
+/// Why a request was turned away
+#[derive(Debug)]
+pub enum Rejection {
+    /// No bearer token
+    Missing,
+    /// The token is malformed, expired, not signed by a known key, ...
+    Invalid(String),
+    /// The token is valid but lacks required claims or scopes
+    Forbidden(String),
+    /// The keys couldn't be loaded
+    Unavailable(String),
+}
+
+impl Rejection {
+    pub fn status(&self) -> u16 {
+        match self {
+            Rejection::Missing | Rejection::Invalid(_) => 401,
+            Rejection::Forbidden(_) => 403,
+            Rejection::Unavailable(_) => 503,
+        }
+    }
+
+    /// `WWW-Authenticate` value as per RFC 6750, `None` if the client isn't at fault
+    pub fn challenge(&self, realm: &str, scopes: &[String]) -> Option<String> {
+        let quote = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+        let realm = quote(realm);
+        match self {
+            Rejection::Missing => Some(format!("Bearer realm=\"{}\"", realm)),
+            Rejection::Invalid(reason) => Some(format!(
+                "Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"",
+                realm,
+                quote(reason)
+            )),
+            Rejection::Forbidden(_) => Some(format!(
+                "Bearer realm=\"{}\", error=\"insufficient_scope\", scope=\"{}\"",
+                realm,
+                quote(&scopes.join(" "))
+            )),
+            Rejection::Unavailable(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::Missing => write!(f, "no bearer token"),
+            Rejection::Invalid(reason) | Rejection::Forbidden(reason) | Rejection::Unavailable(reason) => {
+                write!(f, "{}", reason)
+            }
+        }
+    }
+}
+
+/// A key of a JSON Web Key Set, only the members needed to verify signatures
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// A public key ready to verify signatures
+#[derive(Debug)]
+enum PublicKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    /// Uncompressed point of a P-256 or P-384 key
+    Ec { crv: String, point: Vec<u8> },
+    Ed25519(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Key {
+    kid: Option<String>,
+    alg: Option<String>,
+    key: PublicKey,
+}
+
+fn base64url(value: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .ok()
+}
+
+impl Key {
+    /// `None` for keys that aren't for signatures or of an unsupported type
+    fn from_jwk(jwk: Jwk) -> Option<Self> {
+        if jwk.usage.as_deref().is_some_and(|usage| usage != "sig") {
+            return None;
+        }
+        let key = match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("RSA", _) => PublicKey::Rsa {
+                n: base64url(jwk.n.as_deref()?)?,
+                e: base64url(jwk.e.as_deref()?)?,
+            },
+            ("EC", Some(crv @ ("P-256" | "P-384"))) => {
+                let mut point = vec![0x04];
+                point.extend(base64url(jwk.x.as_deref()?)?);
+                point.extend(base64url(jwk.y.as_deref()?)?);
+                PublicKey::Ec {
+                    crv: crv.to_string(),
+                    point,
+                }
+            }
+            ("OKP", Some("Ed25519")) => PublicKey::Ed25519(base64url(jwk.x.as_deref()?)?),
+            _ => return None,
+        };
+        Some(Key {
+            kid: jwk.kid,
+            alg: jwk.alg,
+            key,
+        })
+    }
+
+    /// Whether `signature` over `message` was made with this key using `alg`
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        if self.alg.as_deref().is_some_and(|key_alg| key_alg != alg) {
+            return false;
+        }
+        match (&self.key, alg) {
+            (PublicKey::Rsa { n, e }, _) => {
+                let params = match alg {
+                    "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                    "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                    "RS512" => &signature::RSA_PKCS1_2048_8192_SHA512,
+                    "PS256" => &signature::RSA_PSS_2048_8192_SHA256,
+                    "PS384" => &signature::RSA_PSS_2048_8192_SHA384,
+                    "PS512" => &signature::RSA_PSS_2048_8192_SHA512,
+                    _ => return false,
+                };
+                RsaPublicKeyComponents { n, e }.verify(params, message, signature).is_ok()
+            }
+            (PublicKey::Ec { crv, point }, "ES256") if crv == "P-256" => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+            (PublicKey::Ec { crv, point }, "ES384") if crv == "P-384" => {
+                UnparsedPublicKey::new(&signature::ECDSA_P384_SHA384_FIXED, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+            (PublicKey::Ed25519(key), "EdDSA") => UnparsedPublicKey::new(&signature::ED25519, key)
+                .verify(message, signature)
+                .is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// Keys that may have signed a token with `kid`: those with that id, or when none
+/// has it, those without an id
+fn candidates<'a>(keys: &'a [Key], kid: Option<&str>) -> Vec<&'a Key> {
+    let Some(kid) = kid else {
+        return keys.iter().collect();
+    };
+    let matching = keys.iter().filter(|key| key.kid.as_deref() == Some(kid)).collect::<Vec<_>>();
+    match matching.is_empty() {
+        true => keys.iter().filter(|key| key.kid.is_none()).collect(),
+        false => matching,
+    }
+}
+
+fn parse_jwks(json: &[u8]) -> anyhow::Result<Vec<Key>> {
+    let set: JwkSet = serde_json::from_slice(json)?;
+    let keys = set.keys.into_iter().filter_map(Key::from_jwk).collect::<Vec<_>>();
+    if keys.is_empty() {
+        return Err(anyhow::anyhow!("no usable signing keys"));
+    }
+    Ok(keys)
+}
+
+/// Where the keys come from
+enum KeySource {
+    File(String),
+    Url(reqwest::Url, reqwest::Client),
+}
+
+/// The keys in use and when they were loaded
+struct KeySet {
+    keys: Vec<Key>,
+    /// `None` until loaded
+    loaded: Option<Instant>,
+    /// Modification time of a key file when loaded
+    modified: Option<SystemTime>,
+}
+
+/// Verifies bearer tokens against the keys of one issuer
+pub struct JwtAuth {
+    source: KeySource,
+    cache: Duration,
+    issuer: Option<String>,
+    audiences: Vec<String>,
+    leeway: u64,
+    claim_headers: Vec<(String, HeaderName)>,
+    pub realm: Option<String>,
+    keys: RwLock<Arc<KeySet>>,
+    /// When the source was last checked, so a failing or unchanged source isn't hammered
+    checked: Mutex<Option<Instant>>,
+    /// Held while loading, so a burst of requests loads once
+    fetching: tokio::sync::Mutex<()>,
+}
+
+impl std::fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtAuth")
+            .field("issuer", &self.issuer)
+            .field("audiences", &self.audiences)
+            .finish()
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Whether `claim` is `expected`, or an array containing it
+fn claim_matches(claim: &Value, expected: &Value) -> bool {
+    match claim {
+        Value::Array(values) if !expected.is_array() => values.contains(expected),
+        _ => claim == expected,
+    }
+}
+
+/// Header value for a claim: strings as they are, arrays comma separated
+fn claim_header_value(claim: &Value) -> String {
+    match claim {
+        Value::String(value) => value.clone(),
+        Value::Array(values) => values.iter().map(claim_header_value).collect::<Vec<_>>().join(","),
+        other => other.to_string(),
+    }
+}
+
+impl JwtAuth {
+    pub fn new(config: &JwtConfig) -> anyhow::Result<Self> {
+        let source = match (&config.jwks_file, &config.jwks_url) {
+            (Some(path), None) => KeySource::File(path.clone()),
+            (None, Some(url)) => {
+                let url = reqwest::Url::parse(url)
+                    .map_err(|e| anyhow::anyhow!("Invalid jwks_url {:?}: {}", url, e))?;
+                let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?;
+                KeySource::Url(url, client)
+            }
+            _ => return Err(anyhow::anyhow!("jwt needs exactly one of jwks_file and jwks_url")),
+        };
+        let claim_headers = config
+            .claim_headers
+            .iter()
+            .map(|(claim, header)| {
+                HeaderName::from_bytes(header.as_bytes())
+                    .map(|header| (claim.clone(), header))
+                    .map_err(|_| anyhow::anyhow!("Invalid claim header {:?}", header))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // A key file must be readable from the start, a URL is fetched on first use
+        let mut keys = KeySet {
+            keys: Vec::new(),
+            loaded: None,
+            modified: None,
+        };
+        if let KeySource::File(path) = &source {
+            let json = std::fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path, e))?;
+            keys.keys = parse_jwks(&json).map_err(|e| anyhow::anyhow!("Invalid JWKS in {}: {}", path, e))?;
+            keys.loaded = Some(Instant::now());
+            keys.modified = modified(path);
+        }
+
+        Ok(Self {
+            source,
+            cache: Duration::from_secs(config.jwks_cache_seconds),
+            issuer: config.issuer.clone(),
+            audiences: config.audiences.clone(),
+            leeway: config.leeway_seconds,
+            claim_headers,
+            realm: config.realm.clone(),
+            keys: RwLock::new(Arc::new(keys)),
+            checked: Mutex::new(None),
+            fetching: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Whether the keys should be loaded again. `unknown_kid` asks for a fetch ahead
+    /// of time, for keys the issuer rotated in.
+    fn stale(&self, keys: &KeySet, unknown_kid: bool) -> bool {
+        let mut checked = self.checked.lock().unwrap();
+        let since = |interval: Duration| checked.is_none_or(|checked| checked.elapsed() >= interval);
+        let stale = match &self.source {
+            KeySource::File(path) => {
+                let due = since(FILE_CHECK_INTERVAL);
+                if due {
+                    *checked = Some(Instant::now());
+                }
+                return due && modified(path) != keys.modified;
+            }
+            KeySource::Url(..) => match keys.loaded {
+                None => since(RETRY_INTERVAL),
+                Some(loaded) => {
+                    (loaded.elapsed() >= self.cache && since(RETRY_INTERVAL))
+                        || (unknown_kid && since(MIN_REFETCH_INTERVAL))
+                }
+            },
+        };
+        if stale {
+            *checked = Some(Instant::now());
+        }
+        stale
+    }
+
+    async fn load(&self) -> anyhow::Result<KeySet> {
+        match &self.source {
+            KeySource::File(path) => {
+                let modified = modified(path);
+                let json = std::fs::read(path)?;
+                Ok(KeySet {
+                    keys: parse_jwks(&json)?,
+                    loaded: Some(Instant::now()),
+                    modified,
+                })
+            }
+            KeySource::Url(url, client) => {
+                let json = client.get(url.clone()).send().await?.error_for_status()?.bytes().await?;
+                Ok(KeySet {
+                    keys: parse_jwks(&json)?,
+                    loaded: Some(Instant::now()),
+                    modified: None,
+                })
+            }
+        }
+    }
+
+    /// The current keys, reloaded first when stale. Keys that fail to load leave
+    /// the previous ones in use.
+    async fn keys(&self, kid: Option<&str>) -> Arc<KeySet> {
+        let keys = self.keys.read().unwrap().clone();
+        // Issuers with keys without an id may still name one in their tokens, which
+        // mustn't cause a fetch every time
+        let unknown_kid = kid.is_some() && candidates(&keys.keys, kid).is_empty();
+        if !self.stale(&keys, unknown_kid) {
+            return keys;
+        }
+
+        let _fetching = match self.fetching.try_lock() {
+            Ok(fetching) => fetching,
+            // Another request is loading them, wait for it only if there are no keys yet
+            Err(_) if keys.loaded.is_some() => return keys,
+            Err(_) => {
+                let fetching = self.fetching.lock().await;
+                let current = self.keys.read().unwrap().clone();
+                if current.loaded.is_some() {
+                    return current;
+                }
+                fetching
+            }
+        };
+        match self.load().await {
+            Ok(loaded) => {
+                info!("Loaded {} JWT signing keys", loaded.keys.len());
+                let loaded = Arc::new(loaded);
+                *self.keys.write().unwrap() = loaded.clone();
+                loaded
+            }
+            Err(e) => {
+                warn!("Failed to load JWKS, keeping the previous keys: {}", e);
+                keys
+            }
+        }
+    }
+
+    /// The claims of a valid bearer token in the `Authorization` header value
+    /// that satisfies `requirements`
+    pub async fn authenticate(
+        &self,
+        authorization: Option<&str>,
+        requirements: &Requirements,
+    ) -> Result<Map<String, Value>, Rejection> {
+        let token = authorization
+            .and_then(|value| value.trim().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim())
+            .ok_or(Rejection::Missing)?;
+
+        let invalid = |reason: &str| Rejection::Invalid(reason.to_string());
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("malformed token"));
+        };
+        let decode = |part: &str| -> Option<Map<String, Value>> { serde_json::from_slice(&base64url(part)?).ok() };
+        let header_json = decode(header).ok_or_else(|| invalid("malformed token header"))?;
+        let claims = decode(payload).ok_or_else(|| invalid("malformed token claims"))?;
+        let signature = base64url(signature).ok_or_else(|| invalid("malformed token signature"))?;
+
+        // "none" and the HMAC algorithms never match a public key
+        let alg = header_json.get("alg").and_then(Value::as_str).ok_or_else(|| invalid("token without alg"))?;
+        // No header extensions are supported, so none may be critical (RFC 7515 section 4.1.11)
+        if header_json.contains_key("crit") {
+            return Err(invalid("unsupported critical header"));
+        }
+        let kid = header_json.get("kid").and_then(Value::as_str);
+        let keys = self.keys(kid).await;
+        if keys.loaded.is_none() {
+            return Err(Rejection::Unavailable("JWT signing keys not loaded".to_string()));
+        }
+        let message = &token.as_bytes()[..header.len() + 1 + payload.len()];
+        let verified = candidates(&keys.keys, kid)
+            .into_iter()
+            .any(|key| key.verify(alg, message, &signature));
+        if !verified {
+            return Err(invalid("signature not verified"));
+        }
+
+        self.validate(&claims)?;
+        check_requirements(&claims, requirements)?;
+        Ok(claims)
+    }
+
+    /// Check the registered claims: `exp` (required), `nbf`, `iss` and `aud`
+    fn validate(&self, claims: &Map<String, Value>) -> Result<(), Rejection> {
+        let invalid = |reason: &str| Err(Rejection::Invalid(reason.to_string()));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        match claims.get("exp").and_then(Value::as_u64) {
+            Some(exp) if now >= exp.saturating_add(self.leeway) => return invalid("token expired"),
+            Some(_) => {}
+            None => return invalid("token without exp"),
+        }
+        if let Some(nbf) = claims.get("nbf").and_then(Value::as_u64)
+            && now.saturating_add(self.leeway) < nbf
+        {
+            return invalid("token not valid yet");
+        }
+        if let Some(issuer) = &self.issuer
+            && claims.get("iss").and_then(Value::as_str) != Some(issuer.as_str())
+        {
+            return invalid("wrong issuer");
+        }
+        if !self.audiences.is_empty() {
+            let accepted = |aud: &Value| aud.as_str().is_some_and(|aud| self.audiences.iter().any(|a| a == aud));
+            let ok = match claims.get("aud") {
+                Some(Value::Array(auds)) => auds.iter().any(accepted),
+                Some(aud) => accepted(aud),
+                None => false,
+            };
+            if !ok {
+                return invalid("wrong audience");
+            }
+        }
+        Ok(())
+    }
+
+    /// Headers forwarding the configured claims, each with no value if the claim
+    /// is missing so that any sent by the client are removed
+    pub fn claim_headers(&self, claims: &Map<String, Value>) -> Vec<(HeaderName, Vec<HeaderValue>)> {
+        self.claim_headers
+            .iter()
+            .map(|(claim, header)| {
+                let value = claims
+                    .get(claim)
+                    .and_then(|value| HeaderValue::from_str(&claim_header_value(value)).ok());
+                (header.clone(), value.into_iter().collect())
+            })
+            .collect()
+    }
+}
+
+fn check_requirements(claims: &Map<String, Value>, requirements: &Requirements) -> Result<(), Rejection> {
+    for (name, expected) in &requirements.claims {
+        if !claims.get(name).is_some_and(|claim| claim_matches(claim, expected)) {
+            return Err(Rejection::Forbidden(format!("claim {} doesn't match", name)));
+        }
+    }
+    if !requirements.scopes.is_empty() {
+        let granted = match claims.get("scope").or_else(|| claims.get("scp")) {
+            Some(Value::String(scopes)) => scopes.split(' ').map(str::to_string).collect(),
+            Some(Value::Array(scopes)) => scopes.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+            _ => Vec::new(),
+        };
+        if let Some(missing) = requirements.scopes.iter().find(|scope| !granted.contains(scope)) {
+            return Err(Rejection::Forbidden(format!("scope {} missing", missing)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+
+    use super::*;
+
+    fn key_pair(seed: u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()
+    }
+
+    fn encode(value: &[u8]) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(value)
+    }
+
+    /// JWKS with a key of id "a" (seed 1) and one without an id (seed 2)
+    fn jwt_auth(name: &str, config: Value) -> JwtAuth {
+        let jwk = |seed: u8| json!({"kty": "OKP", "crv": "Ed25519", "x": encode(key_pair(seed).public_key().as_ref())});
+        let mut keyed = jwk(1);
+        keyed["kid"] = json!("a");
+        let path = std::env::temp_dir().join(format!("pingora-jwks-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, json!({"keys": [keyed, jwk(2)]}).to_string()).unwrap();
+
+        let mut config = config;
+        config["jwks_file"] = json!(path);
+        let auth = JwtAuth::new(&serde_json::from_value(config).unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        auth
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn token(seed: u8, header: Value, claims: Value) -> String {
+        let message = format!("{}.{}", encode(header.to_string().as_bytes()), encode(claims.to_string().as_bytes()));
+        let signature = key_pair(seed).sign(message.as_bytes());
+        format!("Bearer {}.{}", message, encode(signature.as_ref()))
+    }
+
+    fn valid(seed: u8, kid: Option<&str>) -> String {
+        let mut header = json!({"alg": "EdDSA", "typ": "JWT"});
+        if let Some(kid) = kid {
+            header["kid"] = json!(kid);
+        }
+        token(seed, header, json!({"sub": "alice", "exp": now() + 300}))
+    }
+
+    async fn check(auth: &JwtAuth, token: &str, requirements: &Requirements) -> Result<Map<String, Value>, String> {
+        auth.authenticate(Some(token), requirements).await.map_err(|rejection| {
+            format!("{} {}", rejection.status(), rejection)
+        })
+    }
+
+    #[tokio::test]
+    async fn signature() {
+        let auth = jwt_auth("signature", json!({}));
+        let none = Requirements::default();
+
+        let claims = check(&auth, &valid(1, Some("a")), &none).await.unwrap();
+        assert_eq!(claims["sub"], "alice");
+        assert!(check(&auth, &valid(2, None), &none).await.is_ok());
+
+        // Another key, a changed payload, another algorithm, "none"
+        assert_eq!(check(&auth, &valid(3, None), &none).await.unwrap_err(), "401 signature not verified");
+        let token = valid(1, Some("a"));
+        let mut parts = token.split('.').collect::<Vec<_>>();
+        let forged = encode(json!({"sub": "admin", "exp": now() + 300}).to_string().as_bytes());
+        parts[1] = &forged;
+        assert!(check(&auth, &parts.join("."), &none).await.is_err());
+        let rs256 = token.replacen(
+            &encode(br#"{"alg":"EdDSA","kid":"a","typ":"JWT"}"#),
+            &encode(br#"{"alg":"RS256","kid":"a","typ":"JWT"}"#),
+            1,
+        );
+        assert_ne!(rs256, token);
+        assert!(check(&auth, &rs256, &none).await.is_err());
+        let unsigned = format!("Bearer {}.{}.", encode(br#"{"alg":"none"}"#), parts[1]);
+        assert!(check(&auth, &unsigned, &none).await.is_err());
+
+        assert_eq!(auth.authenticate(None, &none).await.unwrap_err().status(), 401);
+        assert!(check(&auth, "Bearer not.a-token", &none).await.is_err());
+        assert!(check(&auth, "Basic YWxpY2U6c2VjcmV0", &none).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn key_ids() {
+        let auth = jwt_auth("key-ids", json!({}));
+        let none = Requirements::default();
+
+        // A kid no key has falls back to the keys without one
+        assert!(check(&auth, &valid(2, Some("rotated")), &none).await.is_ok());
+        assert!(check(&auth, &valid(1, Some("rotated")), &none).await.is_err());
+        // A known kid only matches its own key
+        assert!(check(&auth, &valid(2, Some("a")), &none).await.is_err());
+
+        let keys = auth.keys.read().unwrap().clone();
+        let kids = |kid| candidates(&keys.keys, kid).iter().map(|key| key.kid.clone()).collect::<Vec<_>>();
+        assert_eq!(kids(None).len(), 2);
+        assert_eq!(kids(Some("a")), [Some("a".to_string())]);
+        assert_eq!(kids(Some("rotated")), [None]);
+    }
+
+    #[tokio::test]
+    async fn critical_headers_are_rejected() {
+        let auth = jwt_auth("crit", json!({}));
+        let header = json!({"alg": "EdDSA", "kid": "a", "crit": ["exp"], "exp": now() + 300});
+        let token = token(1, header, json!({"sub": "alice", "exp": now() + 300}));
+        assert_eq!(
+            check(&auth, &token, &Requirements::default()).await.unwrap_err(),
+            "401 unsupported critical header"
+        );
+    }
+
+    #[tokio::test]
+    async fn registered_claims() {
+        let config = json!({"issuer": "https://id.example.com", "audiences": ["app", "api"], "leeway_seconds": 60});
+        let auth = jwt_auth("claims", config);
+        let none = Requirements::default();
+        let with = |claims: Value| {
+            let mut all = json!({"iss": "https://id.example.com", "aud": "app", "exp": now() + 300});
+            all.as_object_mut().unwrap().extend(claims.as_object().unwrap().clone());
+            token(1, json!({"alg": "EdDSA", "kid": "a"}), all)
+        };
+
+        assert!(check(&auth, &with(json!({})), &none).await.is_ok());
+        assert!(check(&auth, &with(json!({"aud": ["other", "api"]})), &none).await.is_ok());
+        // Within the leeway
+        assert!(check(&auth, &with(json!({"exp": now() - 30, "nbf": now() + 30})), &none).await.is_ok());
+
+        for (claims, reason) in [
+            (json!({"exp": now() - 120}), "token expired"),
+            (json!({"exp": null}), "token without exp"),
+            (json!({"nbf": now() + 120}), "token not valid yet"),
+            (json!({"iss": "https://evil.example.com"}), "wrong issuer"),
+            (json!({"aud": "other"}), "wrong audience"),
+            (json!({"aud": ["other"]}), "wrong audience"),
+            (json!({"aud": null}), "wrong audience"),
+        ] {
+            let error = check(&auth, &with(claims.clone()), &none).await.unwrap_err();
+            assert_eq!(error, format!("401 {}", reason), "{}", claims);
+        }
+    }
+
+    #[tokio::test]
+    async fn scopes_and_claims() {
+        let auth = jwt_auth("scopes", json!({"claim_headers": {"sub": "X-User", "groups": "X-Groups"}}));
+        let token = |claims: Value| {
+            let mut all = json!({"sub": "alice", "exp": now() + 300});
+            all.as_object_mut().unwrap().extend(claims.as_object().unwrap().clone());
+            token(1, json!({"alg": "EdDSA", "kid": "a"}), all)
+        };
+        let requirements = Requirements {
+            claims: HashMap::from([("groups".to_string(), json!("admins"))]),
+            scopes: vec!["read".to_string(), "write".to_string()],
+        };
+
+        let claims = check(&auth, &token(json!({"groups": ["users", "admins"], "scope": "read write"})), &requirements)
+            .await
+            .unwrap();
+        assert!(check(&auth, &token(json!({"groups": "admins", "scp": ["write", "read"]})), &requirements).await.is_ok());
+        for claims in [
+            json!({"groups": ["users", "admins"], "scope": "read"}),
+            json!({"groups": ["users"], "scope": "read write"}),
+            json!({"scope": "read write"}),
+        ] {
+            let error = check(&auth, &token(claims.clone()), &requirements).await.unwrap_err();
+            assert!(error.starts_with("403 "), "{}: {}", claims, error);
+        }
+
+        let mut headers = auth.claim_headers(&claims);
+        headers.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        assert_eq!(headers[0].0, "x-groups");
+        assert_eq!(headers[0].1, ["users,admins"]);
+        assert_eq!(headers[1].0, "x-user");
+        assert_eq!(headers[1].1, ["alice"]);
+        // A missing claim removes the header
+        let headers = auth.claim_headers(&Map::new());
+        assert!(headers.iter().all(|(_, values)| values.is_empty()));
+    }
+}
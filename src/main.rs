@@ -1,14 +1,46 @@
 mod acme;
+mod acme_account;
+mod auth;
+mod balancer;
+mod circuit;
+mod dns;
+mod docker;
+mod forward_auth;
+mod forwarded;
+mod health;
+mod https;
+mod jwt;
 mod proxy;
+mod proxy_protocol;
+mod renewal;
+mod resolver;
+mod rewrite;
+mod split;
+mod sticky;
+mod tls;
 
-use crate::acme::{cert_covers_domains, provision_certificates, AcmeConfig};
-use crate::proxy::{DomainRouter, ProxyConfig};
-use log::info;
-use pingora::listeners::tls::TlsSettings;
+use crate::acme::{
+    cert_covers_domains, provision_certificates, AcmeChallenge, AcmeConfig, Http01Tokens,
+    TlsAlpn01Certs,
+};
+use crate::auth::HtpasswdReloadService;
+use crate::docker::DockerDiscoveryService;
+use crate::health::HealthCheckService;
+use crate::proxy::{DomainRouter, ProxyConfig, RouteTable};
+use crate::proxy_protocol::ProxyProtocolApp;
+use crate::renewal::RenewalService;
+use crate::resolver::{Resolver, ResolverService};
+use crate::tls::{CertReloadService, CertStore, TlsProxyApp};
+use log::{info, warn};
+use pingora::apps::ServerApp;
 use pingora::prelude::*;
+use pingora::proxy::http_proxy;
+use pingora::services::background::background_service;
+use pingora::services::listening::Service;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 fn main() {
     // Install the ring crypto provider for rustls before any TLS operations
@@ -23,68 +55,172 @@ fn main() {
     let reader = BufReader::new(file);
     let config: ProxyConfig = serde_json::from_reader(reader)
         .expect("Failed to parse config file");
+    config.validate().expect("Invalid configuration");
 
-    // Check if we need to provision certificates
-    if let Some(tls_config) = &config.tls {
-        if let Some(duckdns_token) = &tls_config.duckdns_token {
-            let domains: Vec<String> = config.domains.keys().cloned().collect();
-            let cert_path = PathBuf::from(&tls_config.cert_path);
-            
-            if !cert_covers_domains(&cert_path, &domains) {
-                info!("Certificate needs to be provisioned for domains: {:?}", domains);
-                
-                let acme_config = AcmeConfig {
-                    domains,
-                    duckdns_token: duckdns_token.clone(),
-                    cert_path: cert_path.clone(),
-                    key_path: PathBuf::from(&tls_config.key_path),
-                    production: tls_config.acme_production,
-                    dns_wait_seconds: tls_config.dns_wait_seconds,
-                    account_path: Some(cert_path.parent().unwrap_or(&PathBuf::from(".")).join("account.json")),
-                };
-                
-                // Run the async provisioning in a blocking context
-                let rt = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
-                rt.block_on(async {
-                    if let Err(e) = provision_certificates(&acme_config).await {
-                        eprintln!("Failed to provision certificates: {}", e);
-                        eprintln!("Continuing with existing certificates if available...");
-                    }
-                });
+    // Pending HTTP-01 and TLS-ALPN-01 challenges, shared between the ACME client and the proxy
+    let http01_tokens = Arc::new(Http01Tokens::default());
+    let tls_alpn01_certs = Arc::new(TlsAlpn01Certs::default());
+
+    // ACME settings, only present when automatic provisioning is configured
+    let acme_config = config
+        .tls
+        .as_ref()
+        .map(|tls_config| {
+            AcmeConfig::from_tls_config(
+                tls_config,
+                config.acme_domains(),
+                http01_tokens.clone(),
+                tls_alpn01_certs.clone(),
+            )
+        })
+        .transpose()
+        .expect("Invalid ACME configuration")
+        .flatten();
+
+    if let Some(acme_config) = &acme_config
+        && matches!(acme_config.challenge, AcmeChallenge::TlsAlpn01(_))
+        && config.tls_listen_addr.is_none()
+    {
+        warn!("TLS-ALPN-01 challenges need tls_listen_addr, certificates cannot be provisioned");
+    }
+
+    // Runs the async work before the server starts, dropped before the server runs
+    // its own runtimes
+    let startup = tokio::runtime::Runtime::new().expect("Failed to create tokio runtime");
+
+    // Check if we need to provision certificates. HTTP-01 and TLS-ALPN-01 challenges are
+    // answered by the proxy itself, so those are provisioned by the renewal service once it runs.
+    if let Some(acme_config) = &acme_config
+        && matches!(acme_config.challenge, AcmeChallenge::Dns01(_))
+        && !cert_covers_domains(&acme_config.cert_path, &acme_config.domains)
+    {
+        info!("Certificate needs to be provisioned for domains: {:?}", acme_config.domains);
+
+        startup.block_on(async {
+            if let Err(e) = provision_certificates(acme_config).await {
+                eprintln!("Failed to provision certificates: {}", e);
+                eprintln!("Continuing with existing certificates if available...");
             }
-        }
+        });
     }
 
     let mut my_server = Server::new(None).unwrap();
     my_server.bootstrap();
 
+    // Compile the routes once, both listeners share them and their load balancers
+    let resolver = Arc::new(Resolver::new(&config.resolver).expect("Invalid resolver configuration"));
+    let routes = Arc::new(RouteTable::new(&config, resolver).expect("Invalid route configuration"));
+
+    // Resolve the backend hosts before accepting requests, then keep them up to date
+    startup.block_on(routes.update_upstreams());
+    drop(startup);
+    my_server.add_service(background_service(
+        "backend resolver",
+        ResolverService::new(routes.clone()),
+    ));
+    my_server.add_service(background_service(
+        "health checks",
+        HealthCheckService::new(routes.clone()),
+    ));
+    if !routes.basic_auths().is_empty() {
+        my_server.add_service(background_service(
+            "htpasswd reload",
+            HtpasswdReloadService::new(routes.clone()),
+        ));
+    }
+    if let Some(docker_config) = &config.docker_discovery {
+        my_server.add_service(background_service(
+            "docker discovery",
+            DockerDiscoveryService::new(docker_config.clone(), routes.clone()),
+        ));
+    }
+
     // Create the domain router with our configuration
-    let router = DomainRouter::new(config.clone());
+    let router = DomainRouter::new(config.clone(), routes.clone(), http01_tokens.clone());
     
-    let mut proxy_service = http_proxy_service(&my_server.configuration, router);
+    let proxy_app = http_proxy(&my_server.configuration, router);
     
     // Add HTTP listener
-    proxy_service.add_tcp(&config.listen_addr);
+    if let Some(proxy_protocol) = &config.proxy_protocol {
+        let sources = proxy_protocol.trusted_sources.iter().map(ToString::to_string).collect::<Vec<_>>();
+        println!("PROXY protocol expected from {}", sources.join(", "));
+    }
+    add_listener(&mut my_server, "Pingora HTTP Proxy Service", proxy_app, &config.listen_addr, &config);
     println!("HTTP listener on {}", config.listen_addr);
 
-    // Add HTTPS listener if TLS is configured
+    // Add HTTPS listener if TLS is configured. TLS is terminated by our own acceptor
+    // so the certificate can be picked per domain from the SNI.
+    let mut served_certs = None;
     if let (Some(tls_addr), Some(tls_config)) = (&config.tls_listen_addr, &config.tls) {
-        let mut tls_settings = TlsSettings::intermediate(&tls_config.cert_path, &tls_config.key_path)
-            .expect("Failed to load TLS certificates");
-        
-        if tls_config.enable_h2 {
-            tls_settings.enable_h2();
-        }
-        
-        proxy_service.add_tls_with_settings(tls_addr, None, tls_settings);
+        let acme_certs = acme_config
+            .as_ref()
+            .is_some_and(|acme_config| matches!(acme_config.challenge, AcmeChallenge::TlsAlpn01(_)))
+            .then(|| tls_alpn01_certs.clone());
+        let cert_store = Arc::new(CertStore::from_config(&config, acme_certs)
+            .expect("Failed to load TLS certificates"));
+        served_certs = Some(cert_store.clone());
+
+        // Swap in renewed certificates without a restart
+        let reload_interval = Duration::from_secs(tls_config.cert_reload_seconds);
+        my_server.add_service(background_service(
+            "certificate reload",
+            CertReloadService::new(cert_store.clone(), reload_interval),
+        ));
+
+        let tls_proxy = http_proxy(
+            &my_server.configuration,
+            DomainRouter::new(config.clone(), routes.clone(), http01_tokens.clone()),
+        );
+        let tls_app = TlsProxyApp::new(tls_proxy, cert_store, tls_config.enable_h2);
+
+        add_listener(&mut my_server, "Pingora HTTPS Proxy Service", tls_app, tls_addr, &config);
         println!("HTTPS listener on {}", tls_addr);
     }
 
+    // Renew ACME certificates before they expire
+    if let (Some(acme_config), Some(tls_config)) = (acme_config, &config.tls) {
+        my_server.add_service(background_service(
+            "certificate renewal",
+            RenewalService::new(
+                acme_config,
+                Duration::from_secs(tls_config.renew_before_days * 86400),
+                Duration::from_secs(tls_config.renewal_check_hours * 3600),
+                served_certs,
+            ),
+        ));
+    }
+
     println!("Configured domains:");
     for (domain, backend) in &config.domains {
-        println!("  {} -> {}:{} (tls to backend: {})", domain, backend.host, backend.port, backend.tls);
+        println!("  {} -> {} (tls to backend: {})", domain, backend.settings, backend.settings.tls);
+        for route in &backend.routes {
+            let path = [&route.path, &route.path_prefix, &route.path_regex]
+                .into_iter()
+                .flatten()
+                .next()
+                .map_or("", String::as_str);
+            println!("    {} -> {} (tls to backend: {})", path, route.settings, route.settings.tls);
+        }
     }
 
-    my_server.add_service(proxy_service);
     my_server.run_forever();
 }
+
+/// Serve `app` on `addr`, reading PROXY protocol headers first if configured
+fn add_listener<A>(server: &mut Server, name: &str, app: A, addr: &str, config: &ProxyConfig)
+where
+    A: ServerApp + Send + Sync + 'static,
+{
+    match &config.proxy_protocol {
+        Some(proxy_protocol) => {
+            let mut service = Service::new(name.to_string(), ProxyProtocolApp::new(app, proxy_protocol));
+            service.add_tcp(addr);
+            server.add_service(service);
+        }
+        None => {
+            let mut service = Service::new(name.to_string(), app);
+            service.add_tcp(addr);
+            server.add_service(service);
+        }
+    }
+}
@@ -1,65 +1,22 @@
+// Shared with the proxy binary, which has no library crate
+#[path = "acme_account.rs"]
+mod acme_account;
+#[path = "dns.rs"]
+mod dns;
+
 use std::time::Duration;
 
-use clap::Parser;
+use clap::{ArgGroup, Parser};
 use tracing::{info, warn};
 
+use crate::acme_account::{contact_urls, directory, eab_key, SavedAccount};
+use crate::dns::{parse_resolver, DnsProviderConfig, PropagationCheck};
+
 use instant_acme::{
-    Account, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt, NewAccount, NewOrder,
-    OrderStatus, RetryPolicy,
+    Account, AuthorizationStatus, ChallengeType, Identifier, NewAccount, NewOrder, OrderStatus,
+    RetryPolicy,
 };
 
-/// Set TXT record via DuckDNS API
-async fn set_duckdns_txt(domain: &str, token: &str, txt_value: &str) -> anyhow::Result<()> {
-    // Extract the subdomain part (e.g., "jelly-tea" from "jelly-tea.duckdns.org")
-    let subdomain = domain
-        .strip_suffix(".duckdns.org")
-        .unwrap_or(domain);
-    
-    let url = format!(
-        "https://www.duckdns.org/update?domains={}&token={}&txt={}&verbose=true",
-        subdomain, token, txt_value
-    );
-    
-    info!("Setting DuckDNS TXT record for {}", subdomain);
-    
-    let response = reqwest::get(&url).await?.text().await?;
-    
-    if response.starts_with("OK") {
-        info!("DuckDNS TXT record set successfully: {}", response.replace('\n', " "));
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("DuckDNS API error: {}", response))
-    }
-}
-
-/// Clear TXT record via DuckDNS API
-async fn clear_duckdns_txt(domain: &str, token: &str) -> anyhow::Result<()> {
-    let subdomain = domain
-        .strip_suffix(".duckdns.org")
-        .unwrap_or(domain);
-    
-    let url = format!(
-        "https://www.duckdns.org/update?domains={}&token={}&txt=&clear=true",
-        subdomain, token
-    );
-    
-    let response = reqwest::get(&url).await?.text().await?;
-    
-    if response.starts_with("OK") {
-        info!("DuckDNS TXT record cleared for {}", subdomain);
-        Ok(())
-    } else {
-        warn!("Failed to clear TXT record: {}", response);
-        Ok(()) // Don't fail on cleanup
-    }
-}
-
-/// Wait for DNS propagation
-async fn wait_for_dns_propagation(seconds: u64) {
-    info!("Waiting {}s for DNS propagation...", seconds);
-    tokio::time::sleep(Duration::from_secs(seconds)).await;
-}
-
 #[tokio::main]
 pub async fn main() -> anyhow::Result<()> {
     // Install the ring crypto provider for rustls
@@ -70,23 +27,65 @@ pub async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     let opts = Options::parse();
 
+    let provider_config = match (&opts.dns_provider, &opts.duckdns_token) {
+        (Some(path), _) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+        (None, Some(token)) => DnsProviderConfig::Duckdns { token: token.clone() },
+        (None, None) => unreachable!("clap requires one of --dns-provider or --duckdns-token"),
+    };
+    let dns_provider = provider_config.build()?;
+
+    // All domains go into one order, so their challenge records must be able to coexist
+    if !dns_provider.capabilities().multiple_txt_values {
+        let mut slots = std::collections::HashSet::new();
+        // A wildcard's record is the one of its zone
+        let slot = |domain: &str| dns_provider.txt_slot(domain.strip_prefix("*.").unwrap_or(domain));
+        if let Some(domain) = opts.domains.iter().find(|d| !slots.insert(slot(d))) {
+            return Err(anyhow::anyhow!(
+                "{} shares its TXT record with another domain, provision it in a separate run",
+                domain
+            ));
+        }
+    }
+    let propagation = PropagationCheck {
+        resolvers: opts
+            .dns_resolver
+            .iter()
+            .map(|resolver| parse_resolver(resolver))
+            .collect::<anyhow::Result<_>>()?,
+        timeout: Duration::from_secs(opts.dns_timeout),
+        fallback_wait: Duration::from_secs(opts.dns_wait),
+    };
+
     info!("Starting certificate provisioning for: {:?}", opts.domains);
 
+    let (directory_url, account_file) = directory(opts.acme_directory.as_deref(), opts.production)?;
+    if opts.acme_directory.is_none() && !opts.production {
+        info!("Using Let's Encrypt STAGING environment (use --production for real certs)");
+    }
+
+    let contact_urls = contact_urls(&opts.contact);
+    let contact = contact_urls.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let eab = match (&opts.eab_key_id, &opts.eab_hmac_key) {
+        (Some(key_id), Some(hmac_key)) => Some(eab_key(key_id, hmac_key)?),
+        _ => None,
+    };
+
+    let builder = match &opts.acme_root_cert {
+        Some(root_cert) => Account::builder_with_root(root_cert)?,
+        None => Account::builder()?,
+    };
+
     // Create a new ACME account
-    let (account, credentials) = Account::builder()?
+    let (account, credentials) = builder
         .create(
             &NewAccount {
-                contact: &[],
+                contact: &contact,
                 terms_of_service_agreed: true,
                 only_return_existing: false,
             },
-            if opts.production {
-                LetsEncrypt::Production.url().to_owned()
-            } else {
-                info!("Using Let's Encrypt STAGING environment (use --production for real certs)");
-                LetsEncrypt::Staging.url().to_owned()
-            },
-            None,
+            directory_url,
+            eab.as_ref(),
         )
         .await?;
     
@@ -106,6 +105,7 @@ pub async fn main() -> anyhow::Result<()> {
     info!("Order created, status: {:?}", order.state().status);
 
     // Process each authorization (one per domain)
+    let mut challenge_records = Vec::new();
     let mut authorizations = order.authorizations();
     while let Some(result) = authorizations.next().await {
         let mut authz = result?;
@@ -121,22 +121,29 @@ pub async fn main() -> anyhow::Result<()> {
             }
         }
 
+        // The identifier of a wildcard authorization is its zone, whose
+        // `_acme-challenge` record the CA looks up
+        let domain = match authz.identifier().identifier {
+            Identifier::Dns(domain) => domain.clone(),
+            identifier => return Err(anyhow::anyhow!("Unsupported identifier {:?}", identifier)),
+        };
+
         // Get the DNS-01 challenge
         let mut challenge = authz
             .challenge(ChallengeType::Dns01)
             .ok_or_else(|| anyhow::anyhow!("No DNS-01 challenge found"))?;
 
-        let domain = challenge.identifier().to_string();
         let txt_value = challenge.key_authorization().dns_value();
         
         info!("Processing challenge for: {}", domain);
         info!("TXT record value: {}", txt_value);
 
-        // Set the TXT record via DuckDNS API
-        set_duckdns_txt(&domain, &opts.duckdns_token, &txt_value).await?;
-        
-        // Wait for DNS propagation (DuckDNS is usually fast, but Let's Encrypt needs time)
-        wait_for_dns_propagation(opts.dns_wait).await;
+        // Set the TXT record via the DNS provider
+        dns_provider.present(&domain, &txt_value).await?;
+        challenge_records.push((domain.clone(), txt_value.clone()));
+
+        // Wait until the record is visible (DuckDNS is usually fast, but Let's Encrypt needs time)
+        propagation.wait(&domain, &txt_value).await;
 
         // Tell ACME server we're ready
         challenge.set_ready().await?;
@@ -168,13 +175,19 @@ pub async fn main() -> anyhow::Result<()> {
     info!("Private key saved to: {}", key_path.display());
 
     // Save account credentials for renewal
-    let creds_path = opts.output_dir.join("account.json");
-    std::fs::write(&creds_path, serde_json::to_string_pretty(&credentials)?)?;
+    let creds_path = opts.output_dir.join(account_file);
+    let saved = SavedAccount {
+        credentials,
+        contact: contact_urls,
+    };
+    std::fs::write(&creds_path, serde_json::to_string_pretty(&saved)?)?;
     info!("Account credentials saved to: {}", creds_path.display());
 
     // Clean up TXT records
-    for domain in &opts.domains {
-        clear_duckdns_txt(domain, &opts.duckdns_token).await?;
+    for (domain, txt_value) in &challenge_records {
+        if let Err(e) = dns_provider.cleanup(domain, txt_value).await {
+            warn!("Failed to remove TXT record for {}: {}", domain, e);
+        }
     }
 
     println!("\n✅ Certificate provisioning complete!");
@@ -185,7 +198,8 @@ pub async fn main() -> anyhow::Result<()> {
 }
 
 #[derive(Parser)]
-#[clap(name = "provision", about = "Provision Let's Encrypt certificates using DuckDNS")]
+#[clap(name = "provision", about = "Provision ACME certificates using DNS-01 challenges")]
+#[clap(group(ArgGroup::new("dns").required(true).args(["duckdns_token", "dns_provider"])))]
 pub struct Options {
     /// Domain names to provision (e.g., jelly-tea.duckdns.org)
     #[clap(long, required = true)]
@@ -193,7 +207,11 @@ pub struct Options {
     
     /// DuckDNS API token
     #[clap(long)]
-    duckdns_token: String,
+    duckdns_token: Option<String>,
+
+    /// JSON file with a `dns_provider` block (same format as in config.json)
+    #[clap(long)]
+    dns_provider: Option<std::path::PathBuf>,
     
     /// Output directory for certificates
     #[clap(long, default_value = "./certs")]
@@ -202,8 +220,37 @@ pub struct Options {
     /// Use production Let's Encrypt (default is staging)
     #[clap(long)]
     production: bool,
+
+    /// ACME directory URL of another CA (overrides --production)
+    #[clap(long)]
+    acme_directory: Option<String>,
+
+    /// PEM root certificate to trust for the ACME directory
+    #[clap(long)]
+    acme_root_cert: Option<std::path::PathBuf>,
+
+    /// External Account Binding key identifier
+    #[clap(long, requires = "eab_hmac_key")]
+    eab_key_id: Option<String>,
+
+    /// External Account Binding HMAC key (base64url)
+    #[clap(long, requires = "eab_key_id")]
+    eab_hmac_key: Option<String>,
+
+    /// Contact email for the ACME account (repeatable)
+    #[clap(long)]
+    contact: Vec<String>,
     
-    /// Seconds to wait for DNS propagation
+    /// Resolver to poll for the TXT record, e.g. 1.1.1.1 or 127.0.0.1:5353 (repeatable;
+    /// default: the zone's authoritative name servers)
+    #[clap(long)]
+    dns_resolver: Vec<String>,
+
+    /// Seconds to poll for the TXT record before trying anyway
+    #[clap(long, default_value = "120")]
+    dns_timeout: u64,
+
+    /// Seconds to wait for DNS propagation when the record cannot be polled
     #[clap(long, default_value = "30")]
     dns_wait: u64,
 }
\ No newline at end of file
@@ -1,22 +1,160 @@
 use async_trait::async_trait;
-use log::info;
+use log::{debug, info, warn};
 use pingora::prelude::*;
-use pingora::http::RequestHeader;
+use bytes::Bytes;
+use futures::future::join_all;
+use pingora::http::{Method, RequestHeader, ResponseHeader};
+use reqwest::header::{HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
+use regex::Regex;
 use std::collections::HashMap;
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
 
-/// Configuration for a backend service
+use crate::acme::{AcmeChallengeType, ExternalAccountBinding, Http01Tokens};
+use crate::auth::{BasicAuth, BasicAuthConfig};
+use crate::balancer::{ConnectionGuard, HashKey, LoadBalancing, UpstreamConfig, UpstreamHost, Upstreams};
+use crate::circuit::{CircuitBreaker, CircuitBreakerConfig};
+use crate::dns::DnsProviderConfig;
+use crate::docker::DockerDiscoveryConfig;
+use crate::forward_auth::{ForwardAuth, ForwardAuthConfig, Verdict};
+use crate::forwarded::{client_ip, forwarded_element, forwarded_for, is_trusted, IpNetwork};
+use crate::health::HealthCheckConfig;
+use crate::jwt::{JwtAuth, JwtConfig, Requirements};
+use crate::https::{https_location, HstsConfig};
+use crate::proxy_protocol::{
+    ProxyProtocolConfig, ProxyProtocolConnector, ProxyProtocolVersion, CONNECT_TIMEOUT as PROXY_PROTOCOL_CONNECT_TIMEOUT,
+};
+use crate::resolver::{Resolver, ResolverConfig};
+use crate::rewrite::{PathRewrite, RewriteConfig};
+use crate::split::{fnv1a, Split, SplitConfig, SplitDecision};
+use crate::sticky::{StickySessionConfig, StickySessions};
+
+/// Where and how a route forwards its requests
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct BackendConfig {
-    /// Hostname or IP of the backend service (can be Docker container name)
+pub struct RouteSettings {
+    /// Hostname or IP of the backend service (can be Docker container name),
+    /// not needed when `upstreams` is set
+    #[serde(default)]
     pub host: String,
-    /// Port the backend service is listening on
+    /// Port the backend service is listening on, not needed when `upstreams` is set
+    #[serde(default)]
     pub port: u16,
+    /// Optional: Several upstreams to balance requests across instead of `host`/`port`
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamConfig>,
+    /// Optional: How requests are spread over `upstreams`: "round_robin", "random",
+    /// "least_connections" or "ketama" (default: "round_robin")
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+    /// Optional: What "ketama" hashes: "client_ip", "header:<name>" or "cookie:<name>"
+    /// (default: "client_ip")
+    pub hash_key: Option<String>,
+    /// Optional: Keep each client on the upstream it was first sent to, via a signed cookie
+    pub sticky_session: Option<StickySessionConfig>,
+    /// Optional: Probe the upstreams and take unhealthy ones out of rotation
+    pub health_check: Option<HealthCheckConfig>,
+    /// Optional: Retries on another upstream after a failed attempt (default: 1)
+    #[serde(default = "default_retries")]
+    pub retries: usize,
+    /// Optional: Fail fast for upstreams that keep failing
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Optional: Split traffic between named backends by percentage, instead of
+    /// `host`/`port` or `upstreams`
+    pub split: Option<SplitConfig>,
+    /// Optional: Require HTTP Basic authentication. Routes without their own `auth`
+    /// use the one of their domain.
+    pub auth: Option<BasicAuthConfig>,
+    /// Optional: Let an external service decide which requests may pass. Routes
+    /// without their own `forward_auth` use the one of their domain.
+    pub forward_auth: Option<ForwardAuthConfig>,
+    /// Optional: Claim values the domain's `jwt` tokens must have, e.g. {"groups": "admins"}.
+    /// Routes without requirements of their own use those of their domain.
+    #[serde(default)]
+    pub require_claims: HashMap<String, serde_json::Value>,
+    /// Optional: Scopes the domain's `jwt` tokens must grant
+    #[serde(default)]
+    pub require_scopes: Vec<String>,
     /// Whether to use TLS when connecting to the backend
     #[serde(default)]
     pub tls: bool,
     /// SNI hostname for TLS connections (defaults to host if not specified)
     pub sni: Option<String>,
+    /// Optional: Send a PROXY protocol header, "v1" or "v2", conveying the client address
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Optional: Remove this prefix from the path before forwarding (e.g., "/app")
+    pub strip_prefix: Option<String>,
+    /// Optional: Prepend this prefix to the path before forwarding
+    pub add_prefix: Option<String>,
+    /// Optional: Regex replacement applied to the forwarded path, after the prefixes
+    pub rewrite: Option<RewriteConfig>,
+    /// Optional: Map backend paths in `Location` headers back to public ones (default: false)
+    #[serde(default)]
+    pub rewrite_location: bool,
+    /// Optional: Map the `Path` of backend cookies back to the public path (default: false)
+    #[serde(default)]
+    pub rewrite_cookie_path: bool,
+}
+
+impl fmt::Display for RouteSettings {
+    /// The backend address, the balanced upstreams with their weights, or the split variants
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(split) = &self.split {
+            let variants = split
+                .variants
+                .iter()
+                .map(|variant| format!("{} {}% -> {}", variant.name, variant.percent, variant.settings))
+                .collect::<Vec<_>>();
+            return write!(f, "split {{ {} }}", variants.join(", "));
+        }
+        if self.upstreams.is_empty() {
+            return write!(f, "{}:{}", self.host, self.port);
+        }
+        let upstreams = self
+            .upstreams
+            .iter()
+            .map(|upstream| format!("{}:{} (weight {})", upstream.host, upstream.port, upstream.weight))
+            .collect::<Vec<_>>();
+        write!(f, "[{}] ({})", upstreams.join(", "), self.load_balancing)
+    }
+}
+
+/// Configuration for a backend service
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BackendConfig {
+    /// Backend for requests that match none of the `routes`
+    #[serde(flatten)]
+    pub settings: RouteSettings,
+    /// Optional: Certificate served for this domain on the TLS listener (PEM format)
+    pub cert_path: Option<String>,
+    /// Optional: Private key matching `cert_path` (PEM format)
+    pub key_path: Option<String>,
+    /// Optional: Redirect plain HTTP requests to the HTTPS listener (default: false)
+    #[serde(default)]
+    pub force_https: bool,
+    /// Optional: Send `Strict-Transport-Security` on HTTPS responses
+    pub hsts: Option<HstsConfig>,
+    /// Optional: Require a valid `Authorization: Bearer` JSON Web Token
+    pub jwt: Option<JwtConfig>,
+    /// Optional: Path based routes to other backends, see [`RouteTable::find_backend`]
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+}
+
+/// A path based route within a domain. Exactly one of `path`, `path_prefix` and
+/// `path_regex` selects the requests it handles.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RouteConfig {
+    /// Match this exact path (e.g., "/health")
+    pub path: Option<String>,
+    /// Match this path and everything below it (e.g., "/api" matches "/api" and "/api/users")
+    pub path_prefix: Option<String>,
+    /// Match paths against this regular expression (e.g., "^/v[0-9]+/")
+    pub path_regex: Option<String>,
+    #[serde(flatten)]
+    pub settings: RouteSettings,
 }
 
 /// TLS configuration for the proxy listener
@@ -30,17 +168,61 @@ pub struct TlsConfig {
     #[serde(default = "default_true")]
     pub enable_h2: bool,
     /// Optional: DuckDNS token for automatic certificate provisioning
+    /// (shorthand for a `dns_provider` block of type "duckdns")
     pub duckdns_token: Option<String>,
+    /// Optional: DNS provider used for ACME DNS-01 challenges
+    pub dns_provider: Option<DnsProviderConfig>,
+    /// Optional: ACME challenge type, "dns-01" or "http-01" (default: "dns-01")
+    #[serde(default)]
+    pub acme_challenge: AcmeChallengeType,
     /// Optional: Use Let's Encrypt production (default: false = staging)
     #[serde(default)]
     pub acme_production: bool,
-    /// Optional: Seconds to wait for DNS propagation (default: 30)
+    /// Optional: ACME directory URL of another CA (ZeroSSL, step-ca, Pebble), overrides `acme_production`
+    pub acme_directory: Option<String>,
+    /// Optional: PEM root certificate to trust for the ACME directory (internal CAs)
+    pub acme_root_cert: Option<String>,
+    /// Optional: External Account Binding credentials from the CA
+    pub acme_eab: Option<ExternalAccountBinding>,
+    /// Optional: Contact emails for the ACME account, e.g. to receive expiry notices
+    #[serde(default)]
+    pub acme_contact: Vec<String>,
+    /// Optional: Order one certificate covering all ACME domains instead of one per domain (default: false)
+    #[serde(default)]
+    pub acme_san_order: bool,
+    /// Optional: Resolvers polled for the challenge TXT record, e.g. "1.1.1.1" or "127.0.0.1:5353"
+    /// (default: the zone's authoritative name servers)
+    #[serde(default)]
+    pub dns_resolvers: Vec<String>,
+    /// Optional: Seconds to poll for the challenge TXT record before trying anyway (default: 120)
+    #[serde(default = "default_dns_propagation_timeout")]
+    pub dns_propagation_timeout_seconds: u64,
+    /// Optional: Seconds to wait for DNS propagation when the record cannot be polled (default: 30)
     #[serde(default = "default_dns_wait")]
     pub dns_wait_seconds: u64,
+    /// Optional: Seconds between checks for changed certificate files, 0 = only on SIGHUP (default: 60)
+    #[serde(default = "default_cert_reload")]
+    pub cert_reload_seconds: u64,
+    /// Optional: Renew ACME certificates this many days before they expire (default: 30)
+    #[serde(default = "default_renew_before")]
+    pub renew_before_days: u64,
+    /// Optional: Hours between certificate expiry checks, at least 1 (default: 12)
+    #[serde(default = "default_renewal_check")]
+    pub renewal_check_hours: u64,
 }
 
+pub fn default_retries() -> usize { 1 }
+
+fn default_dns_propagation_timeout() -> u64 { 120 }
+
 fn default_dns_wait() -> u64 { 30 }
 
+fn default_cert_reload() -> u64 { 60 }
+
+fn default_renew_before() -> u64 { 30 }
+
+fn default_renewal_check() -> u64 { 12 }
+
 fn default_true() -> bool { true }
 
 /// Main proxy configuration
@@ -61,16 +243,706 @@ pub struct ProxyConfig {
     pub domains: HashMap<String, BackendConfig>,
     /// Default backend for unmatched domains (optional)
     pub default_backend: Option<BackendConfig>,
+    /// Optional: Add domains from the labels of running Docker containers
+    pub docker_discovery: Option<DockerDiscoveryConfig>,
+    /// Optional: How backend hostnames are resolved and cached
+    #[serde(default)]
+    pub resolver: ResolverConfig,
+    /// Optional: Proxies in front of this one (IPs or CIDRs) whose `X-Forwarded-*` and
+    /// `Forwarded` headers are trusted and extended
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNetwork>,
+    /// Optional: Also send the RFC 7239 `Forwarded` header to backends (default: false)
+    #[serde(default)]
+    pub forwarded_header: bool,
+    /// Optional: Accept the PROXY protocol from load balancers in front of the listeners
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    /// Optional: Port `force_https` redirects to, when it differs from the port of
+    /// `tls_listen_addr` (e.g., behind a port forward)
+    pub https_port: Option<u16>,
+}
+
+impl ProxyConfig {
+    /// Reject settings that parse but can't work
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(tls) = &self.tls
+            && tls.renewal_check_hours == 0
+        {
+            return Err(anyhow::anyhow!("tls.renewal_check_hours must be at least 1"));
+        }
+        // Without an HTTPS listener of its own the redirect needs to know where one is
+        if self.tls_listen_addr.is_none() && self.https_port.is_none() {
+            let forced = self
+                .domains
+                .iter()
+                .map(|(domain, backend)| (domain.as_str(), backend))
+                .chain(self.default_backend.iter().map(|backend| ("default_backend", backend)))
+                .find(|(_, backend)| backend.force_https);
+            if let Some((name, _)) = forced {
+                return Err(anyhow::anyhow!(
+                    "{} sets force_https, which needs tls_listen_addr or https_port",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Domains whose certificates are provisioned via ACME (those without an explicit certificate)
+    pub fn acme_domains(&self) -> Vec<String> {
+        self.domains
+            .iter()
+            .filter(|(_, backend)| backend.cert_path.is_none())
+            .map(|(domain, _)| domain.clone())
+            .collect()
+    }
+
+    /// Public port of the HTTPS listener: `https_port`, the port of `tls_listen_addr`, or 443
+    pub fn https_port(&self) -> u16 {
+        self.https_port
+            .or_else(|| {
+                let addr = self.tls_listen_addr.as_ref()?;
+                addr.rsplit_once(':')?.1.parse().ok()
+            })
+            .unwrap_or(443)
+    }
+}
+
+/// Path prefix of ACME HTTP-01 challenge requests
+const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Whether `path` is an ACME HTTP-01 challenge: the prefix followed by a single
+/// token, which is base64url and so never contains `/`, `.` or `%`
+fn is_acme_challenge(path: &str) -> bool {
+    path.strip_prefix(ACME_CHALLENGE_PATH).is_some_and(|token| {
+        !token.is_empty() && token.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    })
+}
+
+/// How a route selects requests by path
+#[derive(Debug, Clone)]
+pub enum PathMatcher {
+    Exact(String),
+    /// Matches the prefix itself and paths continuing with a new segment below it
+    Prefix(String),
+    Regex(Regex),
+    /// The domain's own backend, used when no other route matches
+    Any,
+}
+
+impl PathMatcher {
+    fn from_route(route: &RouteConfig) -> anyhow::Result<Self> {
+        match (&route.path, &route.path_prefix, &route.path_regex) {
+            (Some(path), None, None) => Ok(PathMatcher::Exact(path.clone())),
+            (None, Some(prefix), None) => Ok(PathMatcher::Prefix(prefix.clone())),
+            (None, None, Some(regex)) => Ok(PathMatcher::Regex(Regex::new(regex)?)),
+            _ => Err(anyhow::anyhow!(
+                "Route to {} needs exactly one of path, path_prefix or path_regex",
+                route.settings
+            )),
+        }
+    }
+
+    /// Specificity of the match, higher wins: exact paths, then regexes, then the
+    /// longest prefix, then the domain's catch-all. `None` if the path doesn't match.
+    fn score(&self, path: &str) -> Option<(u8, usize)> {
+        match self {
+            PathMatcher::Exact(exact) => (path == exact).then_some((3, exact.len())),
+            PathMatcher::Regex(regex) => regex.is_match(path).then_some((2, 0)),
+            PathMatcher::Prefix(prefix) => {
+                let below = path.strip_prefix(prefix.as_str())?;
+                (below.is_empty() || prefix.ends_with('/') || below.starts_with('/'))
+                    .then_some((1, prefix.len()))
+            }
+            PathMatcher::Any => Some((0, 0)),
+        }
+    }
+
+    /// Name for a cookie of the proxy's own that belongs to this route: `base` for the
+    /// domain's catch-all, with a suffix derived from the matcher for other routes
+    pub fn cookie_name(&self, base: &str) -> String {
+        match self {
+            PathMatcher::Any => base.to_string(),
+            matcher => format!("{}_{:08x}", base, fnv1a(matcher.to_string().as_bytes()) as u32),
+        }
+    }
+
+    /// `Path` attribute limiting a cookie to the requests of this route, as far as
+    /// browsers can tell: the path of exact and prefix routes, `/` otherwise
+    pub fn cookie_path(&self) -> &str {
+        match self {
+            PathMatcher::Exact(path) | PathMatcher::Prefix(path) if path.starts_with('/') => path,
+            _ => "/",
+        }
+    }
+}
+
+impl fmt::Display for PathMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathMatcher::Exact(path) => write!(f, "= {}", path),
+            PathMatcher::Prefix(prefix) => write!(f, "{}*", prefix),
+            PathMatcher::Regex(regex) => write!(f, "~ {}", regex),
+            PathMatcher::Any => f.write_str("*"),
+        }
+    }
+}
+
+/// A route of a domain, as selected by [`RouteTable::find_backend`]
+#[derive(Debug)]
+pub struct Route {
+    pub matcher: PathMatcher,
+    pub settings: RouteSettings,
+    /// Compiled path rewriting, `None` if paths are forwarded unchanged
+    pub path_rewrite: Option<PathRewrite>,
+    /// Load balancer over `settings.upstreams`, or the addresses of `host`. Empty
+    /// for a split route, whose variants have upstreams of their own.
+    pub upstreams: Upstreams,
+    /// Passive failure tracking, `None` without `circuit_breaker`
+    pub breaker: Option<CircuitBreaker>,
+    /// Traffic split between variant routes, `None` without `split`
+    pub split: Option<Split>,
+    /// Affinity cookies, `None` without `sticky_session`
+    pub sticky: Option<StickySessions>,
+    /// Whether the domain redirects plain HTTP requests to HTTPS
+    pub force_https: bool,
+    /// `Strict-Transport-Security` value of the domain, `None` without `hsts`
+    pub hsts: Option<String>,
+    /// Credentials checked before proxying, `None` without `auth`
+    pub auth: Option<Arc<BasicAuth>>,
+    /// Auth service asked before proxying, `None` without `forward_auth`
+    pub forward_auth: Option<Arc<ForwardAuth>>,
+    /// Bearer token validation of the domain, `None` without `jwt`
+    pub jwt: Option<Arc<JwtAuth>>,
+    /// Claims and scopes the bearer token must have
+    pub jwt_requirements: Requirements,
+}
+
+fn auth(settings: &RouteSettings) -> anyhow::Result<Option<Arc<BasicAuth>>> {
+    Ok(settings.auth.as_ref().map(BasicAuth::new).transpose()?.map(Arc::new))
+}
+
+fn forward_auth(settings: &RouteSettings) -> anyhow::Result<Option<Arc<ForwardAuth>>> {
+    Ok(settings.forward_auth.as_ref().map(ForwardAuth::new).transpose()?.map(Arc::new))
+}
+
+fn requirements(settings: &RouteSettings) -> Requirements {
+    Requirements {
+        claims: settings.require_claims.clone(),
+        scopes: settings.require_scopes.clone(),
+    }
+}
+
+impl Route {
+    fn new(matcher: PathMatcher, settings: &RouteSettings, resolver: &Arc<Resolver>) -> anyhow::Result<Self> {
+        if let Some(split) = &settings.split {
+            let split = Split::new(split, settings, &matcher, |variant| {
+                Route::new(matcher.clone(), variant, resolver)
+            })?;
+            return Ok(Route {
+                matcher,
+                settings: settings.clone(),
+                path_rewrite: None,
+                upstreams: Upstreams::new(&[], settings.load_balancing, None, None, resolver.clone())?,
+                breaker: None,
+                split: Some(split),
+                sticky: None,
+                force_https: false,
+                hsts: None,
+                auth: auth(settings)?,
+                forward_auth: forward_auth(settings)?,
+                jwt: None,
+                jwt_requirements: requirements(settings),
+            });
+        }
+
+        // A single host/port is balanced as a pool of one, which holds every
+        // address the host resolves to
+        let single;
+        let upstreams = match settings.upstreams.as_slice() {
+            [] if settings.host.is_empty() || settings.port == 0 => {
+                return Err(anyhow::anyhow!("Backend needs host and port, or upstreams"));
+            }
+            [] => {
+                single = [UpstreamConfig {
+                    host: settings.host.clone(),
+                    port: settings.port,
+                    weight: 1,
+                }];
+                &single[..]
+            }
+            upstreams => upstreams,
+        };
+
+        let health_check = match &settings.health_check {
+            Some(health_check) => {
+                let hosts = upstreams.iter().map(|upstream| upstream.host.as_str()).collect::<Vec<_>>();
+                let check = health_check.build(
+                    &hosts,
+                    settings.sni.as_deref(),
+                    settings.tls,
+                    settings.send_proxy_protocol,
+                )?;
+                Some((check, health_check.interval()))
+            }
+            None => None,
+        };
+        let upstreams = Upstreams::new(
+            upstreams,
+            settings.load_balancing,
+            settings.hash_key.as_deref(),
+            health_check,
+            resolver.clone(),
+        )?;
+        let sticky = settings
+            .sticky_session
+            .as_ref()
+            .map(|sticky| StickySessions::new(sticky, &matcher))
+            .transpose()?;
+
+        Ok(Route {
+            matcher,
+            settings: settings.clone(),
+            path_rewrite: PathRewrite::from_settings(settings)?,
+            upstreams,
+            breaker: settings.circuit_breaker.as_ref().map(CircuitBreaker::new),
+            split: None,
+            sticky,
+            force_https: false,
+            hsts: None,
+            auth: auth(settings)?,
+            forward_auth: forward_auth(settings)?,
+            jwt: None,
+            jwt_requirements: requirements(settings),
+        })
+    }
+}
+
+/// Per-request state shared between the proxy phases
+#[derive(Default)]
+pub struct RequestContext {
+    /// Route chosen for the request in `upstream_peer`
+    pub route: Option<Arc<Route>>,
+    /// Counts the request against the chosen upstream while it is proxied
+    pub connection: Option<ConnectionGuard>,
+    /// Upstream of the current attempt, as known to the route's circuit breaker
+    pub upstream: Option<String>,
+    /// Upstream addresses already tried, retries go elsewhere
+    pub tried: Vec<SocketAddr>,
+    /// Number of upstream attempts so far
+    pub attempts: usize,
+    /// Whether the upstream sent a response header, after which nothing is retried
+    pub upstream_responded: bool,
+    /// Variant chosen by the route's traffic split, kept for retries
+    pub variant: Option<SplitDecision>,
+    /// `Set-Cookie` value pinning the client to the current upstream, when its
+    /// affinity cookie named none or another one
+    pub sticky_cookie: Option<String>,
+    /// `Strict-Transport-Security` value for the response, set on HTTPS only
+    pub hsts: Option<String>,
+    /// Whether the client's Basic credentials are kept from the backend
+    pub strip_authorization: bool,
+    /// Headers set by the forward auth service or from token claims, replacing any
+    /// the client sent
+    pub auth_headers: Vec<(HeaderName, Vec<HeaderValue>)>,
+}
+
+/// Replace the client's values of each header with those from the auth service or
+/// token, removing it where there are none
+fn set_auth_headers(request: &mut RequestHeader, headers: &[(HeaderName, Vec<HeaderValue>)]) -> Result<()> {
+    for (name, values) in headers {
+        request.remove_header(name);
+        for value in values {
+            request.append_header(name.clone(), value.clone())?;
+        }
+    }
+    Ok(())
+}
+
+/// Methods that may be sent again after the request possibly reached the upstream
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE
+    )
+}
+
+/// Compile the routes of a domain, followed by its own backend as the catch-all.
+/// Their upstreams are empty until [`RouteTable::update_upstreams`] resolves them.
+fn compile_routes(backend: &BackendConfig, resolver: &Arc<Resolver>) -> anyhow::Result<Vec<Arc<Route>>> {
+    let hsts = backend.hsts.as_ref().map(HstsConfig::header_value);
+    let domain = Route::new(PathMatcher::Any, &backend.settings, resolver)?;
+    let domain_auth = domain.auth.clone();
+    let domain_forward_auth = domain.forward_auth.clone();
+    let domain_requirements = domain.jwt_requirements.clone();
+    let jwt = backend.jwt.as_ref().map(JwtAuth::new).transpose()?.map(Arc::new);
+    // Every route of the domain shares its HTTPS settings and token validation, and
+    // its authentication unless the route has its own
+    let domain_route = |route: Route| -> anyhow::Result<Arc<Route>> {
+        let route = Route {
+            force_https: backend.force_https,
+            hsts: hsts.clone(),
+            auth: route.auth.clone().or_else(|| domain_auth.clone()),
+            forward_auth: route.forward_auth.clone().or_else(|| domain_forward_auth.clone()),
+            jwt: jwt.clone(),
+            jwt_requirements: match route.jwt_requirements.is_empty() {
+                true => domain_requirements.clone(),
+                false => route.jwt_requirements.clone(),
+            },
+            ..route
+        };
+        if route.jwt.is_none() && !route.jwt_requirements.is_empty() {
+            return Err(anyhow::anyhow!("require_claims and require_scopes need jwt on the domain"));
+        }
+        if route.jwt.is_some() && route.auth.is_some() {
+            return Err(anyhow::anyhow!("jwt and auth can't both use the Authorization header"));
+        }
+        Ok(Arc::new(route))
+    };
+
+    let mut routes = backend
+        .routes
+        .iter()
+        .map(|route| domain_route(Route::new(PathMatcher::from_route(route)?, &route.settings, resolver)?))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    routes.push(domain_route(domain)?);
+    Ok(routes)
+}
+
+/// Whether the request came in on the TLS listener
+fn is_tls(session: &Session) -> bool {
+    session.digest().is_some_and(|digest| digest.ssl_digest.is_some())
+}
+
+/// Compiled routes per domain key, catch-all last
+pub type DomainRoutes = HashMap<String, Vec<Arc<Route>>>;
+
+/// The compiled routes of all domains. Built once and shared by the HTTP and TLS
+/// listeners, so both balance over the same upstream state.
+pub struct RouteTable {
+    /// Resolves the hostnames of every route's upstreams
+    resolver: Arc<Resolver>,
+    /// Domains from the configuration file
+    static_routes: DomainRoutes,
+    /// Static domains merged with discovered ones, replaced when discovery changes
+    routes: RwLock<Arc<DomainRoutes>>,
+    default_routes: Vec<Arc<Route>>,
+}
+
+impl RouteTable {
+    pub fn new(config: &ProxyConfig, resolver: Arc<Resolver>) -> anyhow::Result<Self> {
+        let routes = config
+            .domains
+            .iter()
+            .map(|(domain, backend)| {
+                compile_routes(backend, &resolver)
+                    .map(|routes| (domain.clone(), routes))
+                    .map_err(|e| anyhow::anyhow!("Invalid routes for {}: {}", domain, e))
+            })
+            .collect::<anyhow::Result<DomainRoutes>>()?;
+        let default_routes = match &config.default_backend {
+            Some(backend) => compile_routes(backend, &resolver)
+                .map_err(|e| anyhow::anyhow!("Invalid routes for default_backend: {}", e))?,
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            resolver,
+            routes: RwLock::new(Arc::new(routes.clone())),
+            static_routes: routes,
+            default_routes,
+        })
+    }
+
+    pub fn resolver(&self) -> &Arc<Resolver> {
+        &self.resolver
+    }
+
+    /// Compile the routes of a domain added at runtime, see [`compile_routes`]
+    pub fn compile(&self, backend: &BackendConfig) -> anyhow::Result<Vec<Arc<Route>>> {
+        compile_routes(backend, &self.resolver)
+    }
+
+    /// Re-resolve the upstreams of every route whose cached addresses expired
+    pub async fn update_upstreams(&self) {
+        let routes = self.iter();
+        join_all(routes.iter().map(|(_, route)| route.upstreams.update())).await;
+    }
+
+    /// Replace the discovered domains. Domains from the configuration file take
+    /// precedence over discovered ones with the same name.
+    pub fn set_discovered(&self, discovered: DomainRoutes) {
+        let mut routes = self.static_routes.clone();
+        for (domain, domain_routes) in discovered {
+            routes.entry(domain).or_insert(domain_routes);
+        }
+        *self.routes.write().unwrap() = Arc::new(routes);
+    }
+
+    /// Whether `domain` is configured in the configuration file
+    pub fn is_static(&self, domain: &str) -> bool {
+        self.static_routes.contains_key(domain)
+    }
+
+    /// Every route with the domain it belongs to ("default_backend" for the default).
+    /// Split routes are replaced by their variants, named "<domain> variant <name>".
+    pub fn iter(&self) -> Vec<(String, Arc<Route>)> {
+        let routes = self.routes.read().unwrap().clone();
+        routes
+            .iter()
+            .flat_map(|(domain, routes)| routes.iter().map(move |route| (domain.clone(), route.clone())))
+            .chain(self.default_routes.iter().map(|route| ("default_backend".to_string(), route.clone())))
+            .flat_map(|(domain, route)| match &route.split {
+                Some(split) => split
+                    .variants
+                    .iter()
+                    .map(|variant| (format!("{} variant {}", domain, variant.name), variant.route.clone()))
+                    .collect(),
+                None => vec![(domain, route)],
+            })
+            .collect()
+    }
+
+    /// Every distinct [`BasicAuth`] in use, including those of split routes and their variants
+    pub fn basic_auths(&self) -> Vec<Arc<BasicAuth>> {
+        let routes = self.routes.read().unwrap().clone();
+        let mut auths: Vec<Arc<BasicAuth>> = Vec::new();
+        let all = routes.values().flatten().chain(self.default_routes.iter());
+        for route in all {
+            let variants = route.split.iter().flat_map(|split| split.variants.iter().map(|variant| &variant.route));
+            for auth in std::iter::once(route).chain(variants).filter_map(|route| route.auth.as_ref()) {
+                if !auths.iter().any(|known| Arc::ptr_eq(known, auth)) {
+                    auths.push(auth.clone());
+                }
+            }
+        }
+        auths
+    }
+
+    /// Find the route for a given host and path. The domain is picked by exact match,
+    /// then the longest matching wildcard, then the default backend. Within the domain
+    /// the most specific matching route wins: an exact path, then the first matching
+    /// regex, then the longest prefix, then the domain's own backend.
+    pub fn find_backend(&self, host: &str, path: &str) -> Option<Arc<Route>> {
+        let domains = self.routes.read().unwrap().clone();
+
+        // Exact match first
+        let routes = domains.get(host).or_else(|| {
+            // Try wildcard match (e.g., "*.example.com" matches "app.example.com")
+            domains
+                .iter()
+                .filter(|(domain, _)| {
+                    domain.starts_with("*.") && host.ends_with(&domain[1..]) // ".example.com"
+                })
+                .max_by_key(|(domain, _)| domain.len())
+                .map(|(_, routes)| routes)
+        });
+
+        // Fall back to default backend
+        let routes = routes.unwrap_or(&self.default_routes);
+
+        let mut best: Option<(&Arc<Route>, (u8, usize))> = None;
+        for route in routes {
+            if let Some(score) = route.matcher.score(path)
+                && best.is_none_or(|(_, best_score)| score > best_score)
+            {
+                best = Some((route, score));
+            }
+        }
+        best.map(|(route, _)| route.clone())
+    }
 }
 
 /// Domain-based router that implements ProxyHttp
 pub struct DomainRouter {
     config: ProxyConfig,
+    routes: Arc<RouteTable>,
+    /// Pending ACME HTTP-01 challenges, answered before routing
+    acme_tokens: Arc<Http01Tokens>,
+}
+
+/// Value of the cookie `name` in a `Cookie` request header
+fn cookie_value<'a>(cookie_header: &'a str, name: &str) -> Option<&'a str> {
+    cookie_header
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
 }
 
 impl DomainRouter {
-    pub fn new(config: ProxyConfig) -> Self {
-        Self { config }
+    pub fn new(config: ProxyConfig, routes: Arc<RouteTable>, acme_tokens: Arc<Http01Tokens>) -> Self {
+        Self {
+            config,
+            routes,
+            acme_tokens,
+        }
+    }
+
+    /// Count a failed attempt against the current upstream's circuit
+    fn record_failure(&self, ctx: &RequestContext) {
+        if let (Some(route), Some(upstream)) = (&ctx.route, &ctx.upstream)
+            && let Some(breaker) = &route.breaker
+        {
+            breaker.record_failure(upstream);
+        }
+    }
+
+    /// Whether a failed attempt may be retried: the route has retries left and
+    /// another healthy upstream with a closed circuit that wasn't tried yet
+    fn can_retry(&self, ctx: &RequestContext) -> bool {
+        let Some(route) = &ctx.route else {
+            return false;
+        };
+        if ctx.attempts > route.settings.retries {
+            return false;
+        }
+        route
+            .upstreams
+            .select_with(b"", |upstream, healthy| {
+                healthy
+                    && upstream.as_inet().is_some_and(|addr| {
+                        !ctx.tried.contains(addr)
+                            && route
+                                .breaker
+                                .as_ref()
+                                .is_none_or(|breaker| !breaker.is_open(&addr.to_string()))
+                    })
+            })
+            .is_some()
+    }
+
+    /// Value of the request cookie `name`
+    fn request_cookie<'a>(&self, session: &'a Session, name: &str) -> Option<&'a str> {
+        session
+            .req_header()
+            .headers
+            .get_all("cookie")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(|cookies| cookie_value(cookies, name))
+    }
+
+    /// The request's value for a ketama hash key. Requests without the header or
+    /// cookie are hashed by client IP instead.
+    fn hash_key_value(&self, session: &Session, key: &HashKey) -> Vec<u8> {
+        let headers = &session.req_header().headers;
+        let value = match key {
+            HashKey::ClientIp => None,
+            HashKey::Header(name) => headers.get(name.as_str()).map(|value| value.as_bytes()),
+            HashKey::Cookie(name) => self.request_cookie(session, name).map(str::as_bytes),
+        };
+        match value {
+            Some(value) => value.to_vec(),
+            None => session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .map(|addr| addr.ip().to_string().into_bytes())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Set `X-Forwarded-*`, `X-Real-IP` and optionally `Forwarded` for the backend.
+    /// Those sent by a trusted proxy are extended (`For`) or kept (`Proto`, `Host`,
+    /// `Port`), those sent by anyone else are replaced, or removed in the case of
+    /// `Forwarded` when it is not enabled.
+    fn forwarded_headers(&self, session: &Session, upstream_request: &mut RequestHeader) -> Result<()> {
+        let Some(peer) = session.client_addr().and_then(|addr| addr.as_inet()).map(|addr| addr.ip()) else {
+            return Ok(());
+        };
+        let trusted_proxies = &self.config.trusted_proxies;
+        let trusted = is_trusted(trusted_proxies, &peer);
+        let req = session.req_header();
+        let received = |name: &str| -> Vec<&str> {
+            if !trusted {
+                return Vec::new();
+            }
+            req.headers.get_all(name).iter().filter_map(|value| value.to_str().ok()).collect()
+        };
+
+        let prior_hops = received("x-forwarded-for");
+        let client = client_ip(trusted_proxies, peer, &forwarded_for(&prior_hops));
+        let mut hops = prior_hops.join(", ");
+        if !hops.is_empty() {
+            hops.push_str(", ");
+        }
+        hops.push_str(&peer.to_string());
+        upstream_request.insert_header("X-Forwarded-For", hops)?;
+        upstream_request.insert_header("X-Real-IP", client.to_string())?;
+
+        let proto = if is_tls(session) { "https" } else { "http" };
+        let host = req
+            .headers
+            .get("host")
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| req.uri.authority().map(|authority| authority.as_str()));
+        let port = session.server_addr().and_then(|addr| addr.as_inet()).map(|addr| addr.port());
+        if received("x-forwarded-proto").is_empty() {
+            upstream_request.insert_header("X-Forwarded-Proto", proto)?;
+        }
+        if received("x-forwarded-host").is_empty() {
+            match host {
+                Some(host) => upstream_request.insert_header("X-Forwarded-Host", host)?,
+                None => {
+                    upstream_request.remove_header("X-Forwarded-Host");
+                }
+            }
+        }
+        if received("x-forwarded-port").is_empty() {
+            match port {
+                Some(port) => upstream_request.insert_header("X-Forwarded-Port", port.to_string())?,
+                None => {
+                    upstream_request.remove_header("X-Forwarded-Port");
+                }
+            }
+        }
+
+        if self.config.forwarded_header {
+            let mut forwarded = received("forwarded").join(", ");
+            if !forwarded.is_empty() {
+                forwarded.push_str(", ");
+            }
+            forwarded.push_str(&forwarded_element(peer, proto, host));
+            upstream_request.insert_header("Forwarded", forwarded)?;
+        } else if !trusted {
+            // Backends may read it even when this proxy doesn't write it
+            upstream_request.remove_header("Forwarded");
+        }
+        Ok(())
+    }
+
+    /// Point redirects and cookies from the backend at the public paths
+    fn rewrite_response(
+        &self,
+        session: &Session,
+        upstream_response: &mut ResponseHeader,
+        rewrite: &PathRewrite,
+    ) -> Result<()> {
+        let host = self.get_host_from_session(session).unwrap_or_default();
+        let location = upstream_response
+            .headers
+            .get("location")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|location| rewrite.rewrite_location(location, &host));
+        if let Some(location) = location {
+            upstream_response.insert_header("Location", location)?;
+        }
+
+        let cookies = upstream_response
+            .headers
+            .get_all("set-cookie")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(|cookie| rewrite.rewrite_cookie_path(cookie).unwrap_or_else(|| cookie.to_string()))
+            .collect::<Vec<_>>();
+        if !cookies.is_empty() {
+            upstream_response.remove_header("Set-Cookie");
+            for cookie in cookies {
+                upstream_response.append_header("Set-Cookie", cookie)?;
+            }
+        }
+
+        Ok(())
     }
 
     /// Extract the host from the request, handling both Host header and :authority pseudo-header
@@ -78,20 +950,20 @@ impl DomainRouter {
         let req_header = session.req_header();
         
         // Try Host header first (HTTP/1.1)
-        if let Some(host) = req_header.headers.get("host") {
-            if let Ok(host_str) = host.to_str() {
-                // Strip port if present (e.g., "domain.com:8080" -> "domain.com")
-                let host_without_port = host_str.split(':').next().unwrap_or(host_str);
-                return Some(host_without_port.to_lowercase());
-            }
+        if let Some(host) = req_header.headers.get("host")
+            && let Ok(host_str) = host.to_str()
+        {
+            // Strip port if present (e.g., "domain.com:8080" -> "domain.com")
+            let host_without_port = host_str.split(':').next().unwrap_or(host_str);
+            return Some(host_without_port.to_lowercase());
         }
         
         // Try :authority pseudo-header (HTTP/2)
-        if let Some(authority) = req_header.headers.get(":authority") {
-            if let Ok(auth_str) = authority.to_str() {
-                let host_without_port = auth_str.split(':').next().unwrap_or(auth_str);
-                return Some(host_without_port.to_lowercase());
-            }
+        if let Some(authority) = req_header.headers.get(":authority")
+            && let Ok(auth_str) = authority.to_str()
+        {
+            let host_without_port = auth_str.split(':').next().unwrap_or(auth_str);
+            return Some(host_without_port.to_lowercase());
         }
         
         // Try URI host as last resort
@@ -101,39 +973,145 @@ impl DomainRouter {
         
         None
     }
+}
 
-    /// Find the backend for a given host
-    fn find_backend(&self, host: &str) -> Option<&BackendConfig> {
-        // Exact match first
-        if let Some(backend) = self.config.domains.get(host) {
-            return Some(backend);
+#[async_trait]
+impl ProxyHttp for DomainRouter {
+    type CTX = RequestContext;
+
+    fn new_ctx(&self) -> Self::CTX {
+        RequestContext::default()
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        // Answer ACME HTTP-01 challenges for pending orders; unknown tokens are
+        // proxied as usual in case a backend runs its own ACME client
+        let key_authorization = session
+            .req_header()
+            .uri
+            .path()
+            .strip_prefix(ACME_CHALLENGE_PATH)
+            .and_then(|token| self.acme_tokens.get(token));
+
+        if let Some(key_authorization) = key_authorization {
+            info!("Answering ACME HTTP-01 challenge");
+            let mut header = ResponseHeader::build(200, Some(2))?;
+            header.insert_header("Content-Type", "text/plain")?;
+            header.insert_header("Content-Length", key_authorization.len().to_string())?;
+            session.write_response_header(Box::new(header), false).await?;
+            session
+                .write_response_body(Some(Bytes::from(key_authorization)), true)
+                .await?;
+            return Ok(true);
         }
-        
-        // Try wildcard match (e.g., "*.example.com" matches "app.example.com")
-        for (domain, backend) in &self.config.domains {
-            if domain.starts_with("*.") {
-                let suffix = &domain[1..]; // ".example.com"
-                if host.ends_with(suffix) {
-                    return Some(backend);
+
+        let host = self.get_host_from_session(session).unwrap_or_default();
+        let route = self.routes.find_backend(&host, session.req_header().uri.path());
+        let tls = is_tls(session);
+        // Challenges for a backend's own ACME client are not redirected, but still authenticated
+        let acme_challenge = is_acme_challenge(session.req_header().uri.path());
+
+        // Send plain HTTP to the HTTPS listener
+        if let Some(route) = &route
+            && route.force_https
+            && !tls
+            && !acme_challenge
+        {
+            let req = session.req_header();
+            let path_and_query = req.uri.path_and_query().map_or("/", |path| path.as_str());
+            let location = https_location(&host, self.config.https_port(), path_and_query);
+            // 308 keeps the method and body of anything but GET and HEAD
+            let status = if matches!(req.method, Method::GET | Method::HEAD) { 301 } else { 308 };
+            info!("Redirecting {} {} -> {} ({})", host, path_and_query, location, status);
+
+            let mut header = ResponseHeader::build(status, Some(2))?;
+            header.insert_header("Location", location)?;
+            header.insert_header("Content-Length", "0")?;
+            session.write_response_header(Box::new(header), true).await?;
+            return Ok(true);
+        }
+
+        let authorization = session
+            .req_header()
+            .headers
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        // Ask for credentials
+        if let Some(auth) = route.as_ref().and_then(|route| route.auth.as_ref()) {
+            match auth.authenticate(authorization.as_deref()).await {
+                Some(user) => {
+                    debug!("Authenticated {} for {}", user, host);
+                    ctx.strip_authorization = !auth.passthrough;
+                }
+                None => {
+                    if authorization.is_some() {
+                        warn!("Rejected credentials for {} from {:?}", host, session.client_addr());
+                    }
+                    let mut header = ResponseHeader::build(401, Some(2))?;
+                    header.insert_header("WWW-Authenticate", auth.challenge())?;
+                    header.insert_header("Content-Length", "0")?;
+                    session.write_response_header(Box::new(header), true).await?;
+                    return Ok(true);
                 }
             }
         }
-        
-        // Fall back to default backend
-        self.config.default_backend.as_ref()
-    }
-}
 
-#[async_trait]
-impl ProxyHttp for DomainRouter {
-    type CTX = ();
+        // Ask the auth service, passing on its answer unless it lets the request through
+        if let Some(forward_auth) = route.as_ref().and_then(|route| route.forward_auth.as_ref()) {
+            let mut request = session.req_header().clone();
+            self.forwarded_headers(session, &mut request)?;
+            match forward_auth.check(&request).await {
+                Ok(Verdict::Allow(headers)) => ctx.auth_headers = headers,
+                Ok(Verdict::Deny(header, body)) => {
+                    debug!("Forward auth answered {} for {}", header.status, host);
+                    session.write_response_header(header, body.is_empty()).await?;
+                    if !body.is_empty() {
+                        session.write_response_body(Some(body), true).await?;
+                    }
+                    return Ok(true);
+                }
+                Err(e) => {
+                    warn!("Forward auth for {} failed: {}", host, e);
+                    return pingora::Error::e_explain(ErrorType::HTTPStatus(503), "forward auth unavailable");
+                }
+            }
+        }
+
+        // Validate the bearer token and pass its claims on
+        if let Some(route) = &route
+            && let Some(jwt) = &route.jwt
+        {
+            match jwt.authenticate(authorization.as_deref(), &route.jwt_requirements).await {
+                Ok(claims) => ctx.auth_headers.extend(jwt.claim_headers(&claims)),
+                Err(rejection) => {
+                    if authorization.is_some() {
+                        warn!("Rejected bearer token for {} from {:?}: {}", host, session.client_addr(), rejection);
+                    }
+                    let mut header = ResponseHeader::build(rejection.status(), Some(2))?;
+                    let realm = jwt.realm.as_deref().unwrap_or(&host);
+                    if let Some(challenge) = rejection.challenge(realm, &route.jwt_requirements.scopes) {
+                        header.insert_header("WWW-Authenticate", challenge)?;
+                    }
+                    header.insert_header("Content-Length", "0")?;
+                    session.write_response_header(Box::new(header), true).await?;
+                    return Ok(true);
+                }
+            }
+        }
 
-    fn new_ctx(&self) -> Self::CTX {}
+        if tls {
+            ctx.hsts = route.as_ref().and_then(|route| route.hsts.clone());
+        }
+        ctx.route = route;
+        Ok(false)
+    }
 
     async fn upstream_peer(
         &self,
         session: &mut Session,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
         let req = session.req_header();
         let host = self.get_host_from_session(session)
@@ -161,48 +1139,569 @@ impl ProxyHttp for DomainRouter {
         
         info!("Incoming request for host: {}", host);
         
-        let backend = match self.find_backend(&host) {
-            Some(b) => b,
+        // Found by `request_filter`; retries stay on the route (and split variant)
+        // of the first attempt
+        let route = match ctx.route.clone().or_else(|| self.routes.find_backend(&host, path)) {
+            Some(r) => r,
             None => {
                 println!(">>> NO BACKEND for host: {} - check your config.json domains", host);
                 return Err(pingora::Error::new_str("No backend configured for host"));
             }
         };
-        
-        let upstream_addr = format!("{}:{}", backend.host, backend.port);
-        info!("Routing {} -> {}", host, upstream_addr);
-        
+        let route = match &route.split {
+            Some(split) => {
+                let cookie = split.cookie_name().and_then(|name| self.request_cookie(session, name));
+                let client_ip = session.client_addr().and_then(|addr| addr.as_inet()).map(|addr| addr.ip());
+                let (variant, decision) = split.choose(cookie, client_ip);
+                info!("Split {} (route {}): variant {}", host, route.matcher, variant.name);
+                ctx.variant = Some(decision);
+                variant.route.clone()
+            }
+            None => route,
+        };
+        let backend = &route.settings;
+
+        let upstreams = &route.upstreams;
+        let key = match &upstreams.hash_key {
+            Some(hash_key) => self.hash_key_value(session, hash_key),
+            None => Vec::new(),
+        };
+
+        let breaker = route.breaker.as_ref();
+        let tried = &ctx.tried;
+
+        // A client with an affinity cookie stays on its upstream while that is usable
+        let pinned = route.sticky.as_ref().and_then(|sticky| {
+            let cookie = self.request_cookie(session, &sticky.cookie)?;
+            let (upstream, healthy) = upstreams.find(|upstream| {
+                upstream.as_inet().is_some_and(|addr| sticky.matches(cookie, addr))
+            })?;
+            let addr = upstream.as_inet()?;
+            let usable = healthy
+                && !tried.contains(addr)
+                && breaker.is_none_or(|breaker| breaker.allows(&addr.to_string()));
+            if !usable {
+                info!("Sticky upstream {} of {} unavailable, choosing another", addr, host);
+            }
+            usable.then_some(upstream)
+        });
+
+        // Otherwise prefer upstreams not tried yet for this request, skipping open circuits
+        let is_pinned = pinned.is_some();
+        let upstream = pinned.or_else(|| {
+            upstreams
+                .select_with(&key, |upstream, healthy| {
+                    healthy
+                        && upstream.as_inet().is_some_and(|addr| {
+                            !tried.contains(addr)
+                                && breaker.is_none_or(|breaker| breaker.allows(&addr.to_string()))
+                        })
+                })
+                .or_else(|| {
+                    // Every upstream was tried, a retry may go to one of them again
+                    (!tried.is_empty()).then(|| {
+                        upstreams.select_with(&key, |upstream, healthy| {
+                            healthy
+                                && upstream.as_inet().is_some_and(|addr| {
+                                    breaker.is_none_or(|breaker| breaker.allows(&addr.to_string()))
+                                })
+                        })
+                    })?
+                })
+        });
+        let Some(upstream) = upstream else {
+            println!(">>> NO HEALTHY UPSTREAM for host: {} (route {})", host, route.matcher);
+            return Err(pingora::Error::explain(
+                ErrorType::HTTPStatus(503),
+                "No healthy upstream",
+            ));
+        };
+        let addr = *upstream.as_inet().expect("upstreams are resolved to IP addresses");
+        info!(
+            "Routing {} {} (route {}) -> {} ({})",
+            host, path, route.matcher, addr, upstreams.algorithm
+        );
+
         // Create the peer with appropriate TLS settings
-        let sni = backend.sni.clone().unwrap_or_else(|| backend.host.clone());
-        let peer = Box::new(HttpPeer::new(
-            upstream_addr.as_str(),
-            backend.tls,
-            sni,
-        ));
+        let sni = backend.sni.clone().unwrap_or_else(|| {
+            upstream
+                .ext
+                .get::<UpstreamHost>()
+                .map_or_else(|| addr.ip().to_string(), |host| host.0.clone())
+        });
+        let mut peer = Box::new(HttpPeer::new(addr, backend.tls, sni));
+        if let Some(version) = backend.send_proxy_protocol {
+            let client = session.client_addr().and_then(|addr| addr.as_inet()).copied();
+            let server = session.server_addr().and_then(|addr| addr.as_inet()).copied();
+            let timeout = peer.options.connection_timeout.unwrap_or(PROXY_PROTOCOL_CONNECT_TIMEOUT);
+            peer.options.custom_l4 = Some(Arc::new(ProxyProtocolConnector::new(version, client.zip(server), timeout)));
+            // Pooled connections carry the header of one client, keep them apart
+            let mut hasher = DefaultHasher::new();
+            client.hash(&mut hasher);
+            peer.group_key = hasher.finish();
+        }
+        ctx.connection = Some(upstreams.connections.acquire(addr));
+        ctx.tried.push(addr);
+        ctx.upstream = Some(addr.to_string());
+        ctx.sticky_cookie = route
+            .sticky
+            .as_ref()
+            .filter(|_| !is_pinned)
+            .map(|sticky| sticky.set_cookie(&addr));
+        ctx.attempts += 1;
+        ctx.route = Some(route);
         
         Ok(peer)
     }
 
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        self.record_failure(ctx);
+        // Nothing reached the upstream, so any method can go to another one
+        if self.can_retry(ctx) {
+            info!("Connecting to {} failed, retrying on another upstream", peer);
+            e.set_retry(true);
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        if *e.esource() == ErrorSource::Upstream {
+            self.record_failure(ctx);
+        }
+
+        let replayable = !session.as_ref().retry_buffer_truncated();
+        if !ctx.upstream_responded
+            && replayable
+            && is_idempotent(&session.req_header().method)
+            && self.can_retry(ctx)
+        {
+            info!("Proxying to {} failed, retrying on another upstream", peer);
+            e.set_retry(true);
+        } else {
+            // Pingora's default: retry only when a reused connection went stale
+            e.retry.decide_reuse(client_reused && replayable);
+        }
+        e
+    }
+
     async fn upstream_request_filter(
         &self,
         session: &mut Session,
         upstream_request: &mut RequestHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<()> {
+        // Apply the route's path rewriting, keeping the query string
+        if let Some(rewrite) = ctx.route.as_ref().and_then(|route| route.path_rewrite.as_ref()) {
+            let path_and_query = rewrite.upstream_path_and_query(upstream_request.uri.path(), upstream_request.uri.query());
+            debug!("Rewriting {} -> {}", upstream_request.uri, path_and_query);
+            let uri = path_and_query.parse().map_err(|e| {
+                pingora::Error::because(ErrorType::InternalError, "Invalid rewritten path", e)
+            })?;
+            upstream_request.set_uri(uri);
+        }
+
         // Preserve the original Host header for the backend
         // This is important for backends that use virtual hosting
-        if let Some(host) = session.req_header().headers.get("host") {
-            if let Ok(host_str) = host.to_str() {
-                upstream_request.insert_header("Host", host_str)?;
-            }
+        if let Some(host) = session.req_header().headers.get("host")
+            && let Ok(host_str) = host.to_str()
+        {
+            upstream_request.insert_header("Host", host_str)?;
         }
         
         // Add X-Forwarded headers for the backend to know the original request details
-        if let Some(client_addr) = session.client_addr() {
-            upstream_request.insert_header("X-Forwarded-For", client_addr.to_string())?;
+        self.forwarded_headers(session, upstream_request)?;
+
+        if ctx.strip_authorization {
+            upstream_request.remove_header("Authorization");
+        }
+        set_auth_headers(upstream_request, &ctx.auth_headers)?;
+
+        // Tell the backend which variant of a split it serves
+        if let Some(decision) = &ctx.variant {
+            upstream_request.insert_header(decision.header.clone(), decision.variant.as_str())?;
         }
-        upstream_request.insert_header("X-Forwarded-Proto", "http")?;
         
         Ok(())
     }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        // The upstream answered, which closes its circuit unless with a failure status
+        ctx.upstream_responded = true;
+        if let (Some(route), Some(upstream)) = (&ctx.route, &ctx.upstream)
+            && let Some(breaker) = &route.breaker
+        {
+            breaker.record_response(upstream, upstream_response.status.as_u16());
+        }
+
+        if let Some(rewrite) = ctx.route.as_ref().and_then(|route| route.path_rewrite.as_ref()) {
+            self.rewrite_response(session, upstream_response, rewrite)?;
+        }
+
+        // Pin new clients of a split to their variant and of a sticky route to their
+        // upstream, after the cookie paths were rewritten since these are the proxy's own
+        if let Some(cookie) = ctx.variant.as_ref().and_then(|decision| decision.cookie.as_ref()) {
+            upstream_response.append_header("Set-Cookie", cookie.as_str())?;
+        }
+        if let Some(cookie) = &ctx.sticky_cookie {
+            upstream_response.append_header("Set-Cookie", cookie.as_str())?;
+        }
+
+        if let Some(hsts) = &ctx.hsts {
+            upstream_response.insert_header("Strict-Transport-Security", hsts.as_str())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora::protocols::l4::socket::SocketAddr as PeerAddr;
+    use pingora::protocols::l4::stream::Stream as L4Stream;
+    use pingora::protocols::{GetSocketDigest, SocketDigest};
+    use serde_json::json;
+    use std::os::unix::io::AsRawFd;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn route_table(config: serde_json::Value) -> RouteTable {
+        let config: ProxyConfig = serde_json::from_value(config).unwrap();
+        RouteTable::new(&config, Arc::new(Resolver::new(&config.resolver).unwrap())).unwrap()
+    }
+
+    fn router(config: serde_json::Value) -> DomainRouter {
+        let routes = Arc::new(route_table(config.clone()));
+        DomainRouter::new(serde_json::from_value(config).unwrap(), routes, Arc::new(Http01Tokens::default()))
+    }
+
+    /// A session that read `request` from a client at `peer`, and the client's end
+    async fn read_session(request: &str, peer: &str) -> (Session, tokio::net::TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut stream = L4Stream::from(server);
+        let digest = SocketDigest::from_raw_fd(stream.as_raw_fd());
+        let _ = digest.peer_addr.set(Some(PeerAddr::Inet(peer.parse().unwrap())));
+        let _ = digest.local_addr.set(Some(PeerAddr::Inet("192.0.2.1:80".parse().unwrap())));
+        stream.set_socket_digest(digest);
+
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut session = Session::new_h1(Box::new(stream));
+        assert!(session.read_request().await.unwrap());
+        (session, client)
+    }
+
+    /// Run `request_filter`, returning the status line the client got if it answered
+    async fn filter(router: &DomainRouter, request: &str) -> Option<String> {
+        let (mut session, mut client) = read_session(request, "203.0.113.7:50000").await;
+        let mut ctx = router.new_ctx();
+        if !router.request_filter(&mut session, &mut ctx).await.unwrap() {
+            return None;
+        }
+        session.finish_body().await.unwrap();
+        drop(session);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        Some(response.lines().next().unwrap_or_default().to_string())
+    }
+
+    #[test]
+    fn route_precedence() {
+        let backend = |port: u16| json!({"host": "127.0.0.1", "port": port});
+        let route = |matcher: &str, path: &str, port: u16| {
+            let mut route = backend(port);
+            route[matcher] = json!(path);
+            route
+        };
+        let mut domain = backend(1);
+        domain["routes"] = json!([
+            route("path_prefix", "/api", 2),
+            route("path_prefix", "/api/v2", 3),
+            route("path_regex", "^/api/v[0-9]+/admin", 4),
+            route("path", "/api/v2/admin", 5),
+            route("path_prefix", "/static/", 6),
+            route("path_regex", "^/api/.*\\.json$", 7),
+        ]);
+        let routes = route_table(json!({"listen_addr": "127.0.0.1:0", "domains": {"app.example.com": domain}}));
+
+        for (path, port) in [
+            ("/", 1),
+            ("/other", 1),
+            // Prefixes end at a segment boundary
+            ("/api", 2),
+            ("/api/", 2),
+            ("/api/users", 2),
+            ("/apis", 1),
+            ("/api-docs", 1),
+            // The longest prefix wins
+            ("/api/v2", 3),
+            ("/api/v2/users", 3),
+            ("/api/v20", 2),
+            // Regexes beat prefixes, the first matching regex wins
+            ("/api/v1/admin", 4),
+            ("/api/v2/admin/users", 4),
+            ("/api/v1/admin.json", 4),
+            ("/api/users.json", 7),
+            // Exact paths beat everything
+            ("/api/v2/admin", 5),
+            // A prefix ending in '/' matches anything below it, but not itself without
+            ("/static/app.js", 6),
+            ("/static/", 6),
+            ("/static", 1),
+        ] {
+            let found = routes.find_backend("app.example.com", path).unwrap();
+            assert_eq!(found.settings.port, port, "{}", path);
+        }
+    }
+
+    #[test]
+    fn domain_precedence() {
+        let backend = |port: u16| json!({"host": "127.0.0.1", "port": port});
+        let config = json!({
+            "listen_addr": "127.0.0.1:0",
+            "domains": {
+                "app.example.com": backend(1),
+                "*.example.com": backend(2),
+                "*.internal.example.com": backend(3),
+            },
+            "default_backend": backend(4),
+        });
+        let routes = route_table(config.clone());
+
+        for (host, port) in [
+            ("app.example.com", 1),
+            ("www.example.com", 2),
+            ("db.internal.example.com", 3),
+            // The longest matching wildcard wins
+            ("a.b.internal.example.com", 3),
+            ("a.b.example.com", 2),
+            // A wildcard doesn't match its zone, nor names merely ending like it
+            ("example.com", 4),
+            ("badexample.com", 4),
+            ("app.example.org", 4),
+        ] {
+            let found = routes.find_backend(host, "/").unwrap();
+            assert_eq!(found.settings.port, port, "{}", host);
+        }
+
+        let mut config = config;
+        config.as_object_mut().unwrap().remove("default_backend");
+        assert!(route_table(config).find_backend("example.org", "/").is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_connects_retry_on_another_upstream() {
+        let router = router(json!({
+            "listen_addr": "127.0.0.1:0",
+            "domains": {
+                "app.example.com": {
+                    "upstreams": [{"host": "10.0.0.1", "port": 8080}, {"host": "10.0.0.2", "port": 8080}],
+                    "retries": 1,
+                    "circuit_breaker": {"failure_threshold": 1}
+                }
+            }
+        }));
+        router.routes.update_upstreams().await;
+        let request = "GET / HTTP/1.1\r\nHost: app.example.com\r\n\r\n";
+        let (mut session, _client) = read_session(request, "203.0.113.7:50000").await;
+        let mut ctx = router.new_ctx();
+
+        let peer = router.upstream_peer(&mut session, &mut ctx).await.unwrap();
+        let first = ctx.upstream.clone().unwrap();
+        let e = router.fail_to_connect(&mut session, &peer, &mut ctx, pingora::Error::new(ErrorType::ConnectRefused));
+        assert!(e.retry());
+        let route = ctx.route.clone().unwrap();
+        let breaker = route.breaker.as_ref().unwrap();
+        assert!(breaker.is_open(&first));
+
+        // The retry goes to the other upstream, and is the last one
+        let peer = router.upstream_peer(&mut session, &mut ctx).await.unwrap();
+        let second = ctx.upstream.clone().unwrap();
+        assert_ne!(first, second);
+        let e = router.fail_to_connect(&mut session, &peer, &mut ctx, pingora::Error::new(ErrorType::ConnectRefused));
+        assert!(!e.retry());
+        assert!(breaker.is_open(&second));
+
+        // Both circuits are open, so the next request fails fast
+        let (mut session, _client) = read_session(request, "203.0.113.7:50001").await;
+        let e = router.upstream_peer(&mut session, &mut router.new_ctx()).await.unwrap_err();
+        assert_eq!(e.etype, ErrorType::HTTPStatus(503));
+    }
+
+    #[tokio::test]
+    async fn challenge_paths_are_authenticated() {
+        let htpasswd = std::env::temp_dir().join(format!("pingora-htpasswd-{}-challenge", std::process::id()));
+        std::fs::write(&htpasswd, format!("alice:{}\n", bcrypt::hash("secret", 4).unwrap())).unwrap();
+        let config = |force_https: bool| {
+            json!({
+                "listen_addr": "127.0.0.1:0",
+                "https_port": 443,
+                "domains": {
+                    "deluge.example.com": {
+                        "host": "127.0.0.1",
+                        "port": 8112,
+                        "force_https": force_https,
+                        "auth": {"htpasswd": htpasswd}
+                    }
+                }
+            })
+        };
+        let get = |path: &str| format!("GET {} HTTP/1.1\r\nHost: deluge.example.com\r\n\r\n", path);
+
+        let redirecting = router(config(true));
+        for path in [
+            "/.well-known/acme-challenge/../admin",
+            "/.well-known/acme-challenge/%2e%2e/admin",
+            "/.well-known/acme-challenge/token/../../admin",
+        ] {
+            // Not a challenge, so redirected like any other plain HTTP request
+            let status = filter(&redirecting, &get(path)).await.unwrap();
+            assert!(status.starts_with("HTTP/1.1 301"), "{}: {}", path, status);
+        }
+
+        let router = router(config(false));
+        for path in [
+            "/.well-known/acme-challenge/../admin",
+            "/.well-known/acme-challenge/%2e%2e/admin",
+            "/.well-known/acme-challenge/LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0",
+        ] {
+            let status = filter(&router, &get(path)).await.unwrap();
+            assert!(status.starts_with("HTTP/1.1 401"), "{}: {}", path, status);
+        }
+        std::fs::remove_file(htpasswd).unwrap();
+
+        assert!(is_acme_challenge("/.well-known/acme-challenge/LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0"));
+        for path in [
+            "/.well-known/acme-challenge/",
+            "/.well-known/acme-challenge/..",
+            "/.well-known/acme-challenge/a/b",
+            "/.well-known/acme-challenge/a%2fb",
+            "/.well-known/acme-challenge/a.b",
+        ] {
+            assert!(!is_acme_challenge(path), "{}", path);
+        }
+    }
+
+    /// Headers `forwarded_headers` sends upstream for `request` from `peer`
+    async fn forwarded(config: serde_json::Value, request: &str, peer: &str) -> RequestHeader {
+        let (session, _client) = read_session(request, peer).await;
+        let mut upstream_request = session.req_header().clone();
+        router(config).forwarded_headers(&session, &mut upstream_request).unwrap();
+        upstream_request
+    }
+
+    fn header<'a>(request: &'a RequestHeader, name: &str) -> Option<&'a str> {
+        request.headers.get(name).map(|value| value.to_str().unwrap())
+    }
+
+    const SPOOFED: &str = "GET / HTTP/1.1\r\nHost: app.example.com\r\n\
+        X-Forwarded-For: 198.51.100.1\r\nX-Forwarded-Proto: https\r\n\
+        X-Forwarded-Host: evil.com\r\nX-Forwarded-Port: 443\r\n\
+        Forwarded: for=198.51.100.1\r\nX-Real-IP: 198.51.100.1\r\n\r\n";
+
+    #[tokio::test]
+    async fn untrusted_clients_cannot_spoof_forwarded_headers() {
+        let config = json!({"listen_addr": "127.0.0.1:0", "trusted_proxies": ["10.0.0.0/8"], "domains": {}});
+        let upstream = forwarded(config, SPOOFED, "203.0.113.7:50000").await;
+        assert_eq!(header(&upstream, "x-forwarded-for"), Some("203.0.113.7"));
+        assert_eq!(header(&upstream, "x-real-ip"), Some("203.0.113.7"));
+        assert_eq!(header(&upstream, "x-forwarded-proto"), Some("http"));
+        assert_eq!(header(&upstream, "x-forwarded-host"), Some("app.example.com"));
+        assert_eq!(header(&upstream, "x-forwarded-port"), Some("80"));
+        assert_eq!(header(&upstream, "forwarded"), None);
+    }
+
+    #[tokio::test]
+    async fn trusted_proxies_headers_are_kept() {
+        let config = json!({
+            "listen_addr": "127.0.0.1:0",
+            "trusted_proxies": ["10.0.0.0/8"],
+            "forwarded_header": true,
+            "domains": {}
+        });
+        let upstream = forwarded(config, SPOOFED, "10.0.0.3:50000").await;
+        assert_eq!(header(&upstream, "x-forwarded-for"), Some("198.51.100.1, 10.0.0.3"));
+        assert_eq!(header(&upstream, "x-real-ip"), Some("198.51.100.1"));
+        assert_eq!(header(&upstream, "x-forwarded-proto"), Some("https"));
+        assert_eq!(header(&upstream, "x-forwarded-host"), Some("evil.com"));
+        assert_eq!(header(&upstream, "x-forwarded-port"), Some("443"));
+        assert_eq!(
+            header(&upstream, "forwarded"),
+            Some("for=198.51.100.1, for=10.0.0.3;host=app.example.com;proto=http")
+        );
+    }
+
+    #[tokio::test]
+    async fn untrusted_clients_get_a_fresh_forwarded_header() {
+        let config = json!({"listen_addr": "127.0.0.1:0", "forwarded_header": true, "domains": {}});
+        let upstream = forwarded(config, SPOOFED, "[2001:db8::7]:50000").await;
+        assert_eq!(header(&upstream, "x-forwarded-for"), Some("2001:db8::7"));
+        assert_eq!(
+            header(&upstream, "forwarded"),
+            Some("for=\"[2001:db8::7]\";host=app.example.com;proto=http")
+        );
+    }
+
+    #[test]
+    fn auth_headers_replace_the_clients() {
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        request.insert_header("Remote-User", "admin").unwrap();
+        request.append_header("Remote-Groups", "admins").unwrap();
+        request.insert_header("Cookie", "session=abc").unwrap();
+
+        let headers = vec![
+            (HeaderName::from_static("remote-user"), Vec::new()),
+            (
+                HeaderName::from_static("remote-groups"),
+                vec![HeaderValue::from_static("users"), HeaderValue::from_static("staff")],
+            ),
+        ];
+        set_auth_headers(&mut request, &headers).unwrap();
+
+        assert!(request.headers.get("remote-user").is_none());
+        let groups = request.headers.get_all("remote-groups").iter().collect::<Vec<_>>();
+        assert_eq!(groups, ["users", "staff"]);
+        assert_eq!(request.headers["cookie"], "session=abc");
+    }
+
+    #[test]
+    fn force_https_needs_an_https_port() {
+        let config = |extra: serde_json::Value| {
+            let mut config = json!({
+                "listen_addr": "127.0.0.1:0",
+                "domains": {"app.example.com": {"host": "127.0.0.1", "port": 8080, "force_https": true}}
+            });
+            config.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            serde_json::from_value::<ProxyConfig>(config).unwrap()
+        };
+
+        let error = config(json!({})).validate().unwrap_err().to_string();
+        assert!(error.contains("app.example.com"), "{}", error);
+        assert!(config(json!({"https_port": 443})).validate().is_ok());
+        assert!(config(json!({"tls_listen_addr": "0.0.0.0:8443"})).validate().is_ok());
+
+        let mut default_backend = config(json!({}));
+        default_backend.default_backend = default_backend.domains.remove("app.example.com");
+        let error = default_backend.validate().unwrap_err().to_string();
+        assert!(error.contains("default_backend"), "{}", error);
+    }
 }
@@ -0,0 +1,471 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use pingora::apps::ServerApp;
+use pingora::connectors::L4Connect;
+use pingora::protocols::l4::socket::SocketAddr as PeerAddr;
+use pingora::protocols::l4::stream::Stream as L4Stream;
+use pingora::protocols::{SocketDigest, Stream};
+use pingora::server::ShutdownWatch;
+use pingora::{Error, ErrorType, OrErr};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::forwarded::{is_trusted, IpNetwork};
+
+/// First 12 bytes of every PROXY protocol v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header including the CRLF, as set by the specification
+const V1_MAX_LENGTH: usize = 107;
+
+/// How long a trusted source may take to send the header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long connecting to a backend and sending it the header may take, unless
+/// the peer sets a connection timeout
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepting the PROXY protocol on the listeners
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProxyProtocolConfig {
+    /// Load balancers (IPs or CIDRs) that send a PROXY protocol header, connections
+    /// from anywhere else are served without one
+    pub trusted_sources: Vec<IpNetwork>,
+}
+
+/// PROXY protocol version sent to a backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocolVersion {
+    /// Human readable, "PROXY TCP4 ..."
+    V1,
+    /// Binary
+    V2,
+}
+
+/// Parse a v1 header line without its CRLF. `None` for "UNKNOWN" connections.
+fn parse_v1(line: &[u8]) -> pingora::Result<Option<(SocketAddr, SocketAddr)>> {
+    let line = std::str::from_utf8(line).or_err(ErrorType::InvalidHTTPHeader, "PROXY v1 header is not ASCII")?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, dst, src_port, dst_port] => {
+            let addr = |ip: &str, port: &str| -> Option<SocketAddr> {
+                Some(SocketAddr::new(ip.parse().ok()?, port.parse().ok()?))
+            };
+            match (addr(src, src_port), addr(dst, dst_port)) {
+                (Some(src), Some(dst)) => Ok(Some((src, dst))),
+                _ => Error::e_explain(ErrorType::InvalidHTTPHeader, format!("invalid PROXY v1 header {:?}", line)),
+            }
+        }
+        _ => Error::e_explain(ErrorType::InvalidHTTPHeader, format!("invalid PROXY v1 header {:?}", line)),
+    }
+}
+
+/// Parse the address block of a v2 header. `None` for LOCAL connections (e.g. health
+/// checks of the load balancer) and address families other than TCP over IPv4/IPv6.
+fn parse_v2(command: u8, family: u8, addrs: &[u8]) -> pingora::Result<Option<(SocketAddr, SocketAddr)>> {
+    if command & 0xf0 != 0x20 {
+        return Error::e_explain(ErrorType::InvalidHTTPHeader, "unsupported PROXY v2 version");
+    }
+    if command & 0x0f == 0x00 {
+        return Ok(None);
+    }
+    let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1]]);
+    match family {
+        0x11 if addrs.len() >= 12 => {
+            let src = Ipv4Addr::from(<[u8; 4]>::try_from(&addrs[0..4]).unwrap());
+            let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&addrs[4..8]).unwrap());
+            Ok(Some((SocketAddr::new(src.into(), port(8)), SocketAddr::new(dst.into(), port(10)))))
+        }
+        0x21 if addrs.len() >= 36 => {
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[0..16]).unwrap());
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[16..32]).unwrap());
+            Ok(Some((SocketAddr::new(src.into(), port(32)), SocketAddr::new(dst.into(), port(34)))))
+        }
+        0x11 | 0x21 => Error::e_explain(ErrorType::InvalidHTTPHeader, "truncated PROXY v2 addresses"),
+        _ => Ok(None),
+    }
+}
+
+/// Read a v1 or v2 header from the start of `stream`, consuming exactly the header.
+/// Returns the client and the address it connected to, `None` if not conveyed.
+async fn read_header(stream: &mut Stream) -> pingora::Result<Option<(SocketAddr, SocketAddr)>> {
+    // Both versions are at least 12 bytes long ("PROXY UNKNOWN\r\n" is 15)
+    let mut start = [0u8; 12];
+    stream
+        .read_exact(&mut start)
+        .await
+        .or_err(ErrorType::ReadError, "reading PROXY header")?;
+
+    if start == V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream
+            .read_exact(&mut header)
+            .await
+            .or_err(ErrorType::ReadError, "reading PROXY v2 header")?;
+        let mut addrs = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream
+            .read_exact(&mut addrs)
+            .await
+            .or_err(ErrorType::ReadError, "reading PROXY v2 addresses")?;
+        return parse_v2(header[0], header[1], &addrs);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Error::e_explain(ErrorType::InvalidHTTPHeader, "missing PROXY header");
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Error::e_explain(ErrorType::InvalidHTTPHeader, "PROXY v1 header too long");
+        }
+        line.push(stream.read_u8().await.or_err(ErrorType::ReadError, "reading PROXY v1 header")?);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+/// Reads the PROXY protocol header of connections from trusted load balancers and
+/// hands them to the wrapped application (the HTTP proxy or [`crate::tls::TlsProxyApp`])
+/// with the conveyed client as peer address
+pub struct ProxyProtocolApp<A> {
+    app: Arc<A>,
+    trusted_sources: Vec<IpNetwork>,
+}
+
+impl<A> ProxyProtocolApp<A> {
+    pub fn new(app: A, config: &ProxyProtocolConfig) -> Self {
+        Self {
+            app: Arc::new(app),
+            trusted_sources: config.trusted_sources.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<A> ServerApp for ProxyProtocolApp<A>
+where
+    A: ServerApp + Send + Sync + 'static,
+{
+    async fn process_new(self: &Arc<Self>, mut stream: Stream, shutdown: &ShutdownWatch) -> Option<Stream> {
+        let peer = stream
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr().and_then(|addr| addr.as_inet()).copied());
+
+        if let Some(peer) = peer
+            && is_trusted(&self.trusted_sources, &peer.ip())
+        {
+            let header = match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                Ok(Ok(header)) => header,
+                Ok(Err(e)) => {
+                    warn!("Closing connection from {}: {}", peer, e);
+                    return None;
+                }
+                Err(_) => {
+                    warn!("Closing connection from {}: no PROXY header within {}s", peer, HEADER_TIMEOUT.as_secs());
+                    return None;
+                }
+            };
+
+            // The socket digest is what sessions report as client and server address
+            let fd = stream.as_any().downcast_ref::<L4Stream>().map(|l4| l4.as_raw_fd());
+            if let (Some((client, server)), Some(fd)) = (header, fd) {
+                debug!("PROXY header from {}: client {}, server {}", peer, client, server);
+                let digest = SocketDigest::from_raw_fd(fd);
+                let _ = digest.peer_addr.set(Some(PeerAddr::Inet(client)));
+                let _ = digest.local_addr.set(Some(PeerAddr::Inet(server)));
+                stream.set_socket_digest(digest);
+            }
+        }
+
+        // Reused connections come back here, but only the first request has a header
+        let mut stream = Some(stream);
+        while let Some(s) = stream {
+            stream = self.app.process_new(s, shutdown).await;
+        }
+        None
+    }
+
+    async fn cleanup(&self) {
+        self.app.cleanup().await;
+    }
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Encode a header conveying `addrs` (client, server), or a LOCAL/UNKNOWN header
+/// for connections made by the proxy itself
+pub fn encode_header(version: ProxyProtocolVersion, addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    // Both addresses need the same family, an IPv4 one is mapped otherwise
+    let addrs = addrs.map(|(src, dst)| match (src.ip(), dst.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => (src, dst),
+        _ => (
+            SocketAddr::new(ipv6(src.ip()).into(), src.port()),
+            SocketAddr::new(ipv6(dst.ip()).into(), dst.port()),
+        ),
+    });
+
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((src, dst)) => {
+                let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!("PROXY {} {} {} {} {}\r\n", family, src.ip(), dst.ip(), src.port(), dst.port())
+                    .into_bytes()
+            }
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let mut body = Vec::new();
+            match addrs {
+                Some((src, dst)) => {
+                    header.push(0x21);
+                    match (src.ip(), dst.ip()) {
+                        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                            header.push(0x11);
+                            body.extend_from_slice(&src_ip.octets());
+                            body.extend_from_slice(&dst_ip.octets());
+                        }
+                        (src_ip, dst_ip) => {
+                            header.push(0x21);
+                            body.extend_from_slice(&ipv6(src_ip).octets());
+                            body.extend_from_slice(&ipv6(dst_ip).octets());
+                        }
+                    }
+                    body.extend_from_slice(&src.port().to_be_bytes());
+                    body.extend_from_slice(&dst.port().to_be_bytes());
+                }
+                None => {
+                    header.push(0x20);
+                    header.push(0x00);
+                }
+            }
+            header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            header.extend_from_slice(&body);
+            header
+        }
+    }
+}
+
+/// Connects to a backend and sends a PROXY protocol header first. Pingora leaves
+/// the connection timeout of custom connectors to them, so it is applied here.
+#[derive(Debug)]
+pub struct ProxyProtocolConnector {
+    header: Vec<u8>,
+    timeout: Duration,
+}
+
+impl ProxyProtocolConnector {
+    pub fn new(version: ProxyProtocolVersion, addrs: Option<(SocketAddr, SocketAddr)>, timeout: Duration) -> Self {
+        Self {
+            header: encode_header(version, addrs),
+            timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl L4Connect for ProxyProtocolConnector {
+    async fn connect(&self, addr: &PeerAddr) -> pingora::Result<L4Stream> {
+        let Some(addr) = addr.as_inet() else {
+            return Error::e_explain(ErrorType::ConnectError, "PROXY protocol needs a TCP backend");
+        };
+        let connect = async {
+            let mut stream: L4Stream = pingora::protocols::l4::ext::connect(addr, None).await?.into();
+            stream
+                .write_all(&self.header)
+                .await
+                .or_err(ErrorType::WriteError, "sending PROXY header")?;
+            stream.flush().await.or_err(ErrorType::WriteError, "sending PROXY header")?;
+            Ok(stream)
+        };
+        match tokio::time::timeout(self.timeout, connect).await {
+            Ok(result) => result,
+            Err(_) => Error::e_explain(
+                ErrorType::ConnectTimedout,
+                format!("connecting to {} with PROXY header took over {:?}", addr, self.timeout),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Parse `bytes` as a connection starting with a header, returning the
+    /// conveyed addresses and what remains for the application
+    async fn read(bytes: &[u8]) -> pingora::Result<(Option<(SocketAddr, SocketAddr)>, Vec<u8>)> {
+        let mut stream: Stream = Box::new(Cursor::new(bytes.to_vec()));
+        let header = read_header(&mut stream).await?;
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        Ok((header, rest))
+    }
+
+    fn addrs(src: &str, dst: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((src.parse().unwrap(), dst.parse().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for conveyed in [
+                addrs("203.0.113.7:51234", "192.0.2.1:443"),
+                addrs("[2001:db8::7]:51234", "[2001:db8::1]:443"),
+                None,
+            ] {
+                let mut bytes = encode_header(version, conveyed);
+                bytes.extend_from_slice(b"GET / HTTP/1.1\r\n");
+                let (header, rest) = read(&bytes).await.unwrap();
+                assert_eq!(header, conveyed, "{:?}", version);
+                assert_eq!(rest, b"GET / HTTP/1.1\r\n", "{:?}", version);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mixed_families_are_mapped_to_ipv6() {
+        let mapped = addrs("[::ffff:203.0.113.7]:51234", "[2001:db8::1]:443");
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let bytes = encode_header(version, addrs("203.0.113.7:51234", "[2001:db8::1]:443"));
+            assert_eq!(read(&bytes).await.unwrap().0, mapped, "{:?}", version);
+        }
+        assert!(
+            encode_header(ProxyProtocolVersion::V1, addrs("[2001:db8::7]:1", "192.0.2.1:2"))
+                .starts_with(b"PROXY TCP6 2001:db8::7 ::ffff:192.0.2.1 1 2\r\n")
+        );
+    }
+
+    #[test]
+    fn encodings() {
+        let v1 = encode_header(ProxyProtocolVersion::V1, addrs("203.0.113.7:51234", "192.0.2.1:443"));
+        assert_eq!(v1, b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\n");
+        assert_eq!(encode_header(ProxyProtocolVersion::V1, None), b"PROXY UNKNOWN\r\n");
+
+        let v2 = encode_header(ProxyProtocolVersion::V2, addrs("203.0.113.7:51234", "192.0.2.1:443"));
+        assert_eq!(&v2[..12], V2_SIGNATURE);
+        assert_eq!(&v2[12..16], [0x21, 0x11, 0, 12]);
+        assert_eq!(&v2[16..], [203, 0, 113, 7, 192, 0, 2, 1, 0xc8, 0x22, 0x01, 0xbb]);
+        let local = encode_header(ProxyProtocolVersion::V2, None);
+        assert_eq!(&local[12..], [0x20, 0x00, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn local_and_unknown() {
+        // UNKNOWN may carry addresses, which are ignored
+        let (header, rest) = read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\nrest").await.unwrap();
+        assert_eq!((header, rest.as_slice()), (None, &b"rest"[..]));
+
+        // A LOCAL command ignores the address block, an unsupported family conveys nothing
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x11, 0, 12]);
+        local.extend_from_slice(&[0; 12]);
+        local.extend_from_slice(b"rest");
+        assert_eq!(read(&local).await.unwrap(), (None, b"rest".to_vec()));
+
+        let mut unix = V2_SIGNATURE.to_vec();
+        unix.extend_from_slice(&[0x21, 0x31, 0, 216]);
+        unix.extend_from_slice(&[0; 216]);
+        assert_eq!(read(&unix).await.unwrap(), (None, Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn v2_tlvs_are_consumed() {
+        let mut bytes = encode_header(ProxyProtocolVersion::V2, addrs("203.0.113.7:51234", "192.0.2.1:443"));
+        // An authority TLV after the addresses, counted in the length
+        bytes[15] += 7;
+        bytes.extend_from_slice(&[0x02, 0, 4]);
+        bytes.extend_from_slice(b"a.io");
+        bytes.extend_from_slice(b"rest");
+        let (header, rest) = read(&bytes).await.unwrap();
+        assert_eq!(header, addrs("203.0.113.7:51234", "192.0.2.1:443"));
+        assert_eq!(rest, b"rest");
+    }
+
+    #[tokio::test]
+    async fn truncated_headers() {
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let bytes = encode_header(version, addrs("[2001:db8::7]:51234", "[2001:db8::1]:443"));
+            for length in [0, 5, 12, 14, bytes.len() - 1] {
+                assert!(read(&bytes[..length]).await.is_err(), "{:?} cut at {}", version, length);
+            }
+        }
+
+        // Shorter address blocks than the family needs
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend_from_slice(&[0x21, 0x21, 0, 12]);
+        short.extend_from_slice(&[0; 12]);
+        assert!(read(&short).await.is_err());
+        assert!(parse_v2(0x21, 0x11, &[0; 11]).is_err());
+        assert!(parse_v2(0x11, 0x11, &[0; 12]).is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_and_invalid_v1() {
+        // The longest valid header is accepted, one byte more is not
+        let longest = format!(
+            "PROXY UNKNOWN {0} {0} 65535 65535\r\n",
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"
+        );
+        assert_eq!(longest.len(), V1_MAX_LENGTH);
+        assert!(read(longest.as_bytes()).await.is_ok());
+        let oversized = longest.replace("UNKNOWN", "UNKNOWN ");
+        assert!(read(oversized.as_bytes()).await.is_err());
+        let endless = format!("PROXY UNKNOWN {}", "x".repeat(1000));
+        assert!(read(endless.as_bytes()).await.is_err());
+
+        for invalid in [
+            &b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"[..],
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 51234\r\n",
+            b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 70000\r\n",
+            b"PROXY TCP4 example.com 192.0.2.1 51234 443\r\n",
+            b"PROXY UDP4 203.0.113.7 192.0.2.1 51234 443\r\n",
+        ] {
+            assert!(read(invalid).await.is_err(), "{:?}", String::from_utf8_lossy(invalid));
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_times_out() {
+        let connector = ProxyProtocolConnector::new(ProxyProtocolVersion::V1, None, Duration::from_millis(200));
+
+        // A listener that never accepts: once its backlog is full, handshakes hang
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let stalled = socket.listen(0).unwrap();
+        let stalled_addr = stalled.local_addr().unwrap();
+        let mut backlog = Vec::new();
+        while let Ok(Ok(stream)) =
+            tokio::time::timeout(Duration::from_millis(200), tokio::net::TcpStream::connect(stalled_addr)).await
+        {
+            backlog.push(stream);
+        }
+        let started = std::time::Instant::now();
+        let e = connector.connect(&PeerAddr::Inet(stalled_addr)).await.unwrap_err();
+        assert_eq!(e.etype, ErrorType::ConnectTimedout);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // A listener gets the header first
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut stream = connector.connect(&PeerAddr::Inet(addr)).await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        stream.write_all(b"GET /").await.unwrap();
+        let mut received = [0u8; 20];
+        accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"PROXY UNKNOWN\r\nGET /");
+    }
+}
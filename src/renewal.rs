@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use rand::Rng;
+
+use crate::acme::{
+    cert_covers_domains, cert_expiry, domain_cert_paths, provision_certificates, AcmeConfig,
+};
+use crate::tls::CertStore;
+
+/// Delay before retrying a failed renewal, doubled for every further failure
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Outcome of the most recent renewal attempt for a domain
+#[derive(Debug, Clone)]
+pub enum RenewalOutcome {
+    /// A new certificate was obtained, valid until the given time
+    Renewed(SystemTime),
+    /// Renewal failed with the given error
+    Failed(String),
+}
+
+impl std::fmt::Display for RenewalOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenewalOutcome::Renewed(expiry) => write!(
+                f,
+                "renewed, valid for {} days",
+                expiry.duration_since(SystemTime::now()).unwrap_or_default().as_secs() / 86400
+            ),
+            RenewalOutcome::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// Renewal bookkeeping for a single domain
+#[derive(Debug)]
+struct RenewalState {
+    last_attempt: SystemTime,
+    last_outcome: RenewalOutcome,
+    consecutive_failures: u32,
+    next_retry: Option<SystemTime>,
+}
+
+/// Background service that renews ACME certificates before they expire.
+///
+/// Every managed domain is checked periodically; certificates that are missing or
+/// expire within the renewal window are re-provisioned. Failed renewals are retried
+/// with jittered exponential backoff.
+pub struct RenewalService {
+    acme_config: AcmeConfig,
+    renew_before: Duration,
+    check_interval: Duration,
+    cert_store: Option<Arc<CertStore>>,
+    state: Mutex<HashMap<String, RenewalState>>,
+}
+
+impl RenewalService {
+    /// `acme_config.domains` lists the domains whose certificates are managed
+    pub fn new(
+        acme_config: AcmeConfig,
+        renew_before: Duration,
+        check_interval: Duration,
+        cert_store: Option<Arc<CertStore>>,
+    ) -> Self {
+        Self {
+            acme_config,
+            renew_before,
+            check_interval,
+            cert_store,
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cert_dir(&self) -> PathBuf {
+        self.acme_config
+            .cert_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_path_buf()
+    }
+
+    /// Renew every certificate that is missing or expires within the renewal window.
+    /// Returns how long to wait until the next check.
+    async fn check_certificates(&self) -> Duration {
+        let mut next_check = self.check_interval;
+        let cert_dir = self.cert_dir();
+
+        for domain in &self.acme_config.domains {
+            let now = SystemTime::now();
+
+            if let Some(retry_in) = self.backoff_remaining(domain, now) {
+                next_check = next_check.min(retry_in);
+                continue;
+            }
+
+            let (cert_path, _) = domain_cert_paths(&cert_dir, domain);
+            let expiry = cert_expiry(&cert_path);
+            match expiry {
+                Some(expiry) if !self.is_due(Some(expiry), now) => {
+                    debug!(
+                        "Certificate for {} valid for {} more days",
+                        domain,
+                        expiry.duration_since(now).unwrap_or_default().as_secs() / 86400
+                    );
+                    continue;
+                }
+                Some(expiry) => info!(
+                    "Certificate for {} expires in {} days, renewing",
+                    domain,
+                    expiry.duration_since(now).unwrap_or_default().as_secs() / 86400
+                ),
+                None => info!("No valid certificate for {}, provisioning", domain),
+            }
+
+            let outcome = self.renew(domain, &cert_path).await;
+            if let Some(retry_in) = self.record_outcome(domain, outcome) {
+                next_check = next_check.min(retry_in);
+            }
+        }
+
+        next_check
+    }
+
+    /// Whether a certificate expiring at `expiry` (`None` if missing or unreadable)
+    /// needs renewing at `now`
+    fn is_due(&self, expiry: Option<SystemTime>, now: SystemTime) -> bool {
+        expiry.is_none_or(|expiry| expiry <= now + self.renew_before)
+    }
+
+    async fn renew(&self, domain: &str, cert_path: &Path) -> RenewalOutcome {
+        let mut acme_config = self.acme_config.clone();
+        acme_config.domains = vec![domain.to_string()];
+        // Only replace the default certificate if it is the one for this domain, or if
+        // there is none yet (first start with HTTP-01 provisioning)
+        acme_config.save_default = !acme_config.cert_path.exists()
+            || cert_covers_domains(&acme_config.cert_path, &acme_config.domains);
+
+        match provision_certificates(&acme_config).await {
+            Ok(()) => {
+                if let Some(store) = &self.cert_store {
+                    store.reload();
+                }
+                RenewalOutcome::Renewed(cert_expiry(cert_path).unwrap_or_else(SystemTime::now))
+            }
+            Err(e) => RenewalOutcome::Failed(e.to_string()),
+        }
+    }
+
+    /// Time left before a failed domain may be retried, `None` if it can be checked now
+    fn backoff_remaining(&self, domain: &str, now: SystemTime) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        let domain_state = state.get(domain)?;
+        let retry_in = domain_state.next_retry?.duration_since(now).ok()?;
+
+        debug!(
+            "Skipping {} for {}s after {} failed renewals (last attempt {}s ago: {})",
+            domain,
+            retry_in.as_secs(),
+            domain_state.consecutive_failures,
+            domain_state.last_attempt.elapsed().unwrap_or_default().as_secs(),
+            domain_state.last_outcome
+        );
+        Some(retry_in)
+    }
+
+    /// Store the outcome of a renewal attempt, returning the retry delay if it failed
+    fn record_outcome(&self, domain: &str, outcome: RenewalOutcome) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let consecutive_failures = match (&outcome, state.get(domain)) {
+            (RenewalOutcome::Renewed(_), _) => 0,
+            (RenewalOutcome::Failed(_), Some(previous)) => previous.consecutive_failures + 1,
+            (RenewalOutcome::Failed(_), None) => 1,
+        };
+
+        let retry_in = match &outcome {
+            RenewalOutcome::Renewed(expiry) => {
+                info!(
+                    "Renewed certificate for {}, valid for {} days",
+                    domain,
+                    expiry
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default()
+                        .as_secs()
+                        / 86400
+                );
+                None
+            }
+            RenewalOutcome::Failed(e) => {
+                let delay = self.retry_delay(consecutive_failures);
+                warn!(
+                    "Renewal {} for {} failed, retrying in {}s: {}",
+                    consecutive_failures,
+                    domain,
+                    delay.as_secs(),
+                    e
+                );
+                Some(delay)
+            }
+        };
+
+        let now = SystemTime::now();
+        state.insert(
+            domain.to_string(),
+            RenewalState {
+                last_attempt: now,
+                last_outcome: outcome,
+                consecutive_failures,
+                next_retry: retry_in.map(|delay| now + delay),
+            },
+        );
+
+        retry_in
+    }
+
+    /// Exponential backoff capped at the check interval, with +/-25% jitter so that
+    /// failed domains don't all hit the CA at the same moment
+    fn retry_delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(16);
+        let delay = RETRY_BASE_DELAY
+            .saturating_mul(1 << exponent)
+            .min(self.check_interval);
+        delay.mul_f64(rand::rng().random_range(0.75..1.25))
+    }
+}
+
+#[async_trait]
+impl BackgroundService for RenewalService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        info!(
+            "Certificate renewal enabled for {:?} (renew {} days before expiry)",
+            self.acme_config.domains,
+            self.renew_before.as_secs() / 86400
+        );
+
+        loop {
+            let next_check = self.check_certificates().await;
+
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(next_check) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{date_time_ymd, CertificateParams, KeyPair};
+
+    use super::*;
+    use crate::acme::{AcmeChallenge, Http01Tokens};
+    use crate::dns::PropagationCheck;
+
+    fn service(cert_dir: &Path) -> RenewalService {
+        let acme_config = AcmeConfig {
+            domains: vec!["example.com".to_string(), "example.org".to_string()],
+            challenge: AcmeChallenge::Http01(Arc::new(Http01Tokens::default())),
+            cert_path: cert_dir.join("cert.pem"),
+            key_path: cert_dir.join("key.pem"),
+            directory_url: "https://acme.invalid/directory".to_string(),
+            root_cert: None,
+            eab: None,
+            contact: Vec::new(),
+            propagation: PropagationCheck {
+                resolvers: Vec::new(),
+                timeout: Duration::from_secs(1),
+                fallback_wait: Duration::from_secs(1),
+            },
+            account_path: None,
+            save_default: false,
+            san_order: false,
+        };
+        RenewalService::new(acme_config, Duration::from_secs(30 * 86400), Duration::from_secs(12 * 3600), None)
+    }
+
+    #[test]
+    fn retry_delay_backs_off_with_jitter() {
+        let service = service(Path::new("."));
+        for _ in 0..100 {
+            let first = service.retry_delay(1);
+            assert!(first >= RETRY_BASE_DELAY.mul_f64(0.75) && first <= RETRY_BASE_DELAY.mul_f64(1.25));
+            // Each failure doubles the delay, more than the jitter can make up for
+            for failures in 1..6 {
+                assert!(service.retry_delay(failures + 1) > service.retry_delay(failures).mul_f64(1.2));
+            }
+            // Capped at the check interval, however often it failed
+            for failures in [9, 100, u32::MAX] {
+                let capped = service.retry_delay(failures);
+                assert!(capped >= service.check_interval.mul_f64(0.75), "{:?}", capped);
+                assert!(capped <= service.check_interval.mul_f64(1.25), "{:?}", capped);
+            }
+        }
+    }
+
+    #[test]
+    fn outcomes_are_kept_per_domain() {
+        let service = service(Path::new("."));
+        let now = SystemTime::now();
+        assert!(service.record_outcome("example.com", RenewalOutcome::Failed("rate limited".to_string())).is_some());
+        service.record_outcome("example.com", RenewalOutcome::Failed("rate limited".to_string()));
+        service.record_outcome("example.org", RenewalOutcome::Renewed(now + Duration::from_secs(90 * 86400)));
+
+        assert!(service.backoff_remaining("example.com", now).is_some());
+        assert!(service.backoff_remaining("example.org", now).is_none());
+        {
+            let state = service.state.lock().unwrap();
+            assert_eq!(state["example.com"].consecutive_failures, 2);
+            assert!(matches!(&state["example.com"].last_outcome, RenewalOutcome::Failed(e) if e == "rate limited"));
+            assert_eq!(state["example.org"].consecutive_failures, 0);
+            assert!(matches!(state["example.org"].last_outcome, RenewalOutcome::Renewed(_)));
+            assert_eq!(state["example.org"].last_outcome.to_string(), "renewed, valid for 89 days");
+        }
+
+        // A success ends the backoff
+        service.record_outcome("example.com", RenewalOutcome::Renewed(now + Duration::from_secs(90 * 86400)));
+        assert!(service.backoff_remaining("example.com", now).is_none());
+        assert_eq!(service.state.lock().unwrap()["example.com"].consecutive_failures, 0);
+    }
+
+    #[test]
+    fn renewal_window() {
+        let dir = std::env::temp_dir().join(format!("pingora-renewal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let service = service(&dir);
+        let (cert_path, _) = domain_cert_paths(&dir, "example.com");
+
+        let mut params = CertificateParams::new(vec!["example.com".to_string()]).unwrap();
+        params.not_before = date_time_ymd(2030, 1, 1);
+        params.not_after = date_time_ymd(2030, 4, 1);
+        let cert = params.self_signed(&KeyPair::generate().unwrap()).unwrap();
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+
+        let expiry = cert_expiry(&cert_path).unwrap();
+        let day = Duration::from_secs(86400);
+        assert!(!service.is_due(Some(expiry), expiry - 31 * day));
+        assert!(service.is_due(Some(expiry), expiry - 29 * day));
+        assert!(service.is_due(Some(expiry), expiry + day));
+        // Missing or unreadable certificates are provisioned right away
+        assert!(service.is_due(cert_expiry(&dir.join("missing.pem")), expiry - 60 * day));
+        std::fs::write(&cert_path, "not a certificate").unwrap();
+        assert!(service.is_due(cert_expiry(&cert_path), expiry - 60 * day));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use hickory_proto::rr::{Name, RData, RecordType};
+use log::{debug, info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
+
+use crate::dns::{parse_resolver, query, system_resolvers};
+use crate::proxy::RouteTable;
+
+/// How backend hostnames are resolved and cached
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResolverConfig {
+    /// Optional: DNS servers to query, e.g. "127.0.0.11" (default: from /etc/resolv.conf)
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+    /// Optional: Shortest time addresses are cached, also the retry delay after a
+    /// failed lookup (default: 5)
+    #[serde(default = "default_min_ttl")]
+    pub min_ttl_seconds: u64,
+    /// Optional: Longest time addresses are cached, whatever the record TTL (default: 300)
+    #[serde(default = "default_max_ttl")]
+    pub max_ttl_seconds: u64,
+}
+
+fn default_min_ttl() -> u64 { 5 }
+
+fn default_max_ttl() -> u64 { 300 }
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            nameservers: Vec::new(),
+            min_ttl_seconds: default_min_ttl(),
+            max_ttl_seconds: default_max_ttl(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// Resolves backend hostnames to all their A/AAAA addresses, cached for the record
+/// TTL. Names the DNS servers don't know (e.g. from /etc/hosts or needing a search
+/// domain) fall back to the system resolver. When a lookup fails the previous
+/// addresses are kept.
+#[derive(Debug)]
+pub struct Resolver {
+    nameservers: Vec<SocketAddr>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl Resolver {
+    pub fn new(config: &ResolverConfig) -> anyhow::Result<Self> {
+        let nameservers = if config.nameservers.is_empty() {
+            system_resolvers()
+        } else {
+            config
+                .nameservers
+                .iter()
+                .map(|nameserver| parse_resolver(nameserver))
+                .collect::<anyhow::Result<_>>()?
+        };
+        let min_ttl = Duration::from_secs(config.min_ttl_seconds.max(1));
+
+        Ok(Self {
+            nameservers,
+            min_ttl,
+            max_ttl: Duration::from_secs(config.max_ttl_seconds).max(min_ttl),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Addresses of `host`, from the cache while they are fresh
+    pub async fn resolve(&self, host: &str) -> Vec<IpAddr> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return vec![ip];
+        }
+
+        let previous = {
+            let cache = self.cache.lock().unwrap();
+            match cache.get(host) {
+                Some(entry) if entry.expires > Instant::now() => return entry.addrs.clone(),
+                Some(entry) => Some(entry.addrs.clone()),
+                None => None,
+            }
+        };
+
+        let (addrs, ttl) = match self.lookup(host).await {
+            Ok((addrs, ttl)) => {
+                let ttl = ttl.clamp(self.min_ttl, self.max_ttl);
+                if previous.as_ref() == Some(&addrs) {
+                    debug!("Resolved {} again, unchanged (ttl {}s)", host, ttl.as_secs());
+                } else {
+                    info!("Resolved {} -> {} (ttl {}s)", host, format_addrs(&addrs), ttl.as_secs());
+                }
+                (addrs, ttl)
+            }
+            Err(e) => {
+                let addrs = previous.unwrap_or_default();
+                if addrs.is_empty() {
+                    warn!("Resolving {} failed: {}, retrying in {}s", host, e, self.min_ttl.as_secs());
+                } else {
+                    warn!(
+                        "Resolving {} failed: {}, keeping {} for {}s",
+                        host,
+                        e,
+                        format_addrs(&addrs),
+                        self.min_ttl.as_secs()
+                    );
+                }
+                (addrs, self.min_ttl)
+            }
+        };
+
+        self.cache.lock().unwrap().insert(
+            host.to_string(),
+            CacheEntry {
+                addrs: addrs.clone(),
+                expires: Instant::now() + ttl,
+            },
+        );
+        addrs
+    }
+
+    /// When the next cached entry expires, `None` with nothing cached
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.cache.lock().unwrap().values().map(|entry| entry.expires).min()
+    }
+
+    async fn lookup(&self, host: &str) -> anyhow::Result<(Vec<IpAddr>, Duration)> {
+        match self.query_dns(host).await {
+            Ok(Some(result)) => return Ok(result),
+            Ok(None) => debug!("No A/AAAA records for {}, trying the system resolver", host),
+            Err(e) => debug!("DNS lookup of {} failed: {}, trying the system resolver", host, e),
+        }
+
+        let mut addrs = Vec::new();
+        for addr in tokio::net::lookup_host((host, 0)).await? {
+            if !addrs.contains(&addr.ip()) {
+                addrs.push(addr.ip());
+            }
+        }
+        if addrs.is_empty() {
+            return Err(anyhow::anyhow!("no addresses found"));
+        }
+        addrs.sort();
+        Ok((addrs, self.min_ttl))
+    }
+
+    /// A and AAAA records of `host` with the lowest TTL among them, `None` if there are none
+    async fn query_dns(&self, host: &str) -> anyhow::Result<Option<(Vec<IpAddr>, Duration)>> {
+        let name = Name::from_ascii(host)?;
+        let mut last_error = None;
+
+        for &server in &self.nameservers {
+            let mut addrs = Vec::new();
+            let mut ttl = u32::MAX;
+            let mut failed = false;
+            for record_type in [RecordType::A, RecordType::AAAA] {
+                match query(server, &name, record_type, true).await {
+                    Ok(records) => {
+                        for record in records {
+                            let ip = match record.data() {
+                                RData::A(a) => IpAddr::V4(a.0),
+                                RData::AAAA(aaaa) => IpAddr::V6(aaaa.0),
+                                _ => continue,
+                            };
+                            ttl = ttl.min(record.ttl());
+                            addrs.push(ip);
+                        }
+                    }
+                    Err(e) => {
+                        last_error = Some(e);
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            if failed {
+                continue;
+            }
+
+            if addrs.is_empty() {
+                return Ok(None);
+            }
+            addrs.sort();
+            addrs.dedup();
+            return Ok(Some((addrs, Duration::from_secs(ttl.into()))));
+        }
+
+        match last_error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
+
+fn format_addrs(addrs: &[IpAddr]) -> String {
+    addrs.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// Background service re-resolving backend hostnames as their cached addresses
+/// expire, updating the upstreams of every route
+pub struct ResolverService {
+    routes: Arc<RouteTable>,
+}
+
+impl ResolverService {
+    pub fn new(routes: Arc<RouteTable>) -> Self {
+        Self { routes }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ResolverService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let resolver = self.routes.resolver();
+        loop {
+            self.routes.update_upstreams().await;
+
+            // Wake up when the next address expires, at least every max TTL to pick
+            // up routes added by discovery
+            let now = Instant::now();
+            let wake = resolver
+                .next_expiry()
+                .unwrap_or(now + resolver.max_ttl)
+                .clamp(now + Duration::from_secs(1), now + resolver.max_ttl);
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep_until(wake.into()) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{Message, MessageType, OpCode, ResponseCode};
+    use hickory_proto::rr::rdata::{A, AAAA};
+    use hickory_proto::rr::Record;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    /// Name server answering A/AAAA queries from `records` (name, address, TTL), over
+    /// UDP and TCP on the same port
+    #[derive(Clone)]
+    struct Stub {
+        records: Arc<Vec<(&'static str, IpAddr, u32)>>,
+        /// Send truncated, empty answers over UDP, the full ones only over TCP
+        truncate_udp: bool,
+        /// Answer SERVFAIL while set
+        failing: Arc<AtomicBool>,
+        udp_queries: Arc<AtomicUsize>,
+        tcp_queries: Arc<AtomicUsize>,
+    }
+
+    impl Stub {
+        fn new(records: Vec<(&'static str, &str, u32)>) -> Self {
+            Self {
+                records: Arc::new(records.into_iter().map(|(name, ip, ttl)| (name, ip.parse().unwrap(), ttl)).collect()),
+                truncate_udp: false,
+                failing: Arc::new(AtomicBool::new(false)),
+                udp_queries: Arc::new(AtomicUsize::new(0)),
+                tcp_queries: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        fn answer(&self, request: &[u8], udp: bool) -> Vec<u8> {
+            let request = Message::from_vec(request).unwrap();
+            let query = request.queries()[0].clone();
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(OpCode::Query)
+                .set_response_code(ResponseCode::NoError);
+
+            if self.failing.load(Ordering::SeqCst) {
+                response.set_response_code(ResponseCode::ServFail);
+            } else if udp && self.truncate_udp {
+                response.set_truncated(true);
+            } else {
+                let name = query.name().to_ascii();
+                for (_, ip, ttl) in self.records.iter().filter(|(record, _, _)| format!("{}.", record) == name) {
+                    let data = match (ip, query.query_type()) {
+                        (IpAddr::V4(ip), RecordType::A) => RData::A(A(*ip)),
+                        (IpAddr::V6(ip), RecordType::AAAA) => RData::AAAA(AAAA(*ip)),
+                        _ => continue,
+                    };
+                    response.add_answer(Record::from_rdata(query.name().clone(), *ttl, data));
+                }
+            }
+            response.add_query(query);
+            response.to_vec().unwrap()
+        }
+
+        async fn serve(self) -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let socket = UdpSocket::bind(addr).await.unwrap();
+
+            let udp = self.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 4096];
+                loop {
+                    let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                    udp.udp_queries.fetch_add(1, Ordering::SeqCst);
+                    socket.send_to(&udp.answer(&buf[..len], true), from).await.unwrap();
+                }
+            });
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let tcp = self.clone();
+                    tokio::spawn(async move {
+                        while let Ok(len) = stream.read_u16().await {
+                            let mut request = vec![0u8; len.into()];
+                            stream.read_exact(&mut request).await.unwrap();
+                            tcp.tcp_queries.fetch_add(1, Ordering::SeqCst);
+                            let response = tcp.answer(&request, false);
+                            stream.write_u16(response.len() as u16).await.unwrap();
+                            stream.write_all(&response).await.unwrap();
+                        }
+                    });
+                }
+            });
+            addr
+        }
+    }
+
+    fn resolver(nameserver: SocketAddr, min_ttl_seconds: u64, max_ttl_seconds: u64) -> Resolver {
+        Resolver::new(&ResolverConfig {
+            nameservers: vec![nameserver.to_string()],
+            min_ttl_seconds,
+            max_ttl_seconds,
+        })
+        .unwrap()
+    }
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    /// Make the cached addresses of `host` stale
+    fn expire(resolver: &Resolver, host: &str) {
+        resolver.cache.lock().unwrap().get_mut(host).unwrap().expires = Instant::now() - Duration::from_secs(1);
+    }
+
+    #[tokio::test]
+    async fn a_and_aaaa_records_are_merged() {
+        let stub = Stub::new(vec![
+            ("app.internal", "10.0.0.2", 60),
+            ("app.internal", "2001:db8::1", 30),
+            ("app.internal", "10.0.0.1", 90),
+            ("app.internal", "10.0.0.2", 60),
+            ("other.internal", "10.0.0.9", 60),
+        ]);
+        let resolver = resolver(stub.clone().serve().await, 1, 300);
+
+        let (addrs, ttl) = resolver.query_dns("app.internal").await.unwrap().unwrap();
+        assert_eq!(addrs, ips(&["10.0.0.1", "10.0.0.2", "2001:db8::1"]));
+        // The lowest TTL of all records
+        assert_eq!(ttl, Duration::from_secs(30));
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn addresses_are_cached_for_the_ttl() {
+        let stub = Stub::new(vec![("app.internal", "10.0.0.1", 60)]);
+        let resolver = resolver(stub.clone().serve().await, 1, 300);
+
+        assert_eq!(resolver.resolve("app.internal").await, ips(&["10.0.0.1"]));
+        assert_eq!(resolver.resolve("app.internal").await, ips(&["10.0.0.1"]));
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 2);
+        let expiry = resolver.next_expiry().unwrap() - Instant::now();
+        assert!(expiry > Duration::from_secs(55) && expiry <= Duration::from_secs(60), "{:?}", expiry);
+
+        expire(&resolver, "app.internal");
+        assert_eq!(resolver.resolve("app.internal").await, ips(&["10.0.0.1"]));
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 4);
+
+        // Addresses are returned as is
+        assert_eq!(resolver.resolve("10.1.1.1").await, ips(&["10.1.1.1"]));
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn ttl_is_clamped() {
+        let stub = Stub::new(vec![("long.internal", "10.0.0.1", 86400), ("short.internal", "10.0.0.2", 0)]);
+        let resolver = resolver(stub.serve().await, 10, 100);
+
+        resolver.resolve("long.internal").await;
+        let expiry = resolver.next_expiry().unwrap() - Instant::now();
+        assert!(expiry > Duration::from_secs(95) && expiry <= Duration::from_secs(100), "{:?}", expiry);
+
+        resolver.resolve("short.internal").await;
+        let expiry = resolver.next_expiry().unwrap() - Instant::now();
+        assert!(expiry > Duration::from_secs(5) && expiry <= Duration::from_secs(10), "{:?}", expiry);
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_keep_the_previous_addresses() {
+        let stub = Stub::new(vec![("app.internal", "10.0.0.1", 60)]);
+        let resolver = resolver(stub.clone().serve().await, 10, 300);
+        assert_eq!(resolver.resolve("app.internal").await, ips(&["10.0.0.1"]));
+
+        stub.failing.store(true, Ordering::SeqCst);
+        expire(&resolver, "app.internal");
+        assert_eq!(resolver.resolve("app.internal").await, ips(&["10.0.0.1"]));
+        // Retried after the minimum TTL
+        let expiry = resolver.next_expiry().unwrap() - Instant::now();
+        assert!(expiry <= Duration::from_secs(10), "{:?}", expiry);
+    }
+
+    #[tokio::test]
+    async fn unknown_names_fall_back_to_the_system_resolver() {
+        let stub = Stub::new(Vec::new());
+        let resolver = resolver(stub.clone().serve().await, 1, 300);
+
+        // Not known to the name server, but to /etc/hosts
+        let addrs = resolver.resolve("localhost").await;
+        assert!(!addrs.is_empty());
+        assert!(addrs.iter().all(IpAddr::is_loopback), "{:?}", addrs);
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 2);
+
+        // Also when the name server fails
+        stub.failing.store(true, Ordering::SeqCst);
+        expire(&resolver, "localhost");
+        assert_eq!(resolver.resolve("localhost").await, addrs);
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_are_retried_over_tcp() {
+        let mut stub = Stub::new(vec![("big.internal", "10.0.0.1", 60), ("big.internal", "2001:db8::1", 60)]);
+        stub.truncate_udp = true;
+        let resolver = resolver(stub.clone().serve().await, 1, 300);
+
+        assert_eq!(resolver.resolve("big.internal").await, ips(&["10.0.0.1", "2001:db8::1"]));
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 2);
+        assert_eq!(stub.tcp_queries.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,271 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::RouteSettings;
+
+/// Regex replacement applied to the upstream path, e.g. `^/old/(.*)` -> `/new/$1`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RewriteConfig {
+    pub regex: String,
+    /// Replacement, may refer to capture groups as `$1` or `${name}`
+    pub replacement: String,
+}
+
+/// Strip `prefix` from `path` if the path is the prefix itself or lies below it,
+/// returning the remaining path (empty or starting with '/')
+fn strip_segment_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix.trim_end_matches('/'))?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+/// Prepend `prefix` to a path that is empty or starts with '/'
+fn join_prefix(prefix: &str, path: &str) -> String {
+    non_empty(format!("{}{}", prefix.trim_end_matches('/'), path))
+}
+
+fn non_empty(path: String) -> String {
+    if path.is_empty() { "/".to_string() } else { path }
+}
+
+/// How a route changes request paths between the public URL and the backend:
+/// `strip_prefix`, then `add_prefix`, then the regex `rewrite`
+#[derive(Debug)]
+pub struct PathRewrite {
+    strip_prefix: Option<String>,
+    add_prefix: Option<String>,
+    regex: Option<(Regex, String)>,
+    rewrite_location: bool,
+    rewrite_cookie_path: bool,
+}
+
+impl PathRewrite {
+    /// The rewrite configured for a route, `None` if paths are forwarded unchanged
+    pub fn from_settings(settings: &RouteSettings) -> anyhow::Result<Option<Self>> {
+        if settings.strip_prefix.is_none() && settings.add_prefix.is_none() && settings.rewrite.is_none() {
+            return Ok(None);
+        }
+
+        let regex = match &settings.rewrite {
+            Some(rewrite) => Some((Regex::new(&rewrite.regex)?, rewrite.replacement.clone())),
+            None => None,
+        };
+
+        Ok(Some(PathRewrite {
+            strip_prefix: settings.strip_prefix.clone(),
+            add_prefix: settings.add_prefix.clone(),
+            regex,
+            rewrite_location: settings.rewrite_location,
+            rewrite_cookie_path: settings.rewrite_cookie_path,
+        }))
+    }
+
+    /// Path to request from the backend for a public request path
+    pub fn upstream_path(&self, path: &str) -> String {
+        let mut path = match &self.strip_prefix {
+            Some(prefix) => strip_segment_prefix(path, prefix).unwrap_or(path).to_string(),
+            None => path.to_string(),
+        };
+        path = match &self.add_prefix {
+            Some(prefix) => join_prefix(prefix, &path),
+            None => non_empty(path),
+        };
+        if let Some((regex, replacement)) = &self.regex {
+            path = regex.replace(&path, replacement.as_str()).into_owned();
+        }
+        path
+    }
+
+    /// Path and query to request from the backend, the query string is kept as is
+    pub fn upstream_path_and_query(&self, path: &str, query: Option<&str>) -> String {
+        let path = self.upstream_path(path);
+        match query {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        }
+    }
+
+    /// Public path for a path used by the backend, by undoing the prefix changes.
+    /// `None` if the path is outside the backend's prefix or a regex rewrite is used,
+    /// which can't be reversed.
+    pub fn public_path(&self, upstream_path: &str) -> Option<String> {
+        if self.regex.is_some() {
+            return None;
+        }
+
+        let path = match &self.add_prefix {
+            Some(prefix) => strip_segment_prefix(upstream_path, prefix)?,
+            None => upstream_path,
+        };
+        Some(match &self.strip_prefix {
+            Some(prefix) => join_prefix(prefix, path),
+            None => non_empty(path.to_string()),
+        })
+    }
+
+    /// Rewrite a `Location` header pointing at the backend's paths, either path-absolute
+    /// or an absolute URL on the requested host. `None` leaves the header unchanged.
+    pub fn rewrite_location(&self, location: &str, host: &str) -> Option<String> {
+        if !self.rewrite_location {
+            return None;
+        }
+
+        // Split into origin ("https://host", empty for path-absolute) and path with query
+        let (origin, path_and_query) = if location.starts_with('/') && !location.starts_with("//") {
+            ("", location)
+        } else {
+            let (scheme, rest) = location.split_once("://")?;
+            let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+            let authority = &rest[..authority_end];
+            let authority_host = authority.rsplit_once(':').map_or(authority, |(host, _)| host);
+            if !authority_host.eq_ignore_ascii_case(host) {
+                return None;
+            }
+            (&location[..scheme.len() + 3 + authority_end], &rest[authority_end..])
+        };
+
+        let path_end = path_and_query.find(['?', '#']).unwrap_or(path_and_query.len());
+        let path = match &path_and_query[..path_end] {
+            "" => "/",
+            path => path,
+        };
+        let public = self.public_path(path)?;
+        Some(format!("{}{}{}", origin, public, &path_and_query[path_end..]))
+    }
+
+    /// Rewrite the `Path` attribute of a `Set-Cookie` header. `None` leaves it unchanged.
+    pub fn rewrite_cookie_path(&self, set_cookie: &str) -> Option<String> {
+        if !self.rewrite_cookie_path {
+            return None;
+        }
+
+        let mut changed = false;
+        let attributes = set_cookie
+            .split(';')
+            .enumerate()
+            .map(|(index, attribute)| {
+                // The first part is the cookie's own name=value, e.g. "path=/admin"
+                let trimmed = attribute.trim_start();
+                if index > 0
+                    && trimmed.get(..5).is_some_and(|name| name.eq_ignore_ascii_case("path="))
+                    && let Some(public) = self.public_path(trimmed[5..].trim())
+                {
+                    changed = true;
+                    return format!(" Path={}", public);
+                }
+                attribute.to_string()
+            })
+            .collect::<Vec<_>>();
+
+        changed.then(|| attributes.join(";"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite() -> PathRewrite {
+        PathRewrite {
+            strip_prefix: Some("/app".to_string()),
+            add_prefix: Some("/internal".to_string()),
+            regex: None,
+            rewrite_location: true,
+            rewrite_cookie_path: true,
+        }
+    }
+
+    fn path_rewrite(strip_prefix: Option<&str>, add_prefix: Option<&str>, regex: Option<(&str, &str)>) -> PathRewrite {
+        PathRewrite {
+            strip_prefix: strip_prefix.map(str::to_string),
+            add_prefix: add_prefix.map(str::to_string),
+            regex: regex.map(|(regex, replacement)| (Regex::new(regex).unwrap(), replacement.to_string())),
+            rewrite_location: true,
+            rewrite_cookie_path: true,
+        }
+    }
+
+    #[test]
+    fn prefixes_are_stripped_and_added() {
+        let strip = path_rewrite(Some("/app/"), None, None);
+        assert_eq!(strip.upstream_path("/app/users"), "/users");
+        assert_eq!(strip.upstream_path("/app"), "/");
+        assert_eq!(strip.upstream_path("/app/"), "/");
+        // Only whole segments are stripped
+        assert_eq!(strip.upstream_path("/application"), "/application");
+        assert_eq!(strip.upstream_path("/other"), "/other");
+
+        let add = path_rewrite(None, Some("/v2"), None);
+        assert_eq!(add.upstream_path("/users"), "/v2/users");
+        assert_eq!(add.upstream_path("/"), "/v2/");
+
+        let replace = rewrite();
+        assert_eq!(replace.upstream_path("/app/users/1"), "/internal/users/1");
+        assert_eq!(replace.upstream_path("/app"), "/internal");
+        assert_eq!(replace.upstream_path("/elsewhere"), "/internal/elsewhere");
+
+        // Stripping down to "/" before adding to it
+        let root = path_rewrite(Some("/"), Some("/"), None);
+        assert_eq!(root.upstream_path("/"), "/");
+        assert_eq!(root.upstream_path("/users"), "/users");
+    }
+
+    #[test]
+    fn regex_runs_after_the_prefixes() {
+        let regex = path_rewrite(Some("/app"), None, Some(("^/old/(?<rest>.*)", "/new/${rest}")));
+        assert_eq!(regex.upstream_path("/app/old/page"), "/new/page");
+        assert_eq!(regex.upstream_path("/app/current"), "/current");
+        assert_eq!(regex.public_path("/new/page"), None);
+        assert_eq!(regex.rewrite_location("/new/page", "example.com"), None);
+    }
+
+    #[test]
+    fn query_is_kept() {
+        assert_eq!(rewrite().upstream_path_and_query("/app/search", Some("q=a/b&page=2")), "/internal/search?q=a/b&page=2");
+        assert_eq!(rewrite().upstream_path_and_query("/app", Some("")), "/internal?");
+        assert_eq!(rewrite().upstream_path_and_query("/app/search", None), "/internal/search");
+    }
+
+    #[test]
+    fn locations_are_rewritten() {
+        let rewrite = rewrite();
+        assert_eq!(rewrite.rewrite_location("/internal/login?next=/internal/x#top", "example.com").as_deref(), Some("/app/login?next=/internal/x#top"));
+        assert_eq!(rewrite.rewrite_location("/internal", "example.com").as_deref(), Some("/app"));
+        assert_eq!(rewrite.rewrite_location("https://example.com/internal/a?b=c", "example.com").as_deref(), Some("https://example.com/app/a?b=c"));
+        assert_eq!(rewrite.rewrite_location("http://EXAMPLE.com:8080/internal/", "example.com").as_deref(), Some("http://EXAMPLE.com:8080/app/"));
+        assert_eq!(rewrite.rewrite_location("https://example.com/internal?x=1", "example.com").as_deref(), Some("https://example.com/app?x=1"));
+        // Outside the backend's prefix
+        assert_eq!(rewrite.rewrite_location("/elsewhere", "example.com"), None);
+        assert_eq!(rewrite.rewrite_location("https://example.com", "example.com"), None);
+    }
+
+    #[test]
+    fn foreign_locations_are_left_alone() {
+        let rewrite = rewrite();
+        assert_eq!(rewrite.rewrite_location("https://other.com/internal/a", "example.com"), None);
+        assert_eq!(rewrite.rewrite_location("https://example.com.evil.com/internal/a", "example.com"), None);
+        assert_eq!(rewrite.rewrite_location("//example.com/internal/a", "example.com"), None);
+        assert_eq!(rewrite.rewrite_location("internal/a", "example.com"), None);
+        assert_eq!(rewrite.rewrite_location("mailto:admin@example.com", "example.com"), None);
+
+        let disabled = PathRewrite { rewrite_location: false, ..rewrite };
+        assert_eq!(disabled.rewrite_location("/internal/a", "example.com"), None);
+    }
+
+    #[test]
+    fn cookie_path_attribute_is_rewritten() {
+        assert_eq!(
+            rewrite().rewrite_cookie_path("session=abc; Path=/internal/admin; HttpOnly").as_deref(),
+            Some("session=abc; Path=/app/admin; HttpOnly")
+        );
+        assert_eq!(rewrite().rewrite_cookie_path("session=abc; Path=/elsewhere"), None);
+    }
+
+    #[test]
+    fn cookie_named_path_is_left_alone() {
+        assert_eq!(rewrite().rewrite_cookie_path("path=/internal/x; Secure"), None);
+        assert_eq!(
+            rewrite().rewrite_cookie_path("path=/internal/x; path=/internal").as_deref(),
+            Some("path=/internal/x; Path=/app")
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::balancer::LoadBalancing;
+use crate::proxy::{default_retries, PathMatcher, Route, RouteSettings};
+
+/// Weighted traffic split between named backends, e.g. a canary release
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SplitConfig {
+    /// The backends to split between, their percentages adding up to 100
+    pub variants: Vec<VariantConfig>,
+    /// Optional: What keeps a client on its variant, "cookie" or "client_ip" (default: "cookie")
+    #[serde(default)]
+    pub sticky: Stickiness,
+    /// Optional: Cookie remembering the variant of a client (default: "pingora_variant",
+    /// with a suffix derived from the path for routes other than the domain's own)
+    pub cookie: Option<String>,
+    /// Optional: Seconds the variant cookie is kept by the browser (default: 86400)
+    #[serde(default = "default_cookie_max_age")]
+    pub cookie_max_age_seconds: u64,
+    /// Optional: Request header naming the variant for the backend (default: "X-Variant")
+    #[serde(default = "default_header")]
+    pub header: String,
+}
+
+fn default_cookie_max_age() -> u64 { 86400 }
+
+fn default_header() -> String { "X-Variant".to_string() }
+
+/// A named backend receiving a share of the traffic
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VariantConfig {
+    /// Name sent in the variant header and cookie (e.g., "stable", "canary")
+    pub name: String,
+    /// Share of new clients sent to this variant
+    pub percent: u32,
+    #[serde(flatten)]
+    pub settings: RouteSettings,
+}
+
+/// How a client keeps being sent to the same variant
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stickiness {
+    /// New clients get a random variant, remembered in a cookie
+    #[default]
+    Cookie,
+    /// The client IP is hashed to a variant
+    ClientIp,
+}
+
+#[derive(Debug)]
+pub struct Variant {
+    pub name: String,
+    percent: u32,
+    pub route: Arc<Route>,
+}
+
+/// The variant chosen for a request
+#[derive(Debug)]
+pub struct SplitDecision {
+    /// Request header naming the variant for the backend
+    pub header: String,
+    pub variant: String,
+    /// `Set-Cookie` value pinning a new client to its variant
+    pub cookie: Option<String>,
+}
+
+/// Compiled traffic split of a route, each variant with a route of its own
+#[derive(Debug)]
+pub struct Split {
+    pub variants: Vec<Variant>,
+    sticky: Stickiness,
+    cookie: String,
+    cookie_path: String,
+    cookie_max_age_seconds: u64,
+    header: String,
+}
+
+/// Variant names end up in a cookie and a header, keep them to token characters
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// FNV-1a, stable across builds so clients keep their variant after an upgrade
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Settings of a split route that only its variants can use
+fn variant_only_settings(settings: &RouteSettings) -> Vec<&'static str> {
+    [
+        ("host", !settings.host.is_empty()),
+        ("port", settings.port != 0),
+        ("upstreams", !settings.upstreams.is_empty()),
+        ("load_balancing", settings.load_balancing != LoadBalancing::default()),
+        ("hash_key", settings.hash_key.is_some()),
+        ("sticky_session", settings.sticky_session.is_some()),
+        ("health_check", settings.health_check.is_some()),
+        ("retries", settings.retries != default_retries()),
+        ("circuit_breaker", settings.circuit_breaker.is_some()),
+        ("tls", settings.tls),
+        ("sni", settings.sni.is_some()),
+        ("send_proxy_protocol", settings.send_proxy_protocol.is_some()),
+        ("strip_prefix", settings.strip_prefix.is_some()),
+        ("add_prefix", settings.add_prefix.is_some()),
+        ("rewrite", settings.rewrite.is_some()),
+        ("rewrite_location", settings.rewrite_location),
+        ("rewrite_cookie_path", settings.rewrite_cookie_path),
+    ]
+    .into_iter()
+    .filter_map(|(name, set)| set.then_some(name))
+    .collect()
+}
+
+impl Split {
+    /// Compile the split of the route with `settings` and `matcher`, building each
+    /// variant's route with `route`
+    pub fn new<F>(
+        config: &SplitConfig,
+        settings: &RouteSettings,
+        matcher: &PathMatcher,
+        route: F,
+    ) -> anyhow::Result<Self>
+    where
+        F: Fn(&RouteSettings) -> anyhow::Result<Route>,
+    {
+        let ignored = variant_only_settings(settings);
+        if !ignored.is_empty() {
+            return Err(anyhow::anyhow!(
+                "A split backend can't set {}, set them on its variants",
+                ignored.join(", ")
+            ));
+        }
+        if config.variants.is_empty() {
+            return Err(anyhow::anyhow!("Split needs at least one variant"));
+        }
+        if let Some(variant) = config.variants.iter().find(|variant| variant.percent > 100) {
+            return Err(anyhow::anyhow!("Variant {} has more than 100 percent", variant.name));
+        }
+        let total = config.variants.iter().map(|variant| variant.percent).sum::<u32>();
+        if total != 100 {
+            return Err(anyhow::anyhow!("Split percentages add up to {}, not 100", total));
+        }
+
+        let mut variants = Vec::<Variant>::new();
+        for variant in &config.variants {
+            if !valid_name(&variant.name) {
+                return Err(anyhow::anyhow!(
+                    "Invalid variant name {:?}, use letters, digits, '-', '_' and '.'",
+                    variant.name
+                ));
+            }
+            if variants.iter().any(|other| other.name == variant.name) {
+                return Err(anyhow::anyhow!("Duplicate variant name {:?}", variant.name));
+            }
+            if variant.settings.split.is_some() {
+                return Err(anyhow::anyhow!("Variant {} can't be split again", variant.name));
+            }
+            if variant.settings.auth.is_some()
+                || variant.settings.forward_auth.is_some()
+                || !variant.settings.require_claims.is_empty()
+                || !variant.settings.require_scopes.is_empty()
+            {
+                return Err(anyhow::anyhow!("Variant {} can't have auth, set it next to the split", variant.name));
+            }
+            let route = route(&variant.settings)
+                .map_err(|e| anyhow::anyhow!("Invalid variant {}: {}", variant.name, e))?;
+            variants.push(Variant {
+                name: variant.name.clone(),
+                percent: variant.percent,
+                route: Arc::new(route),
+            });
+        }
+
+        Ok(Split {
+            variants,
+            sticky: config.sticky,
+            cookie: config
+                .cookie
+                .clone()
+                .unwrap_or_else(|| matcher.cookie_name("pingora_variant")),
+            cookie_path: matcher.cookie_path().to_string(),
+            cookie_max_age_seconds: config.cookie_max_age_seconds,
+            header: config.header.clone(),
+        })
+    }
+
+    /// Name of the cookie read by [`Split::choose`], `None` unless sticky by cookie
+    pub fn cookie_name(&self) -> Option<&str> {
+        (self.sticky == Stickiness::Cookie).then_some(self.cookie.as_str())
+    }
+
+    /// Pick the variant for a request. A cookie naming a variant that still gets
+    /// traffic is honoured, otherwise the client IP hash or a random draw decides.
+    pub fn choose(&self, cookie: Option<&str>, client_ip: Option<IpAddr>) -> (&Variant, SplitDecision) {
+        let pinned = cookie.and_then(|name| {
+            self.variants
+                .iter()
+                .find(|variant| variant.name == name && variant.percent > 0)
+        });
+        if let Some(variant) = pinned {
+            return (variant, self.decision(variant, false));
+        }
+
+        let bucket = match (self.sticky, client_ip) {
+            (Stickiness::ClientIp, Some(ip)) => (fnv1a(ip.to_string().as_bytes()) % 100) as u32,
+            _ => rand::rng().random_range(0..100),
+        };
+        // Buckets are handed out in configuration order, so raising the share of
+        // the last variant keeps the clients it already has
+        let mut end = 0;
+        let variant = self
+            .variants
+            .iter()
+            .find(|variant| {
+                end += variant.percent;
+                bucket < end
+            })
+            .expect("percentages add up to 100");
+        (variant, self.decision(variant, self.sticky == Stickiness::Cookie))
+    }
+
+    fn decision(&self, variant: &Variant, set_cookie: bool) -> SplitDecision {
+        SplitDecision {
+            header: self.header.clone(),
+            variant: variant.name.clone(),
+            cookie: set_cookie.then(|| {
+                format!(
+                    "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax",
+                    self.cookie, variant.name, self.cookie_path, self.cookie_max_age_seconds
+                )
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::proxy::{ProxyConfig, RouteTable};
+    use crate::resolver::Resolver;
+
+    fn split(config: serde_json::Value) -> SplitConfig {
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn settings(config: serde_json::Value) -> RouteSettings {
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn compile(split_config: &SplitConfig, settings: &RouteSettings) -> anyhow::Result<Split> {
+        Split::new(split_config, settings, &PathMatcher::Any, |_| {
+            Err(anyhow::anyhow!("variant routes are not built here"))
+        })
+    }
+
+    #[test]
+    fn percent_above_100_is_rejected() {
+        let config = split(json!({ "variants": [
+            { "name": "a", "percent": u32::MAX, "host": "a", "port": 80 },
+            { "name": "b", "percent": 1, "host": "b", "port": 80 }
+        ] }));
+        let error = compile(&config, &settings(json!({}))).unwrap_err();
+        assert!(error.to_string().contains("more than 100 percent"), "{}", error);
+    }
+
+    #[test]
+    fn backend_settings_next_to_split_are_rejected() {
+        let config = split(json!({ "variants": [
+            { "name": "a", "percent": 100, "host": "a", "port": 80 }
+        ] }));
+        let error = compile(
+            &config,
+            &settings(json!({ "strip_prefix": "/app", "health_check": {}, "retries": 3 })),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "A split backend can't set health_check, retries, strip_prefix, set them on its variants"
+        );
+    }
+
+    #[test]
+    fn variant_cookie_belongs_to_its_route() {
+        let config: ProxyConfig = serde_json::from_value(json!({
+            "listen_addr": "127.0.0.1:0",
+            "domains": { "app.example.com": {
+                "split": { "variants": [{ "name": "stable", "percent": 100, "host": "127.0.0.1", "port": 8000 }] },
+                "routes": [{
+                    "path_prefix": "/api",
+                    "split": { "variants": [{ "name": "stable", "percent": 100, "host": "127.0.0.1", "port": 8001 }] }
+                }]
+            } }
+        }))
+        .unwrap();
+        let resolver = Arc::new(Resolver::new(&config.resolver).unwrap());
+        let routes = RouteTable::new(&config, resolver).unwrap();
+        let cookie = |path: &str| {
+            let route = routes.find_backend("app.example.com", path).unwrap();
+            route.split.as_ref().unwrap().choose(None, None).1.cookie.unwrap()
+        };
+
+        let domain = cookie("/");
+        let api = cookie("/api/items");
+        assert!(domain.starts_with("pingora_variant=stable; Path=/;"), "{}", domain);
+        assert!(api.starts_with("pingora_variant_"), "{}", api);
+        assert!(api.contains("; Path=/api;"), "{}", api);
+    }
+}
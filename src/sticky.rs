@@ -0,0 +1,138 @@
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+
+use base64::Engine;
+use ring::hmac;
+use ring::rand::SystemRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::PathMatcher;
+
+/// Key of every sticky route without a `secret`, generated once per process so routes
+/// compiled again (e.g. by Docker discovery) keep accepting the cookies they issued
+static RANDOM_KEY: OnceLock<hmac::Key> = OnceLock::new();
+
+/// Cookie based session affinity to one upstream of a backend
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StickySessionConfig {
+    /// Optional: Name of the affinity cookie (default: "pingora_upstream", with a suffix
+    /// derived from the path for routes other than the domain's own)
+    pub cookie: Option<String>,
+    /// Optional: Key signing the cookie, keep it stable to keep sessions across
+    /// restarts (default: random at startup)
+    pub secret: Option<String>,
+    /// Optional: Seconds the browser keeps the cookie (default: until the browser closes)
+    pub max_age_seconds: Option<u64>,
+}
+
+/// Issues and checks affinity cookies. The cookie holds an HMAC of the upstream
+/// address, so it doesn't reveal the address and can't be forged to pick one.
+#[derive(Debug)]
+pub struct StickySessions {
+    pub cookie: String,
+    /// `Path` of the cookie, limiting it to the route's requests
+    path: String,
+    key: hmac::Key,
+    max_age_seconds: Option<u64>,
+}
+
+impl StickySessions {
+    /// Affinity cookies for the route selected by `matcher`
+    pub fn new(config: &StickySessionConfig, matcher: &PathMatcher) -> anyhow::Result<Self> {
+        let key = match &config.secret {
+            Some(secret) => hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            None => match RANDOM_KEY.get() {
+                Some(key) => key.clone(),
+                None => {
+                    let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                        .map_err(|_| anyhow::anyhow!("Failed to generate sticky session key"))?;
+                    RANDOM_KEY.get_or_init(|| key).clone()
+                }
+            },
+        };
+        Ok(Self {
+            cookie: config
+                .cookie
+                .clone()
+                .unwrap_or_else(|| matcher.cookie_name("pingora_upstream")),
+            path: matcher.cookie_path().to_string(),
+            key,
+            max_age_seconds: config.max_age_seconds,
+        })
+    }
+
+    fn value(&self, upstream: &SocketAddr) -> String {
+        let tag = hmac::sign(&self.key, upstream.to_string().as_bytes());
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(tag.as_ref())
+    }
+
+    /// Whether the cookie value was issued for `upstream`
+    pub fn matches(&self, value: &str, upstream: &SocketAddr) -> bool {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(value)
+            .is_ok_and(|tag| hmac::verify(&self.key, upstream.to_string().as_bytes(), &tag).is_ok())
+    }
+
+    /// `Set-Cookie` value pinning the client to `upstream`
+    pub fn set_cookie(&self, upstream: &SocketAddr) -> String {
+        let mut cookie = format!(
+            "{}={}; Path={}; HttpOnly; SameSite=Lax",
+            self.cookie,
+            self.value(upstream),
+            self.path
+        );
+        if let Some(max_age) = self.max_age_seconds {
+            cookie.push_str(&format!("; Max-Age={}", max_age));
+        }
+        cookie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(cookie: Option<&str>, secret: Option<&str>) -> StickySessionConfig {
+        StickySessionConfig {
+            cookie: cookie.map(str::to_string),
+            secret: secret.map(str::to_string),
+            max_age_seconds: None,
+        }
+    }
+
+    fn value(set_cookie: &str) -> &str {
+        set_cookie.split(';').next().unwrap().split_once('=').unwrap().1
+    }
+
+    #[test]
+    fn random_key_survives_recompiling() {
+        let upstream: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let first = StickySessions::new(&config(None, None), &PathMatcher::Any).unwrap();
+        let again = StickySessions::new(&config(None, None), &PathMatcher::Any).unwrap();
+
+        let cookie = first.set_cookie(&upstream);
+        assert!(again.matches(value(&cookie), &upstream));
+        assert!(!again.matches(value(&cookie), &"10.0.0.2:8080".parse().unwrap()));
+
+        let signed = StickySessions::new(&config(None, Some("secret")), &PathMatcher::Any).unwrap();
+        assert!(!signed.matches(value(&cookie), &upstream));
+    }
+
+    #[test]
+    fn cookie_is_scoped_to_its_route() {
+        let upstream: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let domain = StickySessions::new(&config(None, None), &PathMatcher::Any).unwrap();
+        let api = StickySessions::new(&config(None, None), &PathMatcher::Prefix("/api".to_string())).unwrap();
+        let admin = StickySessions::new(&config(None, None), &PathMatcher::Prefix("/admin".to_string())).unwrap();
+
+        assert_eq!(domain.cookie, "pingora_upstream");
+        assert!(domain.set_cookie(&upstream).contains("; Path=/;"));
+        assert!(api.cookie.starts_with("pingora_upstream_"));
+        assert_ne!(api.cookie, admin.cookie);
+        assert!(api.set_cookie(&upstream).contains("; Path=/api;"));
+
+        let named = StickySessions::new(&config(Some("app_node"), None), &PathMatcher::Prefix("/api".to_string()))
+            .unwrap();
+        assert_eq!(named.cookie, "app_node");
+    }
+}
@@ -0,0 +1,755 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use pingora::apps::ServerApp;
+use pingora::protocols::tls::SslDigest;
+use pingora::protocols::{
+    GetProxyDigest, GetSocketDigest, GetTimingDigest, Peek, Shutdown, SocketDigest, Ssl, Stream,
+    TimingDigest, UniqueID, UniqueIDType, ALPN,
+};
+use pingora::protocols::raw_connect::ProxyDigest;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::MissedTickBehavior;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::acme::{domain_cert_paths, TlsAlpn01Certs};
+use crate::proxy::ProxyConfig;
+
+/// ALPN protocol used by ACME servers for TLS-ALPN-01 validation (RFC 8737)
+const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+/// Load a PEM certificate chain and its private key, checking that the key matches
+/// the certificate and that the certificate can be parsed
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> anyhow::Result<CertifiedKey> {
+    let mut cert_reader = BufReader::new(File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No certificates found in {}", cert_path.display()));
+    }
+
+    let mut key_reader = BufReader::new(File::open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut key_reader)?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", key_path.display()))?;
+
+    let provider = rustls::crypto::CryptoProvider::get_default()
+        .ok_or_else(|| anyhow::anyhow!("No rustls crypto provider installed"))?;
+
+    // from_der also verifies that the private key belongs to the certificate
+    let certified_key = CertifiedKey::from_der(certs, key, provider)?;
+
+    let (_, cert) = x509_parser::parse_x509_certificate(certified_key.end_entity_cert()?)
+        .map_err(|e| anyhow::anyhow!("Invalid certificate {}: {}", cert_path.display(), e))?;
+    debug!(
+        "Certificate {} valid until {}",
+        cert_path.display(),
+        cert.validity().not_after
+    );
+
+    Ok(certified_key)
+}
+
+/// Where a certificate in the store is loaded from
+#[derive(Debug)]
+struct CertSource {
+    /// Domain the certificate is served for, `None` for the default certificate
+    domain: Option<String>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    /// Modification times of the cert and key files when they were last loaded
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl CertSource {
+    fn new(domain: Option<&str>, cert_path: PathBuf, key_path: PathBuf) -> Self {
+        Self {
+            domain: domain.map(|d| d.to_lowercase()),
+            cert_path,
+            key_path,
+            modified: Mutex::new(None),
+        }
+    }
+
+    /// Modification times of the cert and key files, `None` if either is missing
+    fn file_times(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = std::fs::metadata(&self.cert_path).and_then(|m| m.modified()).ok()?;
+        let key = std::fs::metadata(&self.key_path).and_then(|m| m.modified()).ok()?;
+        Some((cert, key))
+    }
+
+    fn name(&self) -> &str {
+        self.domain.as_deref().unwrap_or("default certificate")
+    }
+}
+
+/// Certificates currently served by the store
+#[derive(Debug, Default)]
+struct LoadedCerts {
+    certs: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+/// Per-domain certificates for the TLS listener, selected by SNI.
+///
+/// Certificates are reloaded from disk by [`CertStore::reload`] whenever their files
+/// change; new handshakes pick up the new pair while a broken pair is ignored.
+#[derive(Debug)]
+pub struct CertStore {
+    sources: Vec<CertSource>,
+    loaded: RwLock<LoadedCerts>,
+    /// Pending TLS-ALPN-01 challenges, when that challenge type is in use
+    acme_certs: Option<Arc<TlsAlpn01Certs>>,
+}
+
+impl CertStore {
+    /// Build the store from the TLS config: the default `cert_path`/`key_path`,
+    /// explicit per-domain certificates and the per-domain files written by ACME.
+    /// `acme_certs` enables answering `acme-tls/1` handshakes with challenge certificates.
+    pub fn from_config(
+        config: &ProxyConfig,
+        acme_certs: Option<Arc<TlsAlpn01Certs>>,
+    ) -> anyhow::Result<Self> {
+        let tls_config = config
+            .tls
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No TLS configuration"))?;
+
+        let default_cert = PathBuf::from(&tls_config.cert_path);
+        let cert_dir = default_cert.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let mut sources = vec![CertSource::new(
+            None,
+            default_cert,
+            PathBuf::from(&tls_config.key_path),
+        )];
+
+        for (domain, backend) in &config.domains {
+            let (cert_path, key_path) = match (&backend.cert_path, &backend.key_path) {
+                (Some(cert), Some(key)) => (PathBuf::from(cert), PathBuf::from(key)),
+                // ACME files may not exist yet, they are picked up once provisioned
+                _ => domain_cert_paths(&cert_dir, domain),
+            };
+            sources.push(CertSource::new(Some(domain), cert_path, key_path));
+        }
+
+        let store = CertStore {
+            sources,
+            loaded: RwLock::new(LoadedCerts::default()),
+            acme_certs,
+        };
+        store.reload();
+
+        // Not fatal: certificates provisioned while the proxy runs are picked up on reload
+        if store.loaded.read().unwrap().default.is_none() {
+            warn!(
+                "No default certificate at {}, TLS handshakes for domains without a certificate fail until one is provisioned",
+                tls_config.cert_path
+            );
+        }
+
+        Ok(store)
+    }
+
+    /// Load every certificate whose files changed since the last load. A pair that
+    /// fails validation is skipped and the previously loaded pair stays in use.
+    pub fn reload(&self) {
+        for source in &self.sources {
+            let Some(times) = source.file_times() else {
+                continue;
+            };
+
+            let mut modified = source.modified.lock().unwrap();
+            if *modified == Some(times) {
+                continue;
+            }
+            // Remember the attempt even if it fails, so a broken pair is only reported once
+            *modified = Some(times);
+
+            match load_certified_key(&source.cert_path, &source.key_path) {
+                Ok(key) => {
+                    info!(
+                        "Loaded certificate for {} from {}",
+                        source.name(),
+                        source.cert_path.display()
+                    );
+                    let mut loaded = self.loaded.write().unwrap();
+                    match &source.domain {
+                        Some(domain) => {
+                            loaded.certs.insert(domain.clone(), Arc::new(key));
+                        }
+                        None => loaded.default = Some(Arc::new(key)),
+                    }
+                }
+                Err(e) => warn!(
+                    "Failed to load certificate for {} from {}, keeping the current one: {}",
+                    source.name(),
+                    source.cert_path.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Find the certificate for a server name: exact match, then wildcard, then the default
+    pub fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.read().unwrap();
+
+        if let Some(name) = server_name {
+            let name = name.to_lowercase();
+            if let Some(key) = loaded.certs.get(&name) {
+                return Some(key.clone());
+            }
+
+            // A wildcard certificate covers exactly one label
+            if let Some((_, parent)) = name.split_once('.')
+                && let Some(key) = loaded.certs.get(&format!("*.{}", parent))
+            {
+                return Some(key.clone());
+            }
+        }
+
+        loaded.default.clone()
+    }
+}
+
+/// Background service that reloads the [`CertStore`] when certificate files change
+/// (polled every `interval`) or when the process receives SIGHUP
+pub struct CertReloadService {
+    store: Arc<CertStore>,
+    interval: Duration,
+}
+
+impl CertReloadService {
+    pub fn new(store: Arc<CertStore>, interval: Duration) -> Self {
+        Self { store, interval }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for CertReloadService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut sighup = signal(SignalKind::hangup())
+            .map_err(|e| warn!("Cannot listen for SIGHUP, certificates reload on change only: {}", e))
+            .ok();
+
+        // A zero interval disables polling, leaving SIGHUP as the only trigger
+        let mut poll = (!self.interval.is_zero()).then(|| {
+            let mut interval = tokio::time::interval(self.interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        if sighup.is_none() && poll.is_none() {
+            warn!("Certificate reloading disabled: no SIGHUP and cert_reload_seconds is 0");
+            return;
+        }
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = async {
+                    match sighup.as_mut() {
+                        Some(sighup) => {
+                            sighup.recv().await;
+                        }
+                        None => std::future::pending().await,
+                    }
+                } => info!("SIGHUP received, reloading certificates"),
+                _ = async {
+                    match poll.as_mut() {
+                        Some(interval) => {
+                            interval.tick().await;
+                        }
+                        None => std::future::pending().await,
+                    }
+                } => {}
+            }
+
+            self.store.reload();
+        }
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let server_name = client_hello.server_name();
+
+        // TLS-ALPN-01 validation only accepts the challenge certificate, never a regular one
+        if let Some(acme_certs) = &self.acme_certs
+            && client_hello
+                .alpn()
+                .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN_PROTOCOL))
+        {
+            debug!("Resolving TLS-ALPN-01 challenge certificate for {:?}", server_name);
+            return server_name.and_then(|name| acme_certs.get(name));
+        }
+
+        debug!("Resolving certificate for SNI {:?}", server_name);
+        self.lookup(server_name)
+    }
+}
+
+/// Terminates TLS with certificates from a [`CertStore`] and hands the decrypted
+/// connection to the wrapped application (normally the HTTP proxy)
+pub struct TlsProxyApp<A> {
+    app: Arc<A>,
+    acceptor: TlsAcceptor,
+}
+
+/// rustls configuration resolving certificates from `store`, with the ALPN protocols
+/// the listener offers
+fn server_config(store: Arc<CertStore>, enable_h2: bool) -> ServerConfig {
+    let acme = store.acme_certs.is_some();
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(store);
+
+    if enable_h2 {
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    }
+    if acme {
+        // Only ever negotiated when the client offers nothing else, like ACME validators
+        if config.alpn_protocols.is_empty() {
+            config.alpn_protocols.push(b"http/1.1".to_vec());
+        }
+        config.alpn_protocols.push(ACME_TLS_ALPN_PROTOCOL.to_vec());
+    }
+    config
+}
+
+impl<A> TlsProxyApp<A> {
+    pub fn new(app: A, store: Arc<CertStore>, enable_h2: bool) -> Self {
+        Self {
+            app: Arc::new(app),
+            acceptor: TlsAcceptor::from(Arc::new(server_config(store, enable_h2))),
+        }
+    }
+}
+
+#[async_trait]
+impl<A> ServerApp for TlsProxyApp<A>
+where
+    A: ServerApp + Send + Sync + 'static,
+{
+    async fn process_new(self: &Arc<Self>, stream: Stream, shutdown: &ShutdownWatch) -> Option<Stream> {
+        let tls_stream = match self.acceptor.accept(stream).await {
+            Ok(s) => s,
+            Err(e) => {
+                debug!("TLS handshake failed: {}", e);
+                return None;
+            }
+        };
+
+        // A TLS-ALPN-01 validation is complete once the handshake succeeded
+        if tls_stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN_PROTOCOL) {
+            debug!("Answered TLS-ALPN-01 challenge");
+            let mut tls_stream = tls_stream;
+            let _ = tls_stream.shutdown().await;
+            return None;
+        }
+
+        // The wrapped application keeps reused connections to itself, a stream handed
+        // back here would otherwise go through the TLS handshake a second time
+        let mut stream: Option<Stream> = Some(Box::new(TlsConnection::new(tls_stream)));
+        while let Some(s) = stream {
+            stream = self.app.process_new(s, shutdown).await;
+        }
+        None
+    }
+
+    async fn cleanup(&self) {
+        self.app.cleanup().await;
+    }
+}
+
+/// A server-side TLS connection that pingora can treat as a regular [`Stream`]
+pub struct TlsConnection {
+    stream: TlsStream<Stream>,
+    digest: Arc<SslDigest>,
+}
+
+impl TlsConnection {
+    fn new(stream: TlsStream<Stream>) -> Self {
+        let (_, conn) = stream.get_ref();
+        let cipher = conn
+            .negotiated_cipher_suite()
+            .and_then(|suite| suite.suite().as_str())
+            .unwrap_or_default();
+        let version = conn
+            .protocol_version()
+            .and_then(|version| version.as_str())
+            .unwrap_or_default();
+        let digest = Arc::new(SslDigest::new(cipher, version, None, None, Vec::new()));
+
+        Self { stream, digest }
+    }
+}
+
+impl fmt::Debug for TlsConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConnection")
+            .field("stream", self.stream.get_ref().0)
+            .finish()
+    }
+}
+
+impl AsyncRead for TlsConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl Shutdown for TlsConnection {
+    async fn shutdown(&mut self) {
+        if let Err(e) = AsyncWriteExt::shutdown(&mut self.stream).await {
+            debug!("TLS shutdown failed: {}", e);
+        }
+    }
+}
+
+impl UniqueID for TlsConnection {
+    fn id(&self) -> UniqueIDType {
+        self.stream.get_ref().0.id()
+    }
+}
+
+impl Ssl for TlsConnection {
+    fn get_ssl_digest(&self) -> Option<Arc<SslDigest>> {
+        Some(self.digest.clone())
+    }
+
+    fn selected_alpn_proto(&self) -> Option<ALPN> {
+        match self.stream.get_ref().1.alpn_protocol()? {
+            b"h2" => Some(ALPN::H2),
+            b"http/1.1" => Some(ALPN::H1),
+            _ => None,
+        }
+    }
+}
+
+impl GetTimingDigest for TlsConnection {
+    fn get_timing_digest(&self) -> Vec<Option<TimingDigest>> {
+        self.stream.get_ref().0.get_timing_digest()
+    }
+}
+
+impl GetProxyDigest for TlsConnection {
+    fn get_proxy_digest(&self) -> Option<Arc<ProxyDigest>> {
+        self.stream.get_ref().0.get_proxy_digest()
+    }
+}
+
+impl GetSocketDigest for TlsConnection {
+    fn get_socket_digest(&self) -> Option<Arc<SocketDigest>> {
+        self.stream.get_ref().0.get_socket_digest()
+    }
+}
+
+impl Peek for TlsConnection {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, KeyPair};
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+    use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+    use tokio_rustls::TlsConnector;
+
+    /// Accepts any server certificate, the tests only look at which one was sent
+    #[derive(Debug)]
+    struct AnyCertificate;
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            rustls::crypto::ring::default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    fn self_signed(domain: &str) -> Arc<CertifiedKey> {
+        let key_pair = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![domain.to_string()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into();
+        let provider = rustls::crypto::CryptoProvider::get_default().unwrap();
+        Arc::new(CertifiedKey::from_der(vec![cert.der().clone()], key, provider).unwrap())
+    }
+
+    /// A store serving `regular` for every name, with TLS-ALPN-01 challenges for `acme_domain`
+    fn store(regular: Arc<CertifiedKey>, acme_domain: &str) -> Arc<CertStore> {
+        let acme_certs = Arc::new(TlsAlpn01Certs::default());
+        acme_certs.insert(acme_domain, &[1u8; 32]).unwrap();
+        Arc::new(CertStore {
+            sources: Vec::new(),
+            loaded: RwLock::new(LoadedCerts {
+                certs: HashMap::new(),
+                default: Some(regular),
+            }),
+            acme_certs: Some(acme_certs),
+        })
+    }
+
+    /// Handshake offering `alpn` for `server_name`, returning the server's certificate
+    /// and the negotiated protocol
+    async fn handshake(
+        store: Arc<CertStore>,
+        server_name: &'static str,
+        alpn: &[&[u8]],
+    ) -> std::io::Result<(CertificateDer<'static>, Option<Vec<u8>>)> {
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(store, true)));
+        let mut client = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+            .with_no_client_auth();
+        client.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+        let (client_io, server_io) = tokio::io::duplex(16384);
+        let server = tokio::spawn(async move { acceptor.accept(server_io).await.map(|_| ()) });
+        let stream = TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from(server_name).unwrap(), client_io)
+            .await;
+        let _ = server.await;
+
+        let stream = stream?;
+        let connection = stream.get_ref().1;
+        Ok((
+            connection.peer_certificates().unwrap()[0].clone().into_owned(),
+            connection.alpn_protocol().map(|p| p.to_vec()),
+        ))
+    }
+
+    #[tokio::test]
+    async fn acme_tls_alpn_gets_challenge_certificate() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let regular = self_signed("example.com");
+        let store = store(regular.clone(), "example.com");
+        let challenge = store.acme_certs.as_ref().unwrap().get("example.com").unwrap();
+
+        let (cert, alpn) = handshake(store, "example.com", &[ACME_TLS_ALPN_PROTOCOL])
+            .await
+            .unwrap();
+        assert_eq!(cert, challenge.cert[0]);
+        assert_eq!(alpn.as_deref(), Some(ACME_TLS_ALPN_PROTOCOL));
+    }
+
+    #[tokio::test]
+    async fn regular_handshake_ignores_pending_challenge() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let regular = self_signed("example.com");
+        let store = store(regular.clone(), "example.com");
+
+        let (cert, alpn) = handshake(store, "example.com", &[b"h2", b"http/1.1"])
+            .await
+            .unwrap();
+        assert_eq!(cert, regular.cert[0]);
+        assert_eq!(alpn.as_deref(), Some(&b"h2"[..]));
+    }
+
+    #[tokio::test]
+    async fn acme_tls_alpn_without_challenge_fails() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let store = store(self_signed("example.com"), "example.com");
+
+        // Never the regular certificate, the validation must not pass by accident
+        let result = handshake(store, "other.example.com", &[ACME_TLS_ALPN_PROTOCOL]).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn acme_tls_alpn_offered_only_with_challenges() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let regular = self_signed("example.com");
+        let config = server_config(store(regular.clone(), "example.com"), false);
+        assert_eq!(
+            config.alpn_protocols,
+            vec![b"http/1.1".to_vec(), ACME_TLS_ALPN_PROTOCOL.to_vec()]
+        );
+
+        let without = Arc::new(CertStore {
+            sources: Vec::new(),
+            loaded: RwLock::new(LoadedCerts::default()),
+            acme_certs: None,
+        });
+        assert!(server_config(without, false).alpn_protocols.is_empty());
+    }
+
+    #[test]
+    fn lookup_prefers_exact_then_wildcard_then_default() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let (exact, wildcard, default) = (self_signed("example.com"), self_signed("*.example.com"), self_signed("default"));
+        let store = CertStore {
+            sources: Vec::new(),
+            loaded: RwLock::new(LoadedCerts {
+                certs: HashMap::from([
+                    ("example.com".to_string(), exact.clone()),
+                    ("*.example.com".to_string(), wildcard.clone()),
+                    ("api.example.com".to_string(), exact.clone()),
+                ]),
+                default: Some(default.clone()),
+            }),
+            acme_certs: None,
+        };
+
+        let lookup = |name| store.lookup(name).map(|key| key.cert[0].clone());
+        assert_eq!(lookup(Some("example.com")), Some(exact.cert[0].clone()));
+        assert_eq!(lookup(Some("Example.COM")), Some(exact.cert[0].clone()));
+        assert_eq!(lookup(Some("api.example.com")), Some(exact.cert[0].clone()));
+        assert_eq!(lookup(Some("www.example.com")), Some(wildcard.cert[0].clone()));
+        // A wildcard covers a single label only
+        assert_eq!(lookup(Some("a.www.example.com")), Some(default.cert[0].clone()));
+        assert_eq!(lookup(Some("other.com")), Some(default.cert[0].clone()));
+        assert_eq!(lookup(None), Some(default.cert[0].clone()));
+
+        store.loaded.write().unwrap().default = None;
+        assert!(store.lookup(Some("other.com")).is_none());
+        assert!(store.lookup(None).is_none());
+    }
+
+    /// PEM certificate and key for `domain`
+    fn pem_pair(domain: &str) -> (String, String) {
+        let key_pair = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![domain.to_string()])
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        (cert.pem(), key_pair.serialize_pem())
+    }
+
+    /// Write a file with a given modification time, so changes are seen regardless of
+    /// the file system's timestamp granularity
+    fn write_at(path: &Path, contents: &str, hours: u64) {
+        std::fs::write(path, contents).unwrap();
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(hours * 3600)).unwrap();
+    }
+
+    #[test]
+    fn reload_swaps_changed_pairs_and_keeps_broken_ones() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dir = std::env::temp_dir().join(format!("pingora-cert-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("example.com.crt"), dir.join("example.com.key"));
+        let _ = std::fs::remove_file(&cert_path);
+        let _ = std::fs::remove_file(&key_path);
+
+        let store = CertStore {
+            sources: vec![CertSource::new(Some("Example.com"), cert_path.clone(), key_path.clone())],
+            loaded: RwLock::new(LoadedCerts::default()),
+            acme_certs: None,
+        };
+        let served = || store.lookup(Some("example.com")).map(|key| key.cert[0].clone());
+        let der = |pem: &str| rustls_pemfile::certs(&mut pem.as_bytes()).next().unwrap().unwrap();
+
+        // Not provisioned yet
+        store.reload();
+        assert_eq!(served(), None);
+
+        let (first_cert, first_key) = pem_pair("example.com");
+        write_at(&cert_path, &first_cert, 1);
+        write_at(&key_path, &first_key, 1);
+        store.reload();
+        assert_eq!(served(), Some(der(&first_cert)));
+
+        // A changed modification time swaps in the new pair
+        let (second_cert, second_key) = pem_pair("example.com");
+        write_at(&cert_path, &second_cert, 2);
+        write_at(&key_path, &second_key, 2);
+        store.reload();
+        assert_eq!(served(), Some(der(&second_cert)));
+
+        // Unchanged times aren't reloaded, even if the contents differ
+        write_at(&cert_path, &first_cert, 2);
+        store.reload();
+        assert_eq!(served(), Some(der(&second_cert)));
+
+        // A key that doesn't belong to the certificate keeps the previous pair
+        let (third_cert, _) = pem_pair("example.com");
+        write_at(&cert_path, &third_cert, 3);
+        write_at(&key_path, &first_key, 3);
+        store.reload();
+        assert_eq!(served(), Some(der(&second_cert)));
+
+        // So does a certificate that can't be parsed, e.g. one still being written
+        write_at(&cert_path, &third_cert[..third_cert.len() / 2], 4);
+        store.reload();
+        assert_eq!(served(), Some(der(&second_cert)));
+
+        // Until a valid pair shows up
+        let (fourth_cert, fourth_key) = pem_pair("example.com");
+        write_at(&key_path, &fourth_key, 5);
+        write_at(&cert_path, &fourth_cert, 5);
+        store.reload();
+        assert_eq!(served(), Some(der(&fourth_cert)));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}